@@ -0,0 +1,83 @@
+import {
+  createCipheriv,
+  createDecipheriv,
+  createHmac,
+  randomBytes,
+} from "crypto";
+
+// A clipboard sent over the network instead of through the folder. The ID is
+// the name its file has in the folder, so the same clipboard is not applied
+// twice when it arrives both ways.
+export type ClipEnvelope = {
+  id: string;
+  itemNumber: number;
+  hostname: string;
+  channel: string;
+  type: "text" | "image";
+  // Base64
+  data: string;
+  time: number;
+  sensitive?: boolean;
+  // Set on the answers sent back through the same transport, which carry no
+  // clipboard, to the clipboard with this ID
+  ackOf?: string;
+};
+
+// AES-256-GCM, laid out as the IV, the authentication tag and the ciphertext,
+// for everything encrypted: the clipboards, the payloads and the history
+export const encryptBuffer = (data: Buffer, key: Buffer) => {
+  const iv = randomBytes(12);
+  const cipher = createCipheriv("aes-256-gcm", key, iv);
+  const encrypted = Buffer.concat([cipher.update(data), cipher.final()]);
+  return Buffer.concat([iv, cipher.getAuthTag(), encrypted]);
+};
+
+export const decryptBuffer = (data: Buffer, key: Buffer) => {
+  const decipher = createDecipheriv("aes-256-gcm", key, data.subarray(0, 12));
+  decipher.setAuthTag(data.subarray(12, 28));
+  return Buffer.concat([decipher.update(data.subarray(28)), decipher.final()]);
+};
+
+// Clipboards never cross the network in plaintext: they are encrypted with a
+// key derived from the one shared by paired computers, which also tells that
+// they come from one of them, as the encryption is authenticated
+export const getTransportKey = (key: Buffer) => {
+  return createHmac("sha256", key).update("clipboard-sync-transport").digest();
+};
+
+export const sealEnvelope = (
+  envelope: ClipEnvelope,
+  keyId: string,
+  key: Buffer
+) => {
+  return JSON.stringify({
+    keyId,
+    data: encryptBuffer(
+      Buffer.from(JSON.stringify(envelope), "utf8"),
+      getTransportKey(key)
+    ).toString("base64"),
+  });
+};
+
+// Throws if it was not sealed with a key given by getKey, or if it is older
+// than maxAgeMs
+export const openEnvelope = (
+  message: Buffer,
+  getKey: (keyId: string) => Buffer,
+  maxAgeMs: number
+): ClipEnvelope => {
+  const { keyId, data } = JSON.parse(message.toString("utf8"));
+  const key = getKey(keyId);
+  if (!key || typeof data !== "string") {
+    throw new Error(`Clipboard was sealed with an unknown key ${keyId}`);
+  }
+  const envelope: ClipEnvelope = JSON.parse(
+    decryptBuffer(Buffer.from(data, "base64"), getTransportKey(key)).toString(
+      "utf8"
+    )
+  );
+  if (Date.now() - envelope.time > maxAgeMs) {
+    throw new Error(`Clipboard ${envelope.id} is too old`);
+  }
+  return envelope;
+};
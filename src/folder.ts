@@ -0,0 +1,67 @@
+import path = require("path");
+
+export type ClipboardType = "text" | "image" | "files";
+
+export type ClipboardFile = {
+  itemNumber: number;
+  hostname: string;
+  channel: string;
+  type: ClipboardType;
+  filesCount?: number;
+  // Encrypted and padded, in zero-knowledge mode
+  sealed?: boolean;
+  // The only computer which should receive it, if any
+  target?: string;
+  // Text compressed with Brotli, in a .txt.br file. Node.js has no zstd, which
+  // would need a native module.
+  compressed?: boolean;
+  // Files packaged in a single .K_files.tar file instead of a folder
+  archived?: boolean;
+  // Copied from a password manager, so it is kept out of the history
+  sensitive?: boolean;
+};
+
+export const defaultChannel = "default";
+
+// The names of the clipboard files before their extension, also used by the
+// locks of the _files folders
+export const clipboardFileNamePattern =
+  "(0|[1-9][0-9]*)-([0-9a-zA-Z-]+)(?:@([0-9a-zA-Z-]+))?(?:\\.to-([0-9a-zA-Z-]+))?(\\.sensitive)?";
+
+// Parses the name of a clipboard file, returns null if not valid. Whether a
+// _files clipboard is a folder is left to the caller.
+export const parseClipboardFileName = (fileName: string): ClipboardFile => {
+  const match = fileName.match(
+    new RegExp(
+      `^${clipboardFileNamePattern}\\.(txt|txt\\.br|png|(0|[1-9][0-9]*)_files(\\.tar)?)$`
+    )
+  );
+  if (!match) {
+    return null;
+  }
+
+  const type: ClipboardType = match[6].startsWith("txt")
+    ? "text"
+    : match[6] === "png"
+    ? "image"
+    : "files";
+  const archived = type === "files" && !!match[8];
+
+  return {
+    itemNumber: parseInt(match[1]),
+    hostname: match[2],
+    channel: match[3] || defaultChannel,
+    type,
+    filesCount: type === "files" ? parseInt(match[7]) : undefined,
+    target: match[4],
+    compressed: match[6] === "txt.br" || undefined,
+    archived: archived || undefined,
+    sensitive: !!match[5] || undefined,
+  };
+};
+
+export const isLockFile = (file: string) => {
+  return new RegExp(
+    `^(${clipboardFileNamePattern}|[0-9a-f]{32})\\.lock$`
+  ).test(path.basename(file));
+};
@@ -0,0 +1,55 @@
+import { createDecipheriv, pbkdf2Sync } from "crypto";
+
+export type HistoryEntry = {
+  id: string;
+  type: "text" | "image";
+  text?: string;
+  time: number;
+  hostname: string;
+  pinned: boolean;
+};
+
+export type HistoryType = {
+  entries: HistoryEntry[];
+};
+
+export type HistoryLimits = {
+  maxItems: number;
+  maxAgeDays: number;
+  maxSizeMb: number;
+};
+
+// Written by the versions which encrypted it through electron-store, with
+// AES-CBC and no authentication, so it is only read to be encrypted again
+export const decryptLegacyHistory = (data: Buffer, key: Buffer) => {
+  const iv = data.subarray(0, 16);
+  const decipher = createDecipheriv(
+    "aes-256-cbc",
+    pbkdf2Sync(key, iv.toString(), 10000, 32, "sha512"),
+    iv
+  );
+  return Buffer.concat([decipher.update(data.subarray(17)), decipher.final()]);
+};
+
+// Pinned entries are never pruned and don't count towards the limits
+export const getHistoryEntriesToKeep = (
+  entries: HistoryEntry[],
+  limits: HistoryLimits,
+  getEntrySize: (entry: HistoryEntry) => number
+) => {
+  const minTime = Date.now() - limits.maxAgeDays * 24 * 60 * 60 * 1000;
+  const maxSize = limits.maxSizeMb * 1024 * 1024;
+
+  let unpinnedEntries = 0;
+  let unpinnedSize = 0;
+  return entries.filter((entry) => {
+    if (entry.pinned) {
+      return true;
+    }
+    if (entry.time < minTime || ++unpinnedEntries > limits.maxItems) {
+      return false;
+    }
+    unpinnedSize += getEntrySize(entry);
+    return unpinnedSize <= maxSize;
+  });
+};
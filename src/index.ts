@@ -5,12 +5,25 @@ import {
   dialog,
   shell,
   Notification,
-  clipboard,
+  clipboard as electronClipboard,
   nativeImage,
   MenuItem,
+  globalShortcut,
+  BrowserWindow,
+  ipcMain,
+  safeStorage,
 } from "electron";
-import clipboardEx = require("electron-clipboard-ex");
-import { createHash } from "crypto";
+import electronClipboardEx = require("electron-clipboard-ex");
+import {
+  createECDH,
+  createHash,
+  createHmac,
+  ECDH,
+  Hash,
+  randomBytes,
+  scryptSync,
+  timingSafeEqual,
+} from "crypto";
 import { https } from "follow-redirects";
 import Store = require("electron-store");
 import chokidar = require("chokidar");
@@ -19,9 +32,69 @@ import os = require("os");
 import path = require("path");
 import fs = require("fs");
 import semver = require("semver");
+import { spawn } from "child_process";
+import { Worker } from "worker_threads";
+import { EventEmitter } from "events";
+import http = require("http");
+import http2 = require("http2");
+import net = require("net");
+import tls = require("tls");
+import dgram = require("dgram");
+import zlib = require("zlib");
 import { exit } from "process";
 import { promisify } from "util";
-import { RequestOptions } from "https";
+import {
+  createServer as createHttpsServer,
+  request as httpsRequest,
+  RequestOptions,
+} from "https";
+import {
+  ClipEnvelope,
+  decryptBuffer,
+  encryptBuffer,
+  getTransportKey,
+  openEnvelope,
+  sealEnvelope,
+} from "./envelope";
+import {
+  ClipboardFile,
+  ClipboardType,
+  defaultChannel,
+  isLockFile,
+  parseClipboardFileName,
+} from "./folder";
+import {
+  decryptLegacyHistory,
+  getHistoryEntriesToKeep,
+  HistoryEntry,
+  HistoryType,
+} from "./history";
+import {
+  ControlAction,
+  ControlMessage,
+  getControlKeyId,
+  getControlMessageSignature,
+  getPairingCommitment,
+  getPairingSecrets,
+  isPairingMessageFresh,
+  pairingMaxAgeMs,
+  PairingExchange,
+  PairingMessage,
+} from "./pairing";
+import { extractTarArchive, writeTarArchive } from "./tar";
+import {
+  decodeProtobuf,
+  encodeProtobuf,
+  frameGrpcMessage,
+  ProtobufValue,
+  readGrpcMessage,
+} from "./transports/grpc";
+import { createNatsParser, encodeNatsCommand } from "./transports/nats";
+import {
+  createWebSocketConnection,
+  getWebSocketAccept,
+  WebSocketConnection,
+} from "./transports/websocket";
 
 // Handle creating/removing shortcuts on Windows when installing/uninstalling.
 // eslint-disable-line global-require
@@ -29,7 +102,106 @@ if (require("electron-squirrel-startup")) {
   app.exit();
 }
 
-const gotTheLock = app.requestSingleInstanceLock();
+// Returns the value of "--name=value" or "--name value"
+const getArgValue = (name: string, argv = process.argv) => {
+  const index = argv.findIndex(
+    (arg) => arg === `--${name}` || arg.startsWith(`--${name}=`)
+  );
+  if (index === -1) {
+    return undefined;
+  }
+  const arg = argv[index];
+  return arg.includes("=")
+    ? arg.substring(arg.indexOf("=") + 1)
+    : argv[index + 1];
+};
+
+// Runs as a relay server for the clipboards instead of on the tray, so it can
+// run alongside it
+const isRelayMode = process.argv.includes("--relay");
+
+// Runs the sync against a temporary folder with an in-memory clipboard and
+// scripted peers, to reproduce issues and test without touching the real ones
+const isSimulateMode = process.argv.includes("--simulate");
+
+if (isSimulateMode) {
+  app.setPath(
+    "userData",
+    fs.mkdtempSync(path.join(os.tmpdir(), `${app.name}-simulation-`))
+  );
+}
+
+// Keeps the settings and the data of the app in another folder, e.g. next
+// to a portable install. Falls back to the default one if not writable.
+const configDirectory = process.env.CLIPBOARD_SYNC_CONFIG_DIR;
+if (configDirectory && !isSimulateMode) {
+  try {
+    fs.mkdirSync(path.resolve(configDirectory), { recursive: true });
+    fs.accessSync(path.resolve(configDirectory), fs.constants.W_OK);
+    app.setPath("userData", path.resolve(configDirectory));
+  } catch (error) {
+    console.error(
+      `Folder ${configDirectory} of CLIPBOARD_SYNC_CONFIG_DIR is not writable, using ${app.getPath(
+        "userData"
+      )} instead: ${error}`
+    );
+  }
+}
+
+// Each profile has its own settings, folder and data, and runs alongside the
+// others, e.g. one for work and one for personal use
+const profileArg = getArgValue("profile");
+const profile =
+  profileArg && /^[0-9a-zA-Z-]+$/.test(profileArg) ? profileArg : undefined;
+if (profile) {
+  app.setPath(
+    "userData",
+    path.join(app.getPath("userData"), "profiles", profile)
+  );
+} else if (profileArg) {
+  console.error(`Invalid profile name ${profileArg}, using the default one`);
+}
+
+// Silences the logs below the level given, e.g. "--log-level warn"
+const logLevels = ["error", "warn", "info", "debug"];
+const logLevelIndex = logLevels.indexOf(getArgValue("log-level"));
+if (logLevelIndex !== -1) {
+  const noop = () => {};
+  if (logLevelIndex < logLevels.indexOf("warn")) {
+    console.warn = noop;
+  }
+  if (logLevelIndex < logLevels.indexOf("info")) {
+    console.log = noop;
+    console.info = noop;
+  }
+  if (logLevelIndex < logLevels.indexOf("debug")) {
+    console.debug = noop;
+  }
+}
+
+// Another settings file, e.g. to deploy the same one on several computers.
+// Ignored if it can't be written, rather than failing to start.
+const getConfigFileArg = () => {
+  const file = getArgValue("config");
+  // The simulation keeps its settings in its temporary folder instead
+  if (!file || isSimulateMode) {
+    return undefined;
+  }
+  try {
+    fs.mkdirSync(path.dirname(path.resolve(file)), { recursive: true });
+    fs.accessSync(path.dirname(path.resolve(file)), fs.constants.W_OK);
+    return file;
+  } catch (error) {
+    console.error(
+      `Settings file ${file} can't be written, using the default one instead: ${error}`
+    );
+    return undefined;
+  }
+};
+const configFile = getConfigFileArg();
+
+const gotTheLock =
+  isRelayMode || isSimulateMode || app.requestSingleInstanceLock();
 
 if (!gotTheLock) {
   exit();
@@ -40,27 +212,615 @@ type ConfigType = {
   send: boolean;
   receive: boolean;
   autoCleanup: boolean;
+  cleanupIntervalMinutes: number;
+  cleanupMaxAgeMinutes: number;
+  cleanOwnFilesOnExit: boolean;
+  cleanupPolicy: "age" | "count";
+  keepLastClips: number;
+  archiveFolder?: string;
+  sendTarget?: string;
+  peerSettings: Record<string, PeerSettings>;
+  allowedHosts: string[];
+  blockedHosts: string[];
+  plugins: Record<string, boolean>;
+  sendFilterCommand?: string;
+  receiveFilterCommand?: string;
+  linkNotifications: boolean;
+  autoOpenLinksFrom: string[];
+  channel: string;
+  channels: string[];
+  channelHotkeys: Record<string, string>;
+  keepHistory: boolean;
+  historyMaxItems: number;
+  historyMaxAgeDays: number;
+  historyMaxSizeMb: number;
+  syncHistory: boolean;
+  encryptHistory: boolean;
+  passphraseSalt?: string;
+  passphraseCheck?: string;
+  protectedEncryptionKey?: string;
+  historySearchHotkey: string;
+  pasteFromHistorySearch: boolean;
+  skipNextCopyHotkey?: string;
+  sendNowHotkey?: string;
+  pauseHotkey?: string;
+  sanitizeText: boolean;
+  rawTextForSameOs: boolean;
+  skipResendWithinMinutes: number;
+  clipboardDebounceMs: number;
+  folderEventDelayMs: number;
+  receivedEchoWindowMs: number;
+  keepAliveIntervalMinutes: number;
+  maxImageSizeMb: number;
+  maxFilesSizeMb: number;
+  archiveFiles: boolean;
+  filesManifest: boolean;
+  maxImageDimension: number;
+  maxImageMemoryMb: number;
+  folderBudgetMb: number;
+  dailyWriteBudgetMb: number;
+  networkShareMode: boolean;
+  durableWrites: boolean;
+  grpcPort: number;
+  metricsPort: number;
+  shareHealthFile: boolean;
+  roamSettings: boolean;
+  localSettings: string[];
+  remoteControl: boolean;
+  protectedControlKey?: string;
+  protectedPreviousControlKey?: string;
+  previousControlKeyExpiry?: number;
+  opaqueFilenames: boolean;
+  zeroKnowledgeMode: boolean;
+  directTransport: boolean;
+  directPort: number;
+  directPeers: string[];
+  relayUrl?: string;
+  relayToken?: string;
+  natsUrl?: string;
+  natsSubject: string;
+  natsJetStream: boolean;
+  lanWakeHints: boolean;
+  ntfyServer: string;
+  ntfyTopic?: string;
+  kdeConnect: boolean;
+  sendUniversalClipboard: boolean;
+  traceClipboardFormats: boolean;
+  suspendWhileRunning: string[];
+  paused: boolean;
+  syncSchedule: SyncWindow[];
+  clipboardTypePriority: ClipboardType[];
+  quietWhilePresenting: boolean;
+  suspendSendsWhilePresenting: boolean;
+  screenShareProcesses: string[];
+  peerKeepAlive: boolean;
+  deliveryNotifications: boolean;
+  resendUndelivered: boolean;
+  conflictPolicy: ConflictPolicy;
+  conflictPreferredHost?: string;
+  conflictNotifications: boolean;
+  allowedFileTypes: string[];
+  deniedFileTypes: string[];
+  confirmExecutableFiles: boolean;
+  receiveScanCommand?: string;
+  maxTextSizeKb: number;
+  compressTextOverKb: number;
+  receiveTextFilesAsText: boolean;
+  maxTextFileAsTextKb: number;
+  ocrCommand?: string;
+  receiveNotifications: boolean;
+  menuBarTitle: "off" | "peer" | "size";
+};
+
+// Overrides for the clipboards sent by a given computer
+type PeerSettings = {
+  // Shown instead of the hostname, like "Work Laptop"
+  name?: string;
+  cleanupMaxAgeMinutes?: number;
+  keepLastClips?: number;
 };
 
+// When syncing is on, like { days: ["mon", "fri"], from: "09:00", to: "18:00" },
+// every day if days is not given. It may end on the next day.
+type SyncWindow = {
+  days?: string[];
+  from: string;
+  to: string;
+};
+
+// What to keep when another computer copied at the same time as this one
+type ConflictPolicy = "latest" | "local" | "host";
+
 type ClipboardListener = {
   startListening: () => void;
   on: (arg0: string, arg1: () => void) => void;
+  removeListener: (arg0: string, arg1: () => void) => void;
   stopListening: () => void;
+  // The helper process of clipboard-event watching the clipboard
+  child?: { pid?: number; exitCode: number | null };
+};
+
+// The parts of the clipboard APIs used, which the simulation replaces
+type ClipboardApi = Pick<
+  Electron.Clipboard,
+  | "availableFormats"
+  | "has"
+  | "readBuffer"
+  | "readImage"
+  | "readText"
+  | "write"
+  | "writeImage"
+  | "writeText"
+>;
+
+type ClipboardExApi = Pick<
+  typeof electronClipboardEx,
+  "readFilePaths" | "writeFilePaths"
+>;
+
+type SimulatedClipboard = ClipboardListener & ClipboardApi & ClipboardExApi;
+
+type SimulationStep = {
+  // Milliseconds since the simulation started
+  at: number;
+  // Copies a text on this computer
+  copy?: string;
+  // Makes a peer send a text or an image file
+  from?: string;
+  text?: string;
+  image?: string;
+  // Checks the clipboard text or the number of clipboard files in the folder
+  expectText?: string;
+  expectFiles?: number;
+  // Removes the clipboard files regardless of their age
+  cleanup?: boolean;
+};
+
+type SimulationScript = {
+  steps: SimulationStep[];
 };
 
 type ClipboardIcon = "clipboard" | "clipboard_sent" | "clipboard_received";
 
-type ClipboardType = "text" | "image" | "files";
+type ClipboardDirection = "send" | "receive";
+
+// Describes each file of a _files folder, with the path relative to it
+type FilesManifestEntry = {
+  path: string;
+  size: number;
+  sha256: string;
+  // The Zone.Identifier of the file on Windows, telling where it came from
+  zoneIdentifier?: string;
+};
+
+// Describes a clipboard file written under an opaque name
+type OpaqueIndexEntry = ClipboardFile & {
+  name: string;
+};
+
+// Plugins are WebAssembly modules, run in a worker and instantiated without
+// any imports, or Lua scripts, run by the lua interpreter with only the safe
+// standard libraries. Either way, they can only work on the data we hand them.
+type ClipboardPlugin = {
+  name: string;
+  file: string;
+  // For WebAssembly plugins, started again after failing or timing out
+  worker?: Worker;
+};
+
+// WebAssembly plugins export on_text and accepts, taking the direction
+// (0 = send, 1 = receive), and the text or the type (0 = text, 1 = image,
+// 2 = files). Lua plugins define functions of the same names, taking them as
+// strings.
+type PluginRequest = {
+  call: "on_text" | "accepts";
+  direction: ClipboardDirection;
+  type: ClipboardType;
+  text?: string;
+};
+
+type PluginResponse = {
+  vetoed?: boolean;
+  text?: string;
+};
+
+type ClipEvent = {
+  direction: ClipboardDirection;
+  type: ClipboardType;
+  hostname: string;
+  channel: string;
+  size: number;
+  time: number;
+  // How long the file took to show up, for the clipboards received
+  latency?: number;
+};
+
+// What happened to a clipboard sent or received, for the activity window
+type ActivityEntry = {
+  time: number;
+  direction: ClipboardDirection;
+  hostname: string;
+  type?: ClipboardType;
+  size?: number;
+  outcome: "sent" | "applied" | "skipped" | "failed";
+  reason?: string;
+};
+
+// A way of delivering clipboards to the other computers besides the folder,
+// which stays as the fallback
+type ClipTransport = {
+  name: string;
+  start: () => void;
+  stop: () => void;
+  send: (envelope: ClipEnvelope) => void;
+  // Whether it can deliver clipboards right now
+  isAvailable: () => boolean;
+};
+
+// A way of reaching another computer, "folder" or a transport, with the
+// latest round trip measured from here through it
+type PeerPath = {
+  via: string;
+  roundTrip: number;
+  time: number;
+};
+
+// Internal data which needs to survive restarts
+type StateType = {
+  sentSha256: Record<string, number>;
+  bytesWrittenByDay: Record<string, number>;
+  sendSequence: number;
+};
+
+// Written to measure how long the folder takes to reach the others, which
+// answer with an ack naming the probe
+type ProbeMessage = {
+  id: string;
+  from: string;
+  time: number;
+  // Only set on acks, the computer which wrote the probe
+  to?: string;
+  // Like in the sequence files, missing when written by older versions
+  version?: string;
+  formatVersion?: number;
+  features?: string[];
+};
+
+// Written by each computer after sending, so the others can tell when they
+// missed a clipboard
+type SequenceMessage = {
+  hostname: string;
+  sequence: number;
+  itemNumber: number;
+  channel: string;
+  time: number;
+  // Missing when written by versions before the format was versioned
+  version?: string;
+  formatVersion?: number;
+  // The optional formats it can read, like "compressed-text"
+  features?: string[];
+  platform?: string;
+  // Set when the clipboard was only sent to the computer it names
+  target?: string;
+};
+
+// Written by a computer after applying a clipboard of another one
+type DeliveryAck = {
+  clipId: string;
+  from: string;
+  to: string;
+  time: number;
+};
+
+// Asks a computer to send its latest clipboard again
+type ResendRequest = {
+  from: string;
+  to: string;
+  itemNumber: number;
+  time: number;
+};
+
+type PinnedClipboard = {
+  text: string;
+  time: number;
+};
+
+// The settings shared through the folder when roaming is enabled. The folder
+// path and the ones tied to this computer are left out, and so are the filter
+// commands, as anyone able to write to the folder could make them run anything.
+const roamingSettings: (keyof ConfigType)[] = [
+  "send",
+  "receive",
+  "autoCleanup",
+  "cleanupIntervalMinutes",
+  "cleanupMaxAgeMinutes",
+  "cleanOwnFilesOnExit",
+  "cleanupPolicy",
+  "keepLastClips",
+  "peerSettings",
+  "linkNotifications",
+  "receiveNotifications",
+  "channels",
+  "historyMaxItems",
+  "historyMaxAgeDays",
+  "historyMaxSizeMb",
+  "syncHistory",
+  "sanitizeText",
+  "rawTextForSameOs",
+  "skipResendWithinMinutes",
+  "maxImageSizeMb",
+  "maxFilesSizeMb",
+  "archiveFiles",
+  "filesManifest",
+  "maxTextSizeKb",
+  "compressTextOverKb",
+  "maxImageDimension",
+  "maxImageMemoryMb",
+  "folderBudgetMb",
+  "dailyWriteBudgetMb",
+];
+
+// The shared settings file can be written by anyone with access to the
+// folder, so what enables code or opens links never roams, and the roamed
+// values must have the type of the setting and be within these bounds
+const roamingSettingBounds: Partial<
+  Record<keyof ConfigType, [number, number]>
+> = {
+  cleanupIntervalMinutes: [1, 59],
+  cleanupMaxAgeMinutes: [1, 10080],
+  keepLastClips: [1, 1000],
+  historyMaxItems: [0, 1000],
+  historyMaxAgeDays: [0, 3650],
+  historyMaxSizeMb: [0, 10240],
+  skipResendWithinMinutes: [0, 1440],
+  maxImageSizeMb: [1, 1024],
+  maxFilesSizeMb: [1, 10240],
+  maxTextSizeKb: [0, 102400],
+  compressTextOverKb: [0, 102400],
+  maxImageDimension: [1, 65535],
+  maxImageMemoryMb: [1, 4096],
+  folderBudgetMb: [0, 102400],
+  dailyWriteBudgetMb: [0, 102400],
+};
+
+const isValidRoamingValue = (key: keyof ConfigType, value: unknown) => {
+  const isPlainObject = (object: unknown) =>
+    typeof object === "object" && object !== null && !Array.isArray(object);
+  switch (key) {
+    case "cleanupPolicy":
+      return value === "age" || value === "count";
+    case "channels":
+      return (
+        Array.isArray(value) &&
+        value.length > 0 &&
+        value.every(
+          (channel) =>
+            typeof channel === "string" && /^[0-9a-zA-Z-]+$/.test(channel)
+        )
+      );
+    case "peerSettings":
+      return (
+        isPlainObject(value) &&
+        Object.values(value).every(
+          (peerSettings: PeerSettings) =>
+            isPlainObject(peerSettings) &&
+            (peerSettings.name === undefined ||
+              typeof peerSettings.name === "string") &&
+            (peerSettings.cleanupMaxAgeMinutes === undefined ||
+              (Number.isFinite(peerSettings.cleanupMaxAgeMinutes) &&
+                peerSettings.cleanupMaxAgeMinutes >= 1)) &&
+            (peerSettings.keepLastClips === undefined ||
+              (Number.isFinite(peerSettings.keepLastClips) &&
+                peerSettings.keepLastClips >= 1))
+        )
+      );
+  }
+  const bounds = roamingSettingBounds[key];
+  if (bounds) {
+    return (
+      typeof value === "number" &&
+      Number.isFinite(value) &&
+      value >= bounds[0] &&
+      value <= bounds[1]
+    );
+  }
+  return typeof value === typeof config.get(key);
+};
 
 const config = new Store<ConfigType>({
+  ...(configFile
+    ? {
+        cwd: path.dirname(path.resolve(configFile)),
+        name: path.basename(configFile, path.extname(configFile)),
+        fileExtension: path.extname(configFile).substring(1),
+      }
+    : {}),
   defaults: {
     send: true,
     receive: true,
     autoCleanup: true,
+    cleanupIntervalMinutes: 5,
+    cleanupMaxAgeMinutes: 5,
+    cleanOwnFilesOnExit: false,
+    cleanupPolicy: "age",
+    keepLastClips: 5,
+    peerSettings: {},
+    allowedHosts: [],
+    blockedHosts: [],
+    plugins: {},
+    linkNotifications: true,
+    autoOpenLinksFrom: [],
+    channel: defaultChannel,
+    channels: [defaultChannel, "code", "notes"],
+    channelHotkeys: {},
+    keepHistory: false,
+    historyMaxItems: 30,
+    historyMaxAgeDays: 7,
+    historyMaxSizeMb: 50,
+    syncHistory: false,
+    encryptHistory: false,
+    historySearchHotkey: "",
+    pasteFromHistorySearch: false,
+    sanitizeText: false,
+    rawTextForSameOs: false,
+    skipResendWithinMinutes: 0,
+    clipboardDebounceMs: 1000,
+    folderEventDelayMs: 500,
+    receivedEchoWindowMs: 5000,
+    keepAliveIntervalMinutes: 1,
+    maxImageSizeMb: 50,
+    maxFilesSizeMb: 100,
+    archiveFiles: false,
+    filesManifest: false,
+    maxTextSizeKb: 0,
+    compressTextOverKb: 0,
+    receiveTextFilesAsText: false,
+    maxTextFileAsTextKb: 64,
+    receiveNotifications: false,
+    menuBarTitle: "off",
+    maxImageDimension: 16384,
+    maxImageMemoryMb: 512,
+    folderBudgetMb: 1024,
+    dailyWriteBudgetMb: 1024,
+    networkShareMode: false,
+    durableWrites: false,
+    grpcPort: 0,
+    metricsPort: 0,
+    shareHealthFile: false,
+    roamSettings: false,
+    localSettings: [],
+    remoteControl: false,
+    opaqueFilenames: false,
+    zeroKnowledgeMode: false,
+    directTransport: false,
+    directPort: 47130,
+    directPeers: [],
+    natsSubject: "clipboard-sync.default",
+    natsJetStream: false,
+    lanWakeHints: false,
+    ntfyServer: "https://ntfy.sh",
+    kdeConnect: false,
+    sendUniversalClipboard: true,
+    traceClipboardFormats: false,
+    suspendWhileRunning: [],
+    paused: false,
+    syncSchedule: [],
+    clipboardTypePriority: ["text", "image", "files"],
+    quietWhilePresenting: true,
+    suspendSendsWhilePresenting: false,
+    screenShareProcesses: ["CptHost"],
+    peerKeepAlive: false,
+    deliveryNotifications: false,
+    resendUndelivered: false,
+    conflictPolicy: "latest",
+    conflictNotifications: false,
+    allowedFileTypes: [],
+    deniedFileTypes: [],
+    confirmExecutableFiles: true,
+  },
+});
+
+// Lets scripts set up the folder without going through the tray menu
+if (getArgValue("folder")) {
+  config.set("folder", path.resolve(getArgValue("folder")));
+}
+
+const state = new Store<StateType>({
+  name: "state",
+  defaults: {
+    sentSha256: {},
+    bytesWrittenByDay: {},
+    sendSequence: 0,
   },
 });
 
+// An in-memory clipboard for the simulation, which notifies its changes like
+// clipboard-event does, including the ones made by the app
+const createSimulatedClipboard = (): SimulatedClipboard => {
+  const changes = new EventEmitter();
+  let listening = false;
+  let text: string = null;
+  let image: Electron.NativeImage = null;
+  let filePaths: string[] = null;
+  const write = (
+    newText: string,
+    newImage: Electron.NativeImage,
+    newFilePaths: string[]
+  ) => {
+    text = newText;
+    image = newImage;
+    filePaths = newFilePaths;
+    if (listening) {
+      // Asynchronous like the real clipboard
+      setImmediate(() => changes.emit("change"));
+    }
+  };
+  return {
+    startListening: () => {
+      listening = true;
+    },
+    stopListening: () => {
+      listening = false;
+      changes.removeAllListeners();
+    },
+    on: (event, listener) => {
+      changes.on(event, listener);
+    },
+    removeListener: (event, listener) => {
+      changes.removeListener(event, listener);
+    },
+    availableFormats: () => [
+      ...(text !== null ? ["text/plain"] : []),
+      ...(image ? ["image/png"] : []),
+      ...(filePaths ? ["text/uri-list"] : []),
+    ],
+    has: () => false,
+    readBuffer: (format) =>
+      format === "image/png" && image ? image.toPNG() : Buffer.alloc(0),
+    readText: () => text || "",
+    writeText: (newText) => write(newText, null, null),
+    readImage: () => image || nativeImage.createEmpty(),
+    writeImage: (newImage) => write(null, newImage, null),
+    write: (data) => write(data.text ?? null, data.image || null, null),
+    readFilePaths: () => filePaths || [],
+    writeFilePaths: (newFilePaths) => write(null, null, newFilePaths),
+  };
+};
+
+const simulatedClipboard = isSimulateMode ? createSimulatedClipboard() : null;
+const clipboard: ClipboardApi = simulatedClipboard || electronClipboard;
+const clipboardEx: ClipboardExApi = simulatedClipboard || electronClipboardEx;
+
+// Opened once the app is ready, as it may need to be unlocked
+let history: HistoryType = null;
+let encryptionKey: Buffer = null;
+let controlKey: Buffer = null;
+let previousControlKey: Buffer = null;
+let pairingRequest: {
+  ecdh: ECDH;
+  time: number;
+  exchanges: Map<string, PairingExchange>;
+} = null;
+let opaqueIndex = new Map<string, OpaqueIndexEntry>();
+let opaqueIndexLoadedTime: number = null;
+const pairingApprovals = new Map<string, PairingExchange & { ecdh: ECDH }>();
+let pairingDialogOpen = false;
+
+// Formats set by password managers to keep their clipboards out of
+// clipboard managers
+const sensitiveClipboardFormats = [
+  "ExcludeClipboardContentFromMonitorProcessing",
+  "x-kde-passwordManagerHint",
+  "org.nspasteboard.ConcealedType",
+];
+
+// Set by macOS on the clipboards copied on other Apple devices, which arrive
+// through Universal Clipboard (Handoff)
+const universalClipboardFormat = "com.apple.is-remote-clipboard";
+
 let appIcon: Tray = null;
+let historySearchWindow: BrowserWindow = null;
+let activityWindow: BrowserWindow = null;
 let contextMenu: Menu = null;
 let firstTime = true;
 
@@ -70,6 +830,9 @@ let lastTextWritten: string = null;
 let lastImageSha256Written: string = null;
 let lastClipboardFilePathsWritten: string[] = null;
 let lastTimeWritten: number = null;
+let lastPathWritten: string = null;
+// The _files folders written recently, to avoid copying the same files again
+const filesWrittenBySha256 = new Map<string, string>();
 
 let lastTextRead: string = null;
 let lastImageSha256Read: string = null;
@@ -79,10 +842,224 @@ let lastTimeRead: number = null;
 let clipboardListener: ClipboardListener = null;
 let clipboardFilesWatcher: chokidar.FSWatcher = null;
 let filesCleanerTask: cron.ScheduledTask = null;
+let timerTask: cron.ScheduledTask = null;
 let iconWaiter: NodeJS.Timeout = null;
+let writeRetryWaiter: NodeJS.Timeout = null;
+let writeFailures = 0;
+let writeError: string = null;
+let folderUsageInMb: number = null;
+let folderAvailable = true;
+let suspendingProcess: string = null;
+let scheduledOff = false;
+// Nothing is sent or received while paused, until resumed even if restarted
+let paused = config.get("paused", false) || process.argv.includes("--paused");
+let presenting = false;
+let grpcServer: http2.Http2Server = null;
+const grpcSessions = new Set<http2.ServerHttp2Session>();
+let lastSentTime: number = null;
+let lastReceivedTime: number = null;
+
+let metricsServer: http.Server = null;
+let controlServer: net.Server = null;
+let clipTransports: ClipTransport[] = [];
+let directServers: http.Server[] = [];
+let directPeerAddresses: string[] = [];
+// The Tailscale peers which proved to have one of the paired keys, as the
+// tailnet may also have computers shared into it or not running the app
+const verifiedDirectPeers = new Set<string>();
+// The clipboards applied, by ID, to not apply them again when they arrive
+// through another way
+const appliedClipIds = new Map<string, number>();
+const peerPaths = new Map<string, Map<string, PeerPath>>();
+// When the clipboards still waiting for answers were sent, by ID
+const sentEnvelopeTimes = new Map<string, number>();
+
+// Emits a "clip" event with a ClipEvent for every clipboard sent or received
+const clipEvents = new EventEmitter();
+
+const stats = {
+  clips: new Map<string, number>(),
+  bytes: new Map<ClipboardDirection, number>(),
+  errors: 0,
+  lastError: null as string,
+  latencySum: 0,
+  latencyCount: 0,
+};
+
+clipEvents.on("clip", (clipEvent: ClipEvent) => {
+  const key = `${clipEvent.direction}:${clipEvent.type}`;
+  stats.clips.set(key, (stats.clips.get(key) || 0) + 1);
+  stats.bytes.set(
+    clipEvent.direction,
+    (stats.bytes.get(clipEvent.direction) || 0) + clipEvent.size
+  );
+  if (clipEvent.latency !== undefined) {
+    stats.latencySum += clipEvent.latency;
+    stats.latencyCount++;
+  }
+});
+// The latest clipboards sent and received, newest first
+const activityLog: ActivityEntry[] = [];
+const maxActivityEntries = 200;
+const activityChannel = "activity";
+
+const recordActivity = (entry: Omit<ActivityEntry, "time">) => {
+  activityLog.unshift({ time: Date.now(), ...entry });
+  activityLog.splice(maxActivityEntries);
+  if (activityWindow) {
+    activityWindow.webContents.send(activityChannel, activityLog);
+  }
+};
+
+clipEvents.on("clip", (clipEvent: ClipEvent) => {
+  recordActivity({
+    direction: clipEvent.direction,
+    hostname: clipEvent.hostname,
+    type: clipEvent.type,
+    size: clipEvent.size,
+    outcome: clipEvent.direction === "send" ? "sent" : "applied",
+  });
+});
+
+const repeatedErrors = new Map<string, { count: number }>();
+let folderEventsWaiter: NodeJS.Timeout = null;
+let pendingMenuRefresh = false;
+let pendingSettingsRefresh = false;
+let pendingIndexRefresh = false;
+const pendingControlMessageFiles = new Set<string>();
+const pendingPairingFiles = new Set<string>();
+const pendingProbeFiles = new Set<string>();
+const pendingResendFiles = new Set<string>();
+const pendingDeliveryFiles = new Set<string>();
+// The computers which applied each of the latest clipboards sent, by clip ID
+const deliveries = new Map<string, Set<string>>();
+let deliveryRetriedItemNumber: number = null;
+// The computers which acknowledged a clipboard of this one, so they are known
+// to receive from it, and the channel each one was last seen on
+const deliveringPeers = new Set<string>();
+const peerChannels = new Map<string, string>();
+// Received files with executables, while asking and once allowed
+const executableFilesAsked = new Set<string>();
+const executableFilesAllowed = new Set<string>();
+// Received files being scanned, and once found clean
+const filesBeingScanned = new Set<string>();
+const filesScannedClean = new Set<string>();
+// The newest item number seen in the folder from each computer, and the last
+// sequence number it wrote, to tell when its clipboard files went missing
+const itemNumbersSeen = new Map<string, number>();
+const sequencesSeen = new Map<string, number>();
+// How far ahead the clock of each computer is, estimated from the time in its
+// acks, give or take half the round trip
+const peerClockOffsets = new Map<
+  string,
+  { offsetMs: number; uncertaintyMs: number; time: number }
+>();
+// The optional formats each computer announced it can read
+const peerFeatures = new Map<string, string[]>();
+const peerPlatforms = new Map<string, string>();
+// When each computer last wrote to the folder or answered a probe
+const peersLastSeenTime = new Map<string, number>();
+// The computers writing the folder in another format, and which format
+const incompatiblePeers = new Map<string, number>();
+// The messages handled and the resends requested, with when, kept only while
+// they could still be accepted
+const resendsRequested = new Map<string, number>();
+const handledResendRequests = new Map<string, number>();
+const handledControlMessages = new Map<string, number>();
+const handledProbes = new Map<string, number>();
+const sentProbes = new Map<string, number>();
+const probeAcks = new EventEmitter();
+// Keep-alive probes sent in the last hour, and the round trips of each peer
+const keepAliveProbes = new Map<string, number>();
+const peerRoundTrips = new Map<string, Map<string, number>>();
+let applyingRoamingSettings = false;
+const pendingClipboardFiles = new Set<string>();
+let clipboardPlugins: ClipboardPlugin[] = [];
 
 const hostname = os.hostname();
 
+type TimingSetting =
+  | "clipboardDebounceMs"
+  | "folderEventDelayMs"
+  | "receivedEchoWindowMs"
+  | "keepAliveIntervalMinutes";
+
+// Bounds of the timing settings, so a typo does not stop syncing altogether
+const timingSettingBounds: Record<TimingSetting, [number, number, number]> = {
+  clipboardDebounceMs: [1000, 0, 10000],
+  folderEventDelayMs: [500, 0, 10000],
+  receivedEchoWindowMs: [5000, 0, 60000],
+  keepAliveIntervalMinutes: [1, 1, 60],
+};
+
+const getTimingSetting = (key: TimingSetting) => {
+  const [defaultValue, min, max] = timingSettingBounds[key];
+  const value = Number(config.get(key, defaultValue));
+  if (!Number.isFinite(value)) {
+    return defaultValue;
+  }
+  return Math.min(Math.max(min, Math.round(value)), max);
+};
+
+const errorNotificationWindowMs = 60000;
+
+const maxWriteRetryDelayMs = 300000;
+
+const staleLockMs = 300000;
+
+// Older commands are ignored, and removed along with the clipboard files
+const controlMessageMaxAgeMs = 300000;
+
+// How long the clipboard file may take to show up after the sequence file
+// which announced it, before it is considered missed
+const sequenceGapGraceMs = 30000;
+
+// Bumped whenever the files written to the folder change in a way older
+// versions can't read
+const folderFormatVersion = 1;
+// The optional formats this version can read, announced to the others so
+// they only write them once every computer can read them
+const folderFeatures = ["compressed-text", "sensitive-mark", "transport-acks"];
+
+// The least to wait for a computer online to apply the clipboard sent, before
+// sending it again once, when it is usually slower than this
+const deliveryRetryMs = 120000;
+
+// How long to wait for the acks when measuring the sync latency
+const latencyBenchmarkDurationMs = 60000;
+
+// The keep-alive probes are written every minute, kept for the stats for an
+// hour, and count as missed if not acknowledged within 2 minutes
+const keepAliveWindowMs = 3600000;
+// The sealed clipboards are accepted for a day at most, like JetStream keeps
+// them
+const envelopeMaxAgeMs = 24 * 60 * 60 * 1000;
+// How long the computers have to answer a clipboard sent through a transport
+const transportAckTimeoutMs = 10000;
+const keepAliveAckTimeoutMs = 120000;
+// Copies closer than this are not told apart by the time they were written
+const clockSkewToleranceMs = 1000;
+
+// Payloads are padded to a power of 2 starting from this size, in
+// zero-knowledge mode
+const minSealedPayloadSize = 1024;
+
+// Anyone who can write to the folder can ask to be paired, so only a few
+// requests are answered at a time
+const maxPendingPairings = 3;
+
+// How long the previous remote control passphrase is still accepted after
+// changing it, so the other computers can be changed meanwhile
+const previousControlKeyGraceMs = 7 * 24 * 60 * 60 * 1000;
+
+const syncLoopWindowMs = 30000;
+const syncLoopThreshold = 4;
+const syncLoopQuarantineMs = 600000;
+
+// How long a clipboard is remembered to tell whether the Windows cloud
+// clipboard already synced it
+const cloudClipboardWindowMs = 300000;
+
 const isArrayEquals = (arr1?: any[], arr2?: any[]) => {
   if (arr1 && arr2 && arr1.length == arr2.length) {
     arr1 = arr1.sort();
@@ -127,6 +1104,228 @@ const getTotalNumberOfFiles = (paths: string[]): number => {
   return totalNumberOfFiles;
 };
 
+// For the file type lists to also accept MIME types, like "image/*"
+const mimeTypesByExtension: Record<string, string> = {
+  ".7z": "application/x-7z-compressed",
+  ".bat": "application/x-bat",
+  ".csv": "text/csv",
+  ".dll": "application/x-msdownload",
+  ".doc": "application/msword",
+  ".docx":
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ".exe": "application/x-msdownload",
+  ".gif": "image/gif",
+  ".htm": "text/html",
+  ".html": "text/html",
+  ".jpeg": "image/jpeg",
+  ".jpg": "image/jpeg",
+  ".js": "text/javascript",
+  ".json": "application/json",
+  ".md": "text/markdown",
+  ".mp3": "audio/mpeg",
+  ".mp4": "video/mp4",
+  ".msi": "application/x-msi",
+  ".pdf": "application/pdf",
+  ".png": "image/png",
+  ".ppt": "application/vnd.ms-powerpoint",
+  ".pptx":
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+  ".ps1": "application/x-powershell",
+  ".sh": "application/x-sh",
+  ".svg": "image/svg+xml",
+  ".txt": "text/plain",
+  ".webp": "image/webp",
+  ".xls": "application/vnd.ms-excel",
+  ".xlsx": "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ".zip": "application/zip",
+};
+
+// Entries are extensions like ".exe", or MIME types like "image/*"
+const isFileTypeListed = (file: string, list: string[]) => {
+  const extension = path.extname(file).toLowerCase();
+  const mimeType = mimeTypesByExtension[extension];
+  return list.some((entry) => {
+    entry = entry.trim().toLowerCase();
+    if (entry.includes("/")) {
+      return (
+        mimeType !== undefined &&
+        (entry.endsWith("/*")
+          ? mimeType.startsWith(entry.slice(0, -1))
+          : mimeType === entry)
+      );
+    }
+    return extension === (entry.startsWith(".") ? entry : `.${entry}`);
+  });
+};
+
+// Returns the files which the allowed and denied file types don't let sync
+const getFilesNotAllowed = (paths: string[]) => {
+  const allowedFileTypes = config.get("allowedFileTypes", []);
+  const deniedFileTypes = config.get("deniedFileTypes", []);
+  if (allowedFileTypes.length === 0 && deniedFileTypes.length === 0) {
+    return [];
+  }
+  return iterateThroughFilesRecursively(paths, (file) => {
+    if (
+      isFileTypeListed(file, deniedFileTypes) ||
+      (allowedFileTypes.length > 0 && !isFileTypeListed(file, allowedFileTypes))
+    ) {
+      return file;
+    }
+    return null;
+  }) as string[];
+};
+
+// Files which can run code when opened, including documents with macros
+const executableExtensions = [
+  ".app",
+  ".appimage",
+  ".bat",
+  ".cmd",
+  ".com",
+  ".command",
+  ".cpl",
+  ".deb",
+  ".dll",
+  ".dmg",
+  ".docm",
+  ".exe",
+  ".hta",
+  ".jar",
+  ".js",
+  ".jse",
+  ".lnk",
+  ".msi",
+  ".pif",
+  ".pkg",
+  ".pptm",
+  ".ps1",
+  ".reg",
+  ".rpm",
+  ".scr",
+  ".sh",
+  ".vbe",
+  ".vbs",
+  ".wsf",
+  ".xlsm",
+];
+
+// The first bytes of ELF, Mach-O and PE binaries, and of scripts
+const executableMagicBytes = [
+  "7f454c46",
+  "feedface",
+  "feedfacf",
+  "cefaedfe",
+  "cffaedfe",
+  "cafebabe",
+  "2321",
+  "4d5a",
+];
+
+// The executable bit alone is set on every file in folders on FAT, exFAT,
+// NTFS or SMB shares, so the content must look like a program too
+const hasExecutableContent = (file: string) => {
+  const header = Buffer.alloc(4);
+  let fd: number;
+  try {
+    fd = fs.openSync(file, "r");
+    const bytesRead = fs.readSync(fd, header, 0, header.length, 0);
+    const hex = header.subarray(0, bytesRead).toString("hex");
+    return executableMagicBytes.some((magic) => hex.startsWith(magic));
+  } catch (error) {
+    return false;
+  } finally {
+    if (fd !== undefined) {
+      fs.closeSync(fd);
+    }
+  }
+};
+
+const getExecutableFiles = (paths: string[]) => {
+  return iterateThroughFilesRecursively(paths, (file) => {
+    if (
+      executableExtensions.includes(path.extname(file).toLowerCase()) ||
+      (process.platform !== "win32" &&
+        fs.statSync(file).mode & 0o111 &&
+        hasExecutableContent(file))
+    ) {
+      return file;
+    }
+    return null;
+  }) as string[];
+};
+
+const readZoneIdentifier = (file: string) => {
+  if (process.platform !== "win32") {
+    return undefined;
+  }
+  try {
+    return fs.readFileSync(`${file}:Zone.Identifier`, { encoding: "utf8" });
+  } catch (error) {
+    return undefined;
+  }
+};
+
+const isValidZoneIdentifier = (zoneIdentifier: unknown) => {
+  return (
+    typeof zoneIdentifier === "string" &&
+    zoneIdentifier.startsWith("[ZoneTransfer]") &&
+    zoneIdentifier.length < 4096
+  );
+};
+
+// The mark sent by the other computer, which anyone with access to the folder
+// can write, can only make it stricter: the files are always marked as from
+// the internet at least, and only keep where they were downloaded from when
+// they were marked so there too
+const getZoneIdentifier = (remoteZoneIdentifier: unknown) => {
+  let zoneId = 3;
+  const urls: string[] = [];
+  if (isValidZoneIdentifier(remoteZoneIdentifier)) {
+    const lines = (remoteZoneIdentifier as string).split(/\r?\n/);
+    const remoteZoneId = parseInt(
+      lines.find((line) => line.startsWith("ZoneId="))?.substring(7)
+    );
+    if (remoteZoneId >= 3 && remoteZoneId <= 4) {
+      zoneId = remoteZoneId;
+      urls.push(
+        ...lines.filter((line) => /^(ReferrerUrl|HostUrl)=\S+$/.test(line))
+      );
+    }
+  }
+  return ["[ZoneTransfer]", `ZoneId=${zoneId}`, ...urls, ""].join("\r\n");
+};
+
+// Windows warns before running the files marked as coming from the internet,
+// which is lost along the way through the folder. With a manifest, each file
+// also keeps where it was downloaded from, if it was marked so.
+const markFilesFromInternet = (
+  paths: string[],
+  manifest?: FilesManifestEntry[]
+) => {
+  if (process.platform !== "win32") {
+    return;
+  }
+  paths.forEach((fileOrFolder) => {
+    iterateThroughFilesRecursively([fileOrFolder], (file) => {
+      const relativePath = path
+        .relative(path.dirname(fileOrFolder), file)
+        .split(path.sep)
+        .join("/");
+      const zoneIdentifier = getZoneIdentifier(
+        manifest?.find((entry) => entry.path === relativePath)?.zoneIdentifier
+      );
+      try {
+        fs.writeFileSync(`${file}:Zone.Identifier`, zoneIdentifier, {
+          encoding: "utf8",
+        });
+      } catch (error) {
+        console.error(`Error marking ${file} as from the internet: ${error}`);
+      }
+    });
+  });
+};
+
 const getFilesSizeInMb = (paths: string[]) => {
   let totalSize = 0;
   iterateThroughFilesRecursively(paths, (file) => {
@@ -183,481 +1382,8945 @@ const getRedirectedUrl = async (requestOptions: RequestOptions) => {
   )(requestOptions);
 };
 
-// returns 0 if not valid
-const getItemNumber = (file: string, exceptOwn: boolean = false) => {
-  const parsedFile = path.parse(file);
-  let itemNumber = 0;
+// returns null if not valid
+const parseClipboardFile = (file: string): ClipboardFile => {
   let fileStat;
 
   try {
     fileStat = fs.lstatSync(file);
   } catch (error) {
-    return itemNumber;
+    return null;
   }
 
-  if (fileStat.isDirectory()) {
-    const match = parsedFile.base.match(
-      /^(0|[1-9][0-9]*)-([0-9a-zA-Z-]+)\.(0|[1-9][0-9]*)_files$/
-    );
-    if (match && !(exceptOwn && match[2] === hostname)) {
-      itemNumber = parseInt(match[1]);
-    }
-  } else {
-    const match = parsedFile.base.match(
-      /^(0|[1-9][0-9]*)-([0-9a-zA-Z-]+)\.(txt|png)$/
-    );
-    if (match && !(exceptOwn && match[2] === hostname)) {
-      itemNumber = parseInt(match[1]);
+  if (isOpaqueFile(file)) {
+    const entry = getOpaqueIndexEntry(path.basename(file));
+    if (
+      !entry ||
+      (entry.type === "files" && !entry.archived) !== fileStat.isDirectory()
+    ) {
+      return null;
     }
+    return {
+      itemNumber: entry.itemNumber,
+      hostname: entry.hostname,
+      channel: entry.channel,
+      type: entry.type,
+      filesCount: entry.filesCount,
+      sealed: entry.sealed,
+      target: entry.target,
+      compressed: entry.compressed,
+      archived: entry.archived,
+      sensitive: entry.sensitive,
+    };
   }
-  return itemNumber;
-};
 
-const calculateSha256 = (data: Buffer) => {
-  return createHash("sha256").update(data).digest("hex");
+  const clipboardFile = parseClipboardFileName(path.basename(file));
+  if (
+    !clipboardFile ||
+    (clipboardFile.type === "files" && !clipboardFile.archived) !==
+      fileStat.isDirectory()
+  ) {
+    return null;
+  }
+  return clipboardFile;
 };
 
-const getNextWriteTime = () => {
-  const numbers: number[] = [];
-  fs.readdirSync(syncFolder).forEach((file) => {
-    file = path.join(syncFolder, file);
-    const itemNumber = getItemNumber(file);
-    if (itemNumber) {
-      numbers.push(itemNumber);
-    }
-  });
-  if (numbers.length > 0) {
-    // https://stackoverflow.com/a/1063027/12156188
-    return (
-      numbers
-        .sort((a, b) => {
-          return a - b;
-        })
-        .at(-1) + 1
+// Whether all the computers seen in the folder announced they can read it
+const isFeatureSupportedByPeers = (feature: string) => {
+  const peersWithoutFeature = [
+    ...new Set([...sequencesSeen.keys(), ...peerFeatures.keys()]),
+  ].filter((peer) => !(peerFeatures.get(peer) || []).includes(feature));
+  if (peersWithoutFeature.length > 0) {
+    console.log(
+      `Not using ${feature}, which ${peersWithoutFeature
+        .map(getDeviceName)
+        .join(", ")} can't read`
     );
+    return false;
   }
-  return 1;
+  return true;
 };
 
-const isThereMoreThanOneClipboardFile = () => {
-  let found = 0;
-  fs.readdirSync(syncFolder).forEach((file) => {
-    file = path.join(syncFolder, file);
-    const itemNumber = getItemNumber(file);
-    if (itemNumber) {
-      found++;
-      if (found > 1) {
-        return;
-      }
-    }
-  });
-  return found > 1;
+// Large texts, like the HTML copied from browsers, sync faster compressed.
+// Older versions don't recognize the ".txt.br" files, so it is opt-in, and
+// only used while all the computers announce they can read them.
+const shouldCompressText = (text: string) => {
+  const compressTextOverKb = config.get("compressTextOverKb", 0);
+  return (
+    compressTextOverKb > 0 &&
+    !isZeroKnowledgeMode() &&
+    Buffer.byteLength(text, "utf8") > compressTextOverKb * 1024 &&
+    isFeatureSupportedByPeers("compressed-text")
+  );
 };
 
-let lastTimeChecked: number = null;
+const getTextExtension = (compressed: boolean) => {
+  return compressed ? "txt.br" : "txt";
+};
 
-const writeClipboardToFile = () => {
-  // Prevents duplicated clipboard events
-  const currentTime = Date.now();
-  if (lastTimeChecked && currentTime - lastTimeChecked < 1000) {
-    return;
-  }
-  lastTimeChecked = currentTime;
+// Clipboards sent to a single computer have ".to-HOST" before the extension,
+// which older versions don't recognize, so they don't receive them either
+const getTargetSuffix = (target: string) => {
+  return target ? `.to-${target}` : "";
+};
 
-  let clipboardType: ClipboardType;
-  let clipboardText: string;
-  let clipboardImage: Buffer;
-  let clipboardImageSha256: string;
-  let clipboardFilePaths: string[];
-  let clipboardFilesCount: number;
-  const clipboardFormats = clipboard.availableFormats();
+// Older versions don't recognize ".sensitive" either, so it is only added
+// while all the computers announce they can read it
+const getSensitiveSuffix = (sensitive: boolean) => {
+  return sensitive ? ".sensitive" : "";
+};
 
-  try {
-    if (clipboardFormats.includes("text/plain")) {
-      clipboardText = clipboard.readText();
-      clipboardType = "text";
-    } else if (clipboardFormats.includes("image/png")) {
-      clipboardImage = clipboard.readImage().toPNG();
-      clipboardImageSha256 = calculateSha256(clipboardImage);
-      clipboardType = "image";
-    } else if (clipboardFormats.includes("text/uri-list")) {
-      clipboardFilePaths = clipboardEx.readFilePaths();
-      clipboardType = "files";
-    }
-  } catch (error) {
-    console.error("Error reading current clipboard");
+const isForThisComputer = (clipboardFile: Pick<ClipboardFile, "target">) => {
+  return (
+    !clipboardFile.target ||
+    clipboardFile.target.toLowerCase() === hostname.toLowerCase()
+  );
+};
+
+const getSendTarget = () => {
+  const sendTarget = config.get("sendTarget");
+  return sendTarget && /^[0-9a-zA-Z-]+$/.test(sendTarget)
+    ? sendTarget
+    : undefined;
+};
+
+// returns 0 if not valid
+const getItemNumber = (file: string, exceptOwn: boolean = false) => {
+  const clipboardFile = parseClipboardFile(file);
+  if (!clipboardFile || (exceptOwn && clipboardFile.hostname === hostname)) {
+    return 0;
   }
+  return clipboardFile.itemNumber;
+};
 
-  if (!clipboardType) {
-    return;
+const isValidChannel = (channel: string) => {
+  return /^[0-9a-zA-Z-]+$/.test(channel);
+};
+
+const getChannels = () => {
+  const channels = config.get("channels", []).filter(isValidChannel);
+  if (!channels.includes(defaultChannel)) {
+    channels.unshift(defaultChannel);
   }
+  return channels;
+};
 
-  // Prevent sending the clipboard that was just received
-  if (
-    clipboardType === "text" &&
-    (!clipboardText ||
-      (lastTimeRead &&
-        currentTime - lastTimeRead < 5000 &&
-        lastTextRead === clipboardText))
-  ) {
+const getCurrentChannel = () => {
+  const channel = config.get("channel", defaultChannel);
+  return getChannels().includes(channel) ? channel : defaultChannel;
+};
+
+// The default channel is left out of the file name, so it stays compatible
+// with older versions
+const getChannelSuffix = (channel: string) => {
+  return channel === defaultChannel ? "" : `@${channel}`;
+};
+
+// Shows the first of repeated errors right away, and summarizes the repeats
+// once the window passes, instead of spamming a notification for each one
+const notifyError = (title: string, body: string) => {
+  stats.errors++;
+  stats.lastError = `${title}: ${body}`;
+  const key = `${title}\n${body}`;
+  const repeatedError = repeatedErrors.get(key);
+  if (repeatedError) {
+    repeatedError.count++;
     return;
   }
 
-  if (
-    clipboardType === "image" &&
-    (!clipboardImage ||
-      (lastTimeRead &&
-        currentTime - lastTimeRead < 5000 &&
-        lastImageSha256Read === clipboardImageSha256))
-  ) {
-    return;
+  if (!isQuiet()) {
+    new Notification({ title, body, icon: getAppIcon() }).show();
+  }
+  const newRepeatedError = { count: 0 };
+  repeatedErrors.set(key, newRepeatedError);
+  setTimeout(() => {
+    repeatedErrors.delete(key);
+    if (newRepeatedError.count > 0 && !isQuiet()) {
+      new Notification({
+        title,
+        body: `${body} (happened ${newRepeatedError.count} more times)`,
+        icon: getAppIcon(),
+      }).show();
+    }
+  }, errorNotificationWindowMs);
+};
+
+const getErrorDescription = (error: unknown) => {
+  if (error instanceof Error) {
+    return (error as NodeJS.ErrnoException).code || error.message;
   }
+  return String(error);
+};
 
+// Reads the dimensions from the IHDR chunk, returns null if not a PNG
+const getPngDimensions = (header: Buffer) => {
   if (
-    clipboardType === "files" &&
-    (!clipboardFilePaths ||
-      (lastTimeRead &&
-        currentTime - lastTimeRead < 5000 &&
-        isArrayEquals(lastClipboardFilePathsRead, clipboardFilePaths)) ||
-      getFilesSizeInMb(clipboardFilePaths) > 100)
+    header.length < 24 ||
+    header.readUInt32BE(0) !== 0x89504e47 ||
+    header.toString("ascii", 12, 16) !== "IHDR"
   ) {
-    return;
+    return null;
   }
+  return { width: header.readUInt32BE(16), height: header.readUInt32BE(20) };
+};
 
-  const writeTime = getNextWriteTime();
-  let destinationPath: string;
-  if (clipboardType === "text") {
-    destinationPath = path.join(syncFolder, `${writeTime}-${hostname}.txt`);
-    fs.writeFileSync(destinationPath, clipboardText, {
-      encoding: "utf8",
-    });
-    lastTextWritten = clipboardText;
-  } else if (clipboardType === "image") {
-    destinationPath = path.join(syncFolder, `${writeTime}-${hostname}.png`);
-    fs.writeFileSync(destinationPath, clipboardImage);
-    lastImageSha256Written = clipboardImageSha256;
-  } else if (clipboardType === "files") {
-    clipboardFilesCount = getTotalNumberOfFiles(clipboardFilePaths);
-    destinationPath = path.join(
-      syncFolder,
-      `${writeTime}-${hostname}.${clipboardFilesCount}_files`
+const isImageDimensionAllowed = (size: Electron.Size) => {
+  const maxImageDimension = config.get("maxImageDimension", 16384);
+  if (size.width > maxImageDimension || size.height > maxImageDimension) {
+    console.error(
+      `Image of ${size.width}x${size.height} exceeds the maximum dimension of ${maxImageDimension}. Skipping...`
     );
-    fs.mkdirSync(destinationPath);
-    clipboardFilePaths.forEach((filePath: string) => {
-      const fullDestination = path.join(
-        destinationPath,
-        path.basename(filePath)
-      );
-      if (fs.statSync(filePath).isDirectory()) {
-        copyFolderRecursive(filePath, fullDestination);
-      } else {
-        fs.copyFileSync(filePath, fullDestination);
-      }
-    });
-    lastClipboardFilePathsWritten = clipboardFilePaths;
+    return false;
   }
-  console.log(`Clipboard written to ${destinationPath}`);
-  lastTimeWritten = writeTime;
-
-  setIconFor5Seconds("clipboard_sent");
+  return true;
 };
 
-const readClipboardFromFile = (file: string) => {
-  const currentTime = Date.now();
+// Texts larger than maxTextSizeKb are sent as a file with this name
+const largeTextFileName = "clipboard.txt";
 
-  const filename = path.relative(syncFolder, file).split(path.sep)[0];
-  file = path.join(syncFolder, filename);
+// Up to which size a large text received as a file can be copied as text
+const maxLargeTextAsTextMb = 10;
 
-  const currentFileTime = getItemNumber(file, true);
-  if (!currentFileTime) {
-    return;
+// Up to which size the compressed texts received are decompressed, when
+// maxTextSizeKb is 0
+const maxDecompressedTextMb = 100;
+
+// Returns the path of the file to send instead, or null if it can't be sent
+const writeLargeTextFile = (text: string) => {
+  const sizeInBytes = Buffer.byteLength(text, "utf8");
+  if (sizeInBytes > 100 * 1024 * 1024) {
+    console.error("Text is larger than 100MB. Skipping...");
+    return null;
   }
+  const folder = path.join(app.getPath("temp"), `${app.name}-large-text`);
+  fs.mkdirSync(folder, { recursive: true });
+  const file = path.join(folder, largeTextFileName);
+  fs.writeFileSync(file, text, { encoding: "utf8" });
+  console.log(
+    `Text of ${(sizeInBytes / 1024).toFixed(
+      0
+    )}KB exceeds the maximum size of ${config.get(
+      "maxTextSizeKb",
+      0
+    )}KB, sending it as a file`
+  );
+  return file;
+};
 
-  const fileName = path.parse(file).name;
-  const fileExtension = path.parse(file).ext;
-  const fileClipboardType =
-    fileExtension === ".txt"
-      ? "text"
-      : fileExtension === ".png"
-      ? "image"
-      : fileExtension.endsWith("_files")
-      ? "files"
-      : null;
+const isLargeTextFile = (filePaths: string[]) => {
+  return (
+    filePaths.length === 1 &&
+    path.basename(filePaths[0]) === largeTextFileName &&
+    fs.statSync(filePaths[0]).isFile()
+  );
+};
 
-  let currentText: string;
-  let currentImage: Buffer;
-  let currentClipboardType: ClipboardType;
-  let currentImageSha256: string;
-  let currentFilePaths: string[];
+// For clipboards which are received in one form, but may be wanted in another
+const offerAlternativeClipboard = (
+  title: string,
+  body: string,
+  actionText: string,
+  copyAlternative: () => void
+) => {
+  if (isQuiet()) {
+    return;
+  }
+  const notification = new Notification({
+    title,
+    body,
+    icon: getAppIcon(),
+    actions: [{ type: "button", text: actionText }],
+  });
+  // Actions are only supported on macOS, clicking works everywhere
+  notification.on("action", copyAlternative);
+  notification.on("click", copyAlternative);
+  notification.show();
+};
 
-  const clipboardFormats = clipboard.availableFormats();
-  try {
-    if (clipboardFormats.includes("text/plain")) {
-      currentText = clipboard.readText();
-      currentClipboardType = "text";
-    } else if (clipboardFormats.includes("image/png")) {
-      currentImage = clipboard.readImage().toPNG();
-      currentImageSha256 = calculateSha256(currentImage);
-      currentClipboardType = "image";
-    } else if (clipboardFormats.includes("text/uri-list")) {
-      currentFilePaths = clipboardEx.readFilePaths();
-      currentClipboardType = "files";
-    }
-  } catch (error) {
-    console.error("Error reading current clipboard");
+// The file is put on the clipboard, and the text is offered too
+const offerLargeTextAsText = (file: string, senderHostname: string) => {
+  if (fs.statSync(file).size > maxLargeTextAsTextMb * 1024 * 1024) {
+    return;
   }
+  offerAlternativeClipboard(
+    `Large text received from ${getDeviceName(senderHostname)}`,
+    "It was copied as a file. Click to copy it as text instead.",
+    "Copy as text",
+    () => {
+      const text = fs.readFileSync(file, { encoding: "utf8" });
+      lastTextRead = text;
+      lastTimeRead = Date.now();
+      clipboard.writeText(text);
+    }
+  );
+};
 
-  let newText: string;
-  let newImage: Buffer;
-  let newImageSha256: string;
-  let newFilePaths: string[];
-  let newFilesCount: number;
-  try {
-    if (fileClipboardType === "text") {
-      newText = fs.readFileSync(file, {
-        encoding: "utf8",
-      });
-    } else if (fileClipboardType === "image") {
-      newImage = fs.readFileSync(file);
-      newImageSha256 = calculateSha256(newImage);
-    } else if (fileClipboardType === "files") {
-      const matches = fileExtension.match(/^\.(0|[1-9][0-9]*)_files$/);
-      if (matches && matches.length > 0) {
-        newFilesCount = parseInt(matches[1]);
-      } else {
-        console.error("Unrecognized _files folder, missing files count.");
-        return;
-      }
-      newFilePaths = fs
-        .readdirSync(file)
-        .map((fileName: string) => path.join(file, fileName));
-      const filesCountInFolder = getTotalNumberOfFiles(newFilePaths);
-      if (newFilesCount !== filesCountInFolder) {
-        console.error(
-          `Not all files are yet present in _files folder. Current: ${filesCountInFolder}, expected: ${newFilesCount}. Skipping...`
-        );
-        return;
-      }
+// Runs the OCR command on an image received, and once done adds the text
+// found to the clipboard along with the image, if it was not replaced since.
+// The image file is appended to the command, or replaces {path} in it, and
+// the text is read from its output. Like the scan command, it runs without a
+// shell, so the names of the images received can't inject commands.
+// The temporary folder, if given, is removed once the command finishes
+const addOcrText = (
+  imageFile: string,
+  receivedImage: Electron.NativeImage,
+  imageSha256: string,
+  temporaryFolder?: string
+) => {
+  const removeTemporaryFolder = () => {
+    if (temporaryFolder) {
+      fs.rmSync(temporaryFolder, { recursive: true, force: true });
     }
-  } catch (error) {
-    console.error(`Error reading clipboard from file ${fileName}`);
+  };
+  const ocrCommand = config.get("ocrCommand");
+  if (!ocrCommand) {
+    removeTemporaryFolder();
     return;
   }
-
-  if (currentClipboardType === fileClipboardType) {
-    if (
-      currentClipboardType === "text" &&
-      (!newText || currentText === newText)
-    ) {
-      // Prevents writing duplicated text to clipboard
-      return;
-    } else if (
-      fileClipboardType === "image" &&
-      (!newImage || currentImageSha256 === newImageSha256)
-    ) {
-      // Prevents writing duplicated image to clipboard
+  const [command, ...args] = splitCommandLine(ocrCommand);
+  const ocrArgs = args.some((arg) => arg.includes("{path}"))
+    ? args.map((arg) => arg.split("{path}").join(imageFile))
+    : [...args, imageFile];
+  runCommand(command, ocrArgs, { timeout: 60000 }).then((result) => {
+    removeTemporaryFolder();
+    const text = result.stdout.trim();
+    if (result.status !== 0 || !text) {
+      if (result.status !== 0) {
+        console.error(`OCR command failed: ${result.stderr}`);
+      }
       return;
-    } else if (
-      fileClipboardType === "files" &&
-      (!newFilePaths || isArrayEquals(currentFilePaths, newFilePaths))
+    }
+    if (
+      lastImageSha256Read !== imageSha256 ||
+      clipboard.availableFormats().includes("text/plain") ||
+      !isSameImage(clipboard.readImage(), receivedImage)
     ) {
-      // Prevents writing duplicated files to clipboard
       return;
     }
-  }
+    // Not sent as a new text clipboard
+    lastTextRead = text;
+    lastTimeRead = Date.now();
+    clipboard.write({ image: receivedImage, text });
+    console.log(`Added ${text.length} characters of text found in the image`);
+  });
+};
 
-  // Skips the read if a newer file was already wrote
+const textFileTypes = [
+  "text/*",
+  ".conf",
+  ".ini",
+  ".json",
+  ".log",
+  ".xml",
+  ".yaml",
+  ".yml",
+];
+
+// Returns the text of the files received when they are a single small text
+// file and receiveTextFilesAsText is enabled, or null otherwise
+const readSmallTextFile = (filePaths: string[]) => {
   if (
-    isThereMoreThanOneClipboardFile() &&
-    lastTimeWritten &&
-    currentFileTime <= lastTimeWritten
+    !config.get("receiveTextFilesAsText", false) ||
+    filePaths.length !== 1 ||
+    !fs.statSync(filePaths[0]).isFile() ||
+    fs.statSync(filePaths[0]).size >
+      config.get("maxTextFileAsTextKb", 64) * 1024 ||
+    !isFileTypeListed(filePaths[0], textFileTypes)
   ) {
-    return;
+    return null;
+  }
+  const data = fs.readFileSync(filePaths[0]);
+  const text = data.toString("utf8");
+  // Binary content with a text extension
+  if (data.includes(0) || text.includes("\ufffd")) {
+    return null;
   }
+  return text;
+};
 
-  if (fileClipboardType === "text") {
-    clipboard.writeText(newText);
-    lastTextRead = newText;
-  } else if (fileClipboardType === "image") {
-    clipboard.writeImage(nativeImage.createFromBuffer(newImage));
-    lastImageSha256Read = newImageSha256;
-  } else if (fileClipboardType === "files") {
-    clipboardEx.writeFilePaths(newFilePaths);
-    lastClipboardFilePathsRead = newFilePaths;
+const isFilesSizeAllowed = (filePaths: string[]) => {
+  const maxFilesSizeMb = config.get("maxFilesSizeMb", 100);
+  const filesSizeInMb = getFilesSizeInMb(filePaths);
+  if (filesSizeInMb > maxFilesSizeMb) {
+    console.error(
+      `Files of ${filesSizeInMb.toFixed(
+        1
+      )}MB exceed the maximum size of ${maxFilesSizeMb}MB. Skipping...`
+    );
+    return false;
   }
-  console.log(`Clipboard was read from ${file}`);
-  lastTimeRead = currentTime;
+  return true;
+};
 
-  setIconFor5Seconds("clipboard_received");
+const isImageSizeAllowed = (sizeInBytes: number) => {
+  const maxImageSizeMb = config.get("maxImageSizeMb", 50);
+  if (sizeInBytes > maxImageSizeMb * 1024 * 1024) {
+    console.error(
+      `Image of ${(sizeInBytes / (1024 * 1024)).toFixed(
+        1
+      )}MB exceeds the maximum size of ${maxImageSizeMb}MB. Skipping...`
+    );
+    return false;
+  }
+  return true;
 };
 
-const cleanFiles = () => {
-  const currentTimeMinus5Min = Date.now() - 300000;
-  fs.readdirSync(syncFolder).forEach((file) => {
-    const filePath = path.join(syncFolder, file);
-    if (getItemNumber(filePath)) {
-      const fileStat = fs.statSync(filePath);
-      if (fileStat.ctime.getTime() <= currentTimeMinus5Min) {
-        if (fileStat.isDirectory()) {
-          deleteFolderRecursive(filePath);
-        } else {
-          fs.unlinkSync(filePath);
-        }
-      }
+// Decoded images take 4 bytes per pixel
+// Only notifies about the images received, as the ones copied here are
+// skipped on every clipboard event while they are copied
+const isImageWithinMemoryBudget = (size: Electron.Size, notify = true) => {
+  const maxImageMemoryMb = config.get("maxImageMemoryMb", 512);
+  const imageMemoryMb = (size.width * size.height * 4) / (1024 * 1024);
+  if (imageMemoryMb > maxImageMemoryMb) {
+    console.error(
+      `Image of ${size.width}x${size.height} would take ${Math.round(
+        imageMemoryMb
+      )}MB of memory, more than the budget of ${maxImageMemoryMb}MB. Skipping...`
+    );
+    if (notify) {
+      notifyError(
+        "Image too large",
+        `An image of ${size.width}x${size.height} was not synced to save memory.`
+      );
     }
-  });
+    return false;
+  }
+  return true;
 };
 
-const askForFolder = () => {
-  let previousFolder = config.get("folder");
-
-  const foldersSelected = dialog.showOpenDialogSync({
-    title: "Select folder to save and read clipboard files",
-    properties: ["openDirectory"],
-    defaultPath: previousFolder,
-  });
+// Checks the dimensions before the image gets decoded when possible.
+// Returns null if the image exceeds the memory budget.
+const readClipboardImage = () => {
+  const pngDimensions = getPngDimensions(clipboard.readBuffer("image/png"));
+  if (pngDimensions && !isImageWithinMemoryBudget(pngDimensions, false)) {
+    return null;
+  }
+  const image = clipboard.readImage();
+  if (!isImageWithinMemoryBudget(image.getSize(), false)) {
+    return null;
+  }
+  return image;
+};
 
-  let folderSelected;
-  if (foldersSelected) {
-    folderSelected = foldersSelected[0];
+// Checks the limits without reading the whole image
+const isImageFileAllowed = (file: string) => {
+  if (!isImageSizeAllowed(fs.statSync(file).size)) {
+    return false;
+  }
+  const header = Buffer.alloc(24);
+  const fileDescriptor = fs.openSync(file, "r");
+  try {
+    fs.readSync(fileDescriptor, header, 0, header.length, 0);
+  } finally {
+    fs.closeSync(fileDescriptor);
+  }
+  const dimensions = getPngDimensions(header);
+  if (!dimensions) {
+    console.error(`File ${file} is not a valid PNG image. Skipping...`);
+    return false;
   }
+  return (
+    isImageDimensionAllowed(dimensions) &&
+    isImageWithinMemoryBudget(dimensions)
+  );
+};
 
-  if (!folderSelected && !previousFolder) {
-    dialog.showErrorBox(
-      "Folder was not selected",
-      "Please start the application again to select a folder."
+// Network shares fail with EBUSY while another computer has the file open
+// (ERROR_SHARING_VIOLATION on Windows), or with EACCES while it holds a lock
+// on it, which usually goes away quickly. EACCES is only taken for a lock when
+// the file can be accessed otherwise.
+const isSharingViolation = (error: unknown) => {
+  const { code, path: file } = error as NodeJS.ErrnoException;
+  if (code === "EBUSY") {
+    return true;
+  }
+  if (code !== "EACCES" || !file) {
+    return false;
+  }
+  try {
+    fs.accessSync(
+      fs.existsSync(file) ? file : path.dirname(file),
+      fs.constants.R_OK | fs.constants.W_OK
     );
-    finish(1);
+    return true;
+  } catch (accessError) {
+    return false;
+  }
+};
+
+// Retried a few times after 200ms, 400ms and so on, in network share mode
+const maxSharingViolationRetries = 5;
+let writeSharingViolations = 0;
+const readSharingViolations = new Map<string, number>();
+
+const shouldRetrySharingViolation = (error: unknown, attempt: number) => {
+  return (
+    config.get("networkShareMode", false) &&
+    attempt <= maxSharingViolationRetries &&
+    isSharingViolation(error)
+  );
+};
+
+// Runs a command without blocking the main thread. The status is null when it
+// could not be run or timed out.
+const runCommand = (
+  command: string,
+  args: string[],
+  options: { timeout?: number; input?: string; shell?: boolean } = {}
+) => {
+  return new Promise<{ status: number; stdout: string; stderr: string }>(
+    (resolve) => {
+      let stdout = "";
+      let stderr = "";
+      const child = spawn(command, args, {
+        shell: options.shell,
+        timeout: options.timeout ?? 5000,
+        windowsHide: true,
+      });
+      child.stdout.on("data", (data) => (stdout += data));
+      child.stderr.on("data", (data) => (stderr += data));
+      // Both error and close are emitted when it fails to run
+      child.on("error", () => resolve({ status: null, stdout, stderr }));
+      child.on("close", (status) => resolve({ status, stdout, stderr }));
+      child.stdin.on("error", () => undefined);
+      child.stdin.end(options.input);
+    }
+  );
+};
+
+const fsyncPath = (fileOrFolder: string) => {
+  const isDirectory = fs.statSync(fileOrFolder).isDirectory();
+  // Folders can't be opened to be flushed on Windows, where NTFS journals
+  // their entries anyway
+  if (isDirectory && process.platform === "win32") {
     return;
-  } else if (!folderSelected) {
+  }
+  const fileDescriptor = fs.openSync(fileOrFolder, isDirectory ? "r" : "r+");
+  try {
+    fs.fsyncSync(fileDescriptor);
+  } finally {
+    fs.closeSync(fileDescriptor);
+  }
+};
+
+// With durableWrites, the files written to the folder and the folder listing
+// them are flushed to the disk once written, so a power loss can't leave a
+// truncated file behind for the other computers to read
+const flushToDisk = (fileOrFolder: string) => {
+  if (!config.get("durableWrites", false)) {
     return;
   }
-  syncFolder = folderSelected;
-  config.set("folder", folderSelected);
+  const flushRecursively = (filePath: string) => {
+    if (fs.statSync(filePath).isDirectory()) {
+      fs.readdirSync(filePath).forEach((file) =>
+        flushRecursively(path.join(filePath, file))
+      );
+    }
+    fsyncPath(filePath);
+  };
+  try {
+    flushRecursively(fileOrFolder);
+    fsyncPath(path.dirname(fileOrFolder));
+  } catch (error) {
+    console.error(`Error flushing ${fileOrFolder} to the disk: ${error}`);
+  }
+};
 
-  if (folderSelected !== previousFolder) {
-    reload();
+const calculateSha256 = (data: Buffer) => {
+  return createHash("sha256").update(data).digest("hex");
+};
+
+// Reads the file in chunks, so large files don't need to fit in memory
+const updateHashWithFile = (hash: Hash, file: string) => {
+  const chunk = Buffer.alloc(1024 * 1024);
+  const fileDescriptor = fs.openSync(file, "r");
+  try {
+    let bytesRead = fs.readSync(fileDescriptor, chunk, 0, chunk.length, null);
+    while (bytesRead > 0) {
+      hash.update(chunk.subarray(0, bytesRead));
+      bytesRead = fs.readSync(fileDescriptor, chunk, 0, chunk.length, null);
+    }
+  } finally {
+    fs.closeSync(fileDescriptor);
+  }
+  return hash;
+};
+
+// A cheap fingerprint from the dimensions and a downscaled copy of the image.
+// Different fingerprints mean different images, but equal ones still need to
+// be confirmed with the full hash.
+const calculateImagePreHash = (image: Electron.NativeImage) => {
+  const size = image.getSize();
+  return createHash("sha256")
+    .update(`${size.width}x${size.height}`)
+    .update(image.resize({ width: 16, height: 16 }).toBitmap())
+    .digest("hex");
+};
+
+const isSameImage = (
+  image: Electron.NativeImage,
+  otherImage: Electron.NativeImage
+) => {
+  if (!image || !otherImage) {
+    return false;
+  }
+  if (calculateImagePreHash(image) !== calculateImagePreHash(otherImage)) {
+    return false;
+  }
+  return calculateSha256(image.toPNG()) === calculateSha256(otherImage.toPNG());
+};
+
+// The image copied last, so the same one isn't encoded and hashed again on
+// every clipboard event. Only compared in full when the pre-hashes match.
+let lastImageEncoded: {
+  preHash: string;
+  image: Electron.NativeImage;
+  png: Buffer;
+  sha256: string;
+} = null;
+
+const encodeClipboardImage = (image: Electron.NativeImage) => {
+  const preHash = calculateImagePreHash(image);
+  if (
+    lastImageEncoded &&
+    lastImageEncoded.preHash === preHash &&
+    lastImageEncoded.image.toBitmap().equals(image.toBitmap())
+  ) {
+    return lastImageEncoded;
+  }
+  const png = image.toPNG();
+  lastImageEncoded = { preHash, image, png, sha256: calculateSha256(png) };
+  return lastImageEncoded;
+};
+
+const calculateFileSha256 = (file: string) => {
+  return updateHashWithFile(createHash("sha256"), file).digest("hex");
+};
+
+// Hashes the relative paths and contents of all the files
+const calculateFilesSha256 = (paths: string[]) => {
+  const hash = createHash("sha256");
+  [...paths].sort().forEach((fileOrFolder) => {
+    iterateThroughFilesRecursively([fileOrFolder], (file) => {
+      hash.update(path.relative(path.dirname(fileOrFolder), file));
+      hash.update("\0");
+      updateHashWithFile(hash, file);
+      hash.update("\0");
+    });
+  });
+  return hash.digest("hex");
+};
+
+// Written inside the _files folders, so the others can tell when every file
+// was fully synced, and not only that they are all present
+const filesManifestName = ".clipboard-sync-manifest.json";
+
+const getFilesManifest = (paths: string[]) => {
+  const entries: FilesManifestEntry[] = [];
+  paths.forEach((fileOrFolder) => {
+    iterateThroughFilesRecursively([fileOrFolder], (file) => {
+      entries.push({
+        path: path
+          .relative(path.dirname(fileOrFolder), file)
+          .split(path.sep)
+          .join("/"),
+        size: fs.statSync(file).size,
+        sha256: calculateFileSha256(file),
+        zoneIdentifier: readZoneIdentifier(file),
+      });
+    });
+  });
+  return entries;
+};
+
+// The paths must stay inside the folder, as they come from other computers
+const isValidFilesManifest = (
+  manifest: unknown
+): manifest is FilesManifestEntry[] => {
+  return (
+    Array.isArray(manifest) &&
+    manifest.every(
+      (entry) =>
+        entry &&
+        typeof entry.path === "string" &&
+        !entry.path.includes("\\") &&
+        !path.posix.isAbsolute(entry.path) &&
+        !path.win32.isAbsolute(entry.path) &&
+        entry.path
+          .split("/")
+          .every((part: string) => part && part !== "." && part !== "..") &&
+        typeof entry.size === "number" &&
+        typeof entry.sha256 === "string" &&
+        (entry.zoneIdentifier === undefined ||
+          typeof entry.zoneIdentifier === "string")
+    )
+  );
+};
+
+// Returns the entries whose files are missing or differ, like the ones still
+// being synced
+const getFilesNotMatchingManifest = (
+  folder: string,
+  manifest: FilesManifestEntry[]
+) => {
+  return manifest.filter((entry) => {
+    const file = path.join(folder, ...entry.path.split("/"));
+    try {
+      return (
+        fs.statSync(file).size !== entry.size ||
+        calculateFileSha256(file) !== entry.sha256
+      );
+    } catch (error) {
+      return true;
+    }
+  });
+};
+
+// Works as a logical clock: one more than the newest item number seen, also
+// the ones already cleaned up, so it never goes back whatever the clocks say
+const getNextWriteTime = () => {
+  const numbers: number[] = [
+    lastTimeWritten || 0,
+    ...itemNumbersSeen.values(),
+  ];
+  fs.readdirSync(syncFolder).forEach((file) => {
+    file = path.join(syncFolder, file);
+    const itemNumber = getItemNumber(file);
+    if (itemNumber) {
+      numbers.push(itemNumber);
+    }
+  });
+  if (numbers.length > 0) {
+    // https://stackoverflow.com/a/1063027/12156188
+    return (
+      numbers
+        .sort((a, b) => {
+          return a - b;
+        })
+        .at(-1) + 1
+    );
+  }
+  return 1;
+};
+
+const isThereMoreThanOneClipboardFile = () => {
+  let found = 0;
+  fs.readdirSync(syncFolder).forEach((file) => {
+    file = path.join(syncFolder, file);
+    const itemNumber = getItemNumber(file);
+    if (itemNumber) {
+      found++;
+      if (found > 1) {
+        return;
+      }
+    }
+  });
+  return found > 1;
+};
+
+const deriveEncryptionKey = (passphrase: string) => {
+  let salt = config.get("passphraseSalt");
+  if (!salt) {
+    salt = randomBytes(16).toString("hex");
+    config.set("passphraseSalt", salt);
+  }
+  return scryptSync(passphrase, salt, 32);
+};
+
+// Allows telling whether a passphrase is the one chosen before
+const getPassphraseCheck = (key: Buffer) => {
+  return createHmac("sha256", key).update("clipboard-sync").digest("hex");
+};
+
+// The key is kept protected by the operating system when possible, so the
+// passphrase does not need to be asked on every start
+const storeEncryptionKey = (key: Buffer) => {
+  if (safeStorage.isEncryptionAvailable()) {
+    config.set(
+      "protectedEncryptionKey",
+      safeStorage.encryptString(key.toString("hex")).toString("base64")
+    );
+  }
+};
+
+// returns null if the passphrase was not given or is wrong
+const loadEncryptionKey = async () => {
+  const protectedKey = config.get("protectedEncryptionKey");
+  if (protectedKey && safeStorage.isEncryptionAvailable()) {
+    try {
+      return Buffer.from(
+        safeStorage.decryptString(Buffer.from(protectedKey, "base64")),
+        "hex"
+      );
+    } catch (error) {
+      console.error("Error decrypting the stored encryption key");
+    }
+  }
+
+  const passphrase = await askForText({
+    title: "Encryption passphrase",
+    message: `Enter the passphrase to unlock ${app.name}`,
+    password: true,
+  });
+  if (!passphrase) {
+    return null;
+  }
+  const key = deriveEncryptionKey(passphrase);
+  if (getPassphraseCheck(key) !== config.get("passphraseCheck")) {
+    dialog.showErrorBox(
+      "Wrong passphrase",
+      "The passphrase does not match the one chosen before."
+    );
+    return null;
+  }
+  storeEncryptionKey(key);
+  return key;
+};
+
+const escapeHtml = (text: string) => {
+  return text
+    .replace(/&/g, "&amp;")
+    .replace(/</g, "&lt;")
+    .replace(/>/g, "&gt;")
+    .replace(/"/g, "&quot;");
+};
+
+// The windows run isolated and without Node.js, talking to the app only
+// through the preload script, on the channel given
+const getWindowWebPreferences = (
+  channel: string
+): Electron.WebPreferences => ({
+  contextIsolation: true,
+  nodeIntegration: false,
+  preload: path.join(__dirname, "preload.js"),
+  additionalArguments: [`--ipc-channel=${channel}`],
+});
+
+// Shows a small window asking for a text, resolves to null if it is closed
+const askForText = (options: {
+  title: string;
+  message: string;
+  password?: boolean;
+}): Promise<string> => {
+  return new Promise((resolve) => {
+    const channel = `ask-for-text-${Date.now()}`;
+    let answer: string = null;
+    const window = new BrowserWindow({
+      width: 420,
+      height: 170,
+      title: options.title,
+      icon: getAppIcon(),
+      resizable: false,
+      minimizable: false,
+      maximizable: false,
+      autoHideMenuBar: true,
+      webPreferences: getWindowWebPreferences(channel),
+    });
+    ipcMain.once(channel, (event, value: string) => {
+      answer = value;
+      window.close();
+    });
+    window.on("closed", () => {
+      ipcMain.removeAllListeners(channel);
+      resolve(answer);
+    });
+    const html = `<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>${escapeHtml(options.title)}</title>
+    <style>
+      body { font-family: sans-serif; font-size: 14px; margin: 16px; }
+      input { width: 100%; box-sizing: border-box; margin: 12px 0; }
+      div { text-align: right; }
+    </style>
+  </head>
+  <body>
+    <form id="form">
+      <label for="text">${escapeHtml(options.message)}</label>
+      <input id="text" type="${options.password ? "password" : "text"}" autofocus />
+      <div><button type="submit">OK</button></div>
+    </form>
+    <script>
+      document.getElementById("form").addEventListener("submit", (event) => {
+        event.preventDefault();
+        window.clipboardSync.send(document.getElementById("text").value);
+      });
+    </script>
+  </body>
+</html>`;
+    window.loadURL(`data:text/html;charset=utf-8,${encodeURIComponent(html)}`);
+  });
+};
+
+const getPluginsFolder = () => {
+  return path.join(app.getPath("userData"), "plugins");
+};
+
+// WebAssembly plugins are named after their file, and Lua plugins keep the
+// extension in the name, so both kinds can be told apart in the menu
+const getAvailablePlugins = () => {
+  const pluginsFolder = getPluginsFolder();
+  if (!fs.existsSync(pluginsFolder)) {
+    return [];
+  }
+  return fs
+    .readdirSync(pluginsFolder)
+    .filter((file) => [".wasm", ".lua"].includes(path.extname(file)))
+    .map((file) =>
+      path.extname(file) === ".wasm" ? path.parse(file).name : file
+    );
+};
+
+const getPluginFile = (name: string) => {
+  return path.join(
+    getPluginsFolder(),
+    path.extname(name) === ".lua" ? name : `${name}.wasm`
+  );
+};
+
+const pluginTimeoutMs = 5000;
+const maxPluginMemoryMb = 64;
+
+// Runs in a worker, so a plugin stuck or using too much memory can be stopped
+// without blocking the app. No imports are given to the plugin, which keeps it
+// sandboxed.
+const wasmPluginWorkerSource = `
+const { parentPort, workerData } = require("worker_threads");
+const fs = require("fs");
+
+const plugin = new WebAssembly.Instance(
+  new WebAssembly.Module(fs.readFileSync(workerData.file)),
+  {}
+).exports;
+if (
+  !(plugin.memory instanceof WebAssembly.Memory) ||
+  typeof plugin.alloc !== "function"
+) {
+  throw new Error("The plugin does not export memory and alloc");
+}
+
+const call = (request) => {
+  if (typeof plugin[request.call] !== "function") {
+    return {};
+  }
+  if (request.call === "accepts") {
+    return { vetoed: plugin.accepts(request.direction, request.type) === 0 };
+  }
+  const input = Buffer.from(request.text, "utf8");
+  const pointer = plugin.alloc(input.length);
+  new Uint8Array(plugin.memory.buffer, pointer, input.length).set(input);
+  const result = plugin.on_text(request.direction, pointer, input.length);
+  if (result === -1) {
+    return { vetoed: true };
+  } else if (result === 0) {
+    return {};
+  }
+  const length = new DataView(plugin.memory.buffer).getUint32(result, true);
+  return {
+    text: Buffer.from(plugin.memory.buffer, result + 4, length).toString(
+      "utf8"
+    ),
+  };
+};
+
+parentPort.on("message", (request) => {
+  const response = call(request);
+  // The memory of WebAssembly is not counted by the limits of the worker
+  if (plugin.memory.buffer.byteLength > workerData.maxMemory) {
+    throw new Error("The plugin used too much memory");
+  }
+  parentPort.postMessage({ id: request.id, ...response });
+});
+`;
+
+// Runs the plugin with only the safe standard libraries available, so it can't
+// reach the files, the network or other programs. Given the plugin file, the
+// function to call and its arguments, one per line, the last one being the
+// text.
+const luaPluginRunner = [
+  'local file = assert(io.open(io.read("*l"), "rb"))',
+  'local source = file:read("*a")',
+  "file:close()",
+  'local name, direction, value = io.read("*l"), io.read("*l"), io.read("*a")',
+  "local env = {",
+  "  string = string, table = table, math = math, utf8 = utf8,",
+  "  pairs = pairs, ipairs = ipairs, next = next, select = select,",
+  "  type = type, tostring = tostring, tonumber = tonumber,",
+  "  error = error, assert = assert, pcall = pcall,",
+  "}",
+  'assert(load(source, "=plugin", "t", env))()',
+  "local result = nil",
+  'if type(env[name]) == "function" then',
+  "  result = env[name](direction, value)",
+  "end",
+  "if result == false then",
+  '  io.write("veto")',
+  'elseif type(result) == "string" then',
+  '  io.write("text\\n", result)',
+  "end",
+].join("\n");
+
+const startPluginWorker = (plugin: ClipboardPlugin) => {
+  const worker = new Worker(wasmPluginWorkerSource, {
+    eval: true,
+    workerData: {
+      file: plugin.file,
+      maxMemory: maxPluginMemoryMb * 1024 * 1024,
+    },
+    resourceLimits: {
+      maxOldGenerationSizeMb: maxPluginMemoryMb,
+      maxYoungGenerationSizeMb: 16,
+      stackSizeMb: 4,
+    },
+  });
+  worker.on("error", (error) => {
+    console.error(`Error running plugin ${plugin.name}: ${error}`);
+  });
+  // Started again on the next call
+  worker.on("exit", () => {
+    if (plugin.worker === worker) {
+      plugin.worker = null;
+    }
+  });
+  plugin.worker = worker;
+  return worker;
+};
+
+let lastPluginRequestId = 0;
+
+const callWasmPlugin = (plugin: ClipboardPlugin, request: PluginRequest) => {
+  const worker = plugin.worker || startPluginWorker(plugin);
+  const id = ++lastPluginRequestId;
+  return new Promise<PluginResponse>((resolve, reject) => {
+    const handleMessage = (response: PluginResponse & { id: number }) => {
+      if (response.id === id) {
+        finish();
+        resolve(response);
+      }
+    };
+    const handleExit = () => {
+      finish();
+      reject(new Error("The plugin stopped"));
+    };
+    const timeout = setTimeout(() => {
+      finish();
+      worker.terminate();
+      reject(new Error(`The plugin took more than ${pluginTimeoutMs}ms`));
+    }, pluginTimeoutMs);
+    const finish = () => {
+      clearTimeout(timeout);
+      worker.off("message", handleMessage);
+      worker.off("exit", handleExit);
+    };
+    worker.on("message", handleMessage);
+    worker.on("exit", handleExit);
+    worker.postMessage({
+      id,
+      call: request.call,
+      direction: request.direction === "send" ? 0 : 1,
+      type: ["text", "image", "files"].indexOf(request.type),
+      text: request.text,
+    });
+  });
+};
+
+const callLuaPlugin = async (
+  plugin: ClipboardPlugin,
+  request: PluginRequest
+): Promise<PluginResponse> => {
+  const result = await runCommand("lua", ["-e", luaPluginRunner], {
+    input: [
+      plugin.file,
+      request.call,
+      request.direction,
+      request.call === "accepts" ? request.type : request.text,
+    ].join("\n"),
+    timeout: pluginTimeoutMs,
+  });
+  if (result.status !== 0) {
+    throw new Error(result.stderr || "lua could not be run");
+  }
+  if (result.stdout === "veto") {
+    return { vetoed: true };
+  } else if (result.stdout.startsWith("text\n")) {
+    return { text: result.stdout.substring("text\n".length) };
+  }
+  return {};
+};
+
+// Failures keep the clipboard unchanged
+const callPlugin = async (
+  plugin: ClipboardPlugin,
+  request: PluginRequest
+): Promise<PluginResponse> => {
+  try {
+    return await (path.extname(plugin.file) === ".lua"
+      ? callLuaPlugin(plugin, request)
+      : callWasmPlugin(plugin, request));
+  } catch (error) {
+    console.error(`Error running plugin ${plugin.name}: ${error}`);
+    notifyError("Plugin failed", `The plugin ${plugin.name} failed to run.`);
+    return {};
+  }
+};
+
+// Latest outputs of the plugins, by hash of the call and its input, and the
+// calls still running, like for the filter commands
+const pluginOutputs = new Map<string, boolean | string | null>();
+const pluginRuns = new Map<string, Promise<void>>();
+const maxPluginOutputs = 20;
+
+const unloadPlugins = () => {
+  clipboardPlugins.forEach((plugin) => plugin.worker?.terminate());
+  clipboardPlugins = [];
+  pluginOutputs.clear();
+  pluginRuns.clear();
+};
+
+const loadPlugins = () => {
+  unloadPlugins();
+  const enabledPlugins = config.get("plugins", {});
+  getAvailablePlugins().forEach((name) => {
+    if (!enabledPlugins[name]) {
+      return;
+    }
+    const plugin: ClipboardPlugin = { name, file: getPluginFile(name) };
+    // Started right away so a broken plugin shows up in the logs now
+    if (path.extname(plugin.file) === ".wasm") {
+      startPluginWorker(plugin);
+    }
+    clipboardPlugins.push(plugin);
+    console.log(`Plugin ${name} loaded`);
+  });
+};
+
+// Runs the plugins in the background, so the first time this returns a promise
+// resolved once their output is kept, and the clipboard must then be synced
+// again
+const getPluginsOutput = <T extends boolean | string | null>(
+  key: string,
+  run: () => Promise<T>
+): T | Promise<void> => {
+  if (pluginOutputs.has(key)) {
+    return pluginOutputs.get(key) as T;
+  }
+  if (pluginRuns.has(key)) {
+    return pluginRuns.get(key);
+  }
+  const pluginRun = run().then((output) => {
+    if (pluginOutputs.size >= maxPluginOutputs) {
+      pluginOutputs.delete(pluginOutputs.keys().next().value);
+    }
+    pluginOutputs.set(key, output);
+    pluginRuns.delete(key);
+  });
+  pluginRuns.set(key, pluginRun);
+  return pluginRun;
+};
+
+// returns false if any plugin vetoed the clipboard
+const isAcceptedByPlugins = (
+  clipboardType: ClipboardType,
+  direction: ClipboardDirection
+): boolean | Promise<void> => {
+  if (clipboardPlugins.length === 0) {
+    return true;
+  }
+  const key = `accepts\0${direction}\0${clipboardType}`;
+  return getPluginsOutput(key, async () => {
+    for (const plugin of clipboardPlugins) {
+      const response = await callPlugin(plugin, {
+        call: "accepts",
+        direction,
+        type: clipboardType,
+      });
+      if (response.vetoed) {
+        console.log(`Clipboard vetoed by plugin ${plugin.name}`);
+        return false;
+      }
+    }
+    return true;
+  });
+};
+
+// returns null if any plugin vetoed the text
+const runPluginsOnText = (
+  text: string,
+  direction: ClipboardDirection
+): string | null | Promise<void> => {
+  if (clipboardPlugins.length === 0) {
+    return text;
+  }
+  const key = calculateSha256(
+    Buffer.from(`on_text\0${direction}\0${text}`, "utf8")
+  );
+  return getPluginsOutput(key, async () => {
+    let output = text;
+    for (const plugin of clipboardPlugins) {
+      const response = await callPlugin(plugin, {
+        call: "on_text",
+        direction,
+        type: "text",
+        text: output,
+      });
+      if (response.vetoed) {
+        console.log(`Clipboard vetoed by plugin ${plugin.name}`);
+        return null;
+      }
+      if (response.text !== undefined) {
+        output = response.text;
+      }
+    }
+    return output;
+  });
+};
+
+// Latest outputs of the filter commands, by hash of the command and the text,
+// and the commands still running
+const filterCommandOutputs = new Map<string, string | null>();
+const filterCommandRuns = new Map<string, Promise<void>>();
+const maxFilterCommandOutputs = 20;
+
+// Pipes the text through the filter command configured for the direction,
+// like git filters do. The command runs in the background, so the first time
+// this returns a promise resolved once its output is kept, and the clipboard
+// must then be synced again. Returns null if the command fails.
+const runFilterCommandOnText = (
+  text: string,
+  direction: ClipboardDirection
+): string | null | Promise<void> => {
+  const filterCommand = config.get(
+    direction === "send" ? "sendFilterCommand" : "receiveFilterCommand"
+  );
+  if (!filterCommand) {
+    return text;
+  }
+  const key = calculateSha256(
+    Buffer.from(`${filterCommand}\0${text}`, "utf8")
+  );
+  if (filterCommandOutputs.has(key)) {
+    const output = filterCommandOutputs.get(key);
+    // Failures are run again the next time the same text is copied
+    if (output === null) {
+      filterCommandOutputs.delete(key);
+    }
+    return output;
+  }
+  if (filterCommandRuns.has(key)) {
+    return filterCommandRuns.get(key);
+  }
+  const run = runCommand(filterCommand, [], {
+    input: text,
+    shell: true,
+    timeout: 10000,
+  }).then((result) => {
+    let output = result.stdout;
+    if (result.status !== 0) {
+      console.error(
+        `Error running ${direction} filter command: ${result.stderr}`
+      );
+      notifyError(
+        "Filter command failed",
+        `The ${direction} filter command failed, so the clipboard was not synced.`
+      );
+      output = null;
+    }
+    if (filterCommandOutputs.size >= maxFilterCommandOutputs) {
+      filterCommandOutputs.delete(filterCommandOutputs.keys().next().value);
+    }
+    filterCommandOutputs.set(key, output);
+    filterCommandRuns.delete(key);
+  });
+  filterCommandRuns.set(key, run);
+  return run;
+};
+
+// Whether all the given computers announced they run on the same OS as this
+// one, so the text copied there can be pasted here as is
+const isSameOsAsPeers = (peers: string[]) => {
+  return (
+    peers.length > 0 &&
+    peers.every((peer) => peerPlatforms.get(peer) === process.platform)
+  );
+};
+
+// Replaces unpaired surrogates, which can't be encoded as UTF-8, with the
+// replacement character. With sanitizeText enabled, also strips control
+// characters (except tabs and line breaks) and zero-width spaces. The
+// zero-width joiners are kept, as emoji sequences and scripts like Persian and
+// Devanagari need them. With rawTextForSameOs, the text is only repaired
+// between computers on the same OS.
+const sanitizeText = (text: string, peers: string[]) => {
+  text = text.replace(
+    /[\uD800-\uDBFF](?![\uDC00-\uDFFF])|(?<![\uD800-\uDBFF])[\uDC00-\uDFFF]/g,
+    "\uFFFD"
+  );
+  if (!config.get("sanitizeText", false)) {
+    return text;
+  }
+  if (config.get("rawTextForSameOs", false) && isSameOsAsPeers(peers)) {
+    return text;
+  }
+  return text
+    .replace(/[\u0000-\u0008\u000B\u000C\u000E-\u001F\u007F]/g, "")
+    .replace(/[\u200B\u2060\uFEFF]/g, "");
+};
+
+const isSingleUrl = (text: string) => {
+  const trimmedText = text.trim();
+  if (!trimmedText || /\s/.test(trimmedText)) {
+    return false;
+  }
+  try {
+    const url = new URL(trimmedText);
+    return url.protocol === "http:" || url.protocol === "https:";
+  } catch (error) {
+    return false;
+  }
+};
+
+// Both computers decide the same way, so they end up with the same clipboard
+const isConflictWonByRemote = (
+  remoteHostname: string,
+  remoteItemNumber: number,
+  remoteTime: number
+) => {
+  const policy = config.get("conflictPolicy", "latest");
+  const preferredHost = config.get("conflictPreferredHost");
+  if (policy === "local") {
+    return false;
+  }
+  if (
+    policy === "host" &&
+    preferredHost &&
+    (preferredHost === hostname || preferredHost === remoteHostname)
+  ) {
+    return preferredHost === remoteHostname;
+  }
+  // The item numbers are the same on both computers, unlike the times
+  if (remoteItemNumber !== lastTimeWritten) {
+    return remoteItemNumber > lastTimeWritten;
+  }
+  // The clocks of the computers may differ, so the remote time is corrected by
+  // the offset estimated from the probes, and when the copies are too close to
+  // tell, both computers keep the same one, by hostname
+  const clockOffset = peerClockOffsets.get(remoteHostname);
+  const correctedRemoteTime = remoteTime - (clockOffset?.offsetMs || 0);
+  const toleranceMs = Math.max(
+    clockSkewToleranceMs,
+    clockOffset?.uncertaintyMs || 0
+  );
+  if (Math.abs(correctedRemoteTime - lastSentTime) > toleranceMs) {
+    return correctedRemoteTime > lastSentTime;
+  }
+  return remoteHostname > hostname;
+};
+
+// Lets the user switch to the clipboard which was not kept
+const notifyConflict = (
+  remoteHostname: string,
+  keptLocal: boolean,
+  discardedSummary: string,
+  keepDiscarded: () => void
+) => {
+  if (!config.get("conflictNotifications", false) || isQuiet()) {
+    return;
+  }
+  const notification = new Notification({
+    title: `Copied at the same time as ${getDeviceName(remoteHostname)}`,
+    body: `Kept the clipboard ${
+      keptLocal ? "copied here" : `from ${getDeviceName(remoteHostname)}`
+    }. Click to use ${discardedSummary} instead.`,
+    icon: getAppIcon(),
+    actions: [{ type: "button", text: "Use the other one" }],
+  });
+  // Actions are only supported on macOS, clicking works everywhere
+  notification.on("action", keepDiscarded);
+  notification.on("click", keepDiscarded);
+  notification.show();
+};
+
+const formatSize = (sizeInBytes: number) => {
+  return sizeInBytes >= 1024 * 1024
+    ? `${(sizeInBytes / (1024 * 1024)).toFixed(1)}MB`
+    : `${Math.ceil(sizeInBytes / 1024)}KB`;
+};
+
+// Lists the files received with their sizes, the executables first and
+// marked, so the decision to receive them is informed
+const getFilesPreview = (file: string, executableFiles: string[]) => {
+  const maxListedFiles = 15;
+  const entries = iterateThroughFilesRecursively([file], (filePath) => ({
+    name: path.relative(file, filePath),
+    size: fs.statSync(filePath).size,
+    executable: executableFiles.includes(filePath),
+  })) as { name: string; size: number; executable: boolean }[];
+  entries.sort(
+    (a, b) =>
+      Number(b.executable) - Number(a.executable) ||
+      a.name.localeCompare(b.name)
+  );
+  const totalSize = entries.reduce((total, entry) => total + entry.size, 0);
+  const lines = entries
+    .slice(0, maxListedFiles)
+    .map(
+      (entry) =>
+        `${entry.executable ? "⚠ " : ""}${entry.name} (${formatSize(
+          entry.size
+        )})`
+    );
+  if (entries.length > maxListedFiles) {
+    lines.push(`and ${entries.length - maxListedFiles} more`);
+  }
+  lines.push(`${entries.length} files, ${formatSize(totalSize)} in total`);
+  return lines.join("\n");
+};
+
+// Resolves to true when the user allows receiving them, and then the next
+// read of the file puts them on the clipboard
+const confirmExecutableFiles = async (
+  file: string,
+  senderHostname: string,
+  executableFiles: string[]
+) => {
+  executableFilesAsked.add(file);
+  const senderName = getDeviceName(senderHostname);
+  console.log(
+    `Files from ${senderName} include executables, asking before receiving them`
+  );
+  const { response } = await dialog.showMessageBox({
+    type: "warning",
+    title: "Receive executable files",
+    message: `The files copied on ${senderName} include some which can run code when opened`,
+    detail: `${getFilesPreview(
+      file,
+      executableFiles
+    )}\n\nOnly receive them if you copied them yourself.`,
+    buttons: ["Receive", "Skip"],
+    defaultId: 1,
+    cancelId: 1,
+  });
+  executableFilesAsked.delete(file);
+  if (response !== 0) {
+    console.log(`Skipped the executable files from ${senderName}`);
+    recordActivity({
+      direction: "receive",
+      hostname: senderHostname,
+      type: "files",
+      outcome: "skipped",
+      reason: "executable files not accepted",
+    });
+    return false;
+  }
+  executableFilesAllowed.add(file);
+  return true;
+};
+
+// Splits a command line into the program and its arguments, by the spaces
+// outside double quotes. Backslashes are kept, as in Windows paths.
+const splitCommandLine = (commandLine: string) => {
+  return (commandLine.match(/(?:[^\s"]+|"[^"]*")+/g) || []).map((part) =>
+    part.replace(/"/g, "")
+  );
+};
+
+// Runs the scan command on the folder of the files received, which must exit
+// with 0 for them to be put on the clipboard by the next read. The folder is
+// appended to the command, or replaces {path} in it.
+const scanReceivedFiles = (file: string, senderHostname: string) => {
+  filesBeingScanned.add(file);
+  // Run without a shell, so the names of the files received can't inject
+  // commands
+  const [command, ...args] = splitCommandLine(
+    config.get("receiveScanCommand")
+  );
+  const scanArgs = args.some((arg) => arg.includes("{path}"))
+    ? args.map((arg) => arg.split("{path}").join(file))
+    : [...args, file];
+  const senderName = getDeviceName(senderHostname);
+  console.log(`Scanning the files received from ${senderName}...`);
+  return new Promise<boolean>((resolve) => {
+    let output = "";
+    const child = spawn(command, scanArgs, {
+      windowsHide: true,
+      timeout: 300000,
+    });
+    child.stdout.on("data", (data) => (output += data));
+    child.stderr.on("data", (data) => (output += data));
+    const finishScan = (clean: boolean, reason?: string) => {
+      // Both error and close are emitted when it fails to run
+      if (!filesBeingScanned.delete(file)) {
+        return;
+      }
+      if (clean) {
+        filesScannedClean.add(file);
+        console.log(`Files received from ${senderName} are clean`);
+      } else {
+        console.error(
+          `Files received from ${senderName} were blocked by the scan: ${reason}\n${output.trim()}`
+        );
+        recordActivity({
+          direction: "receive",
+          hostname: senderHostname,
+          type: "files",
+          outcome: "failed",
+          reason: `blocked by the scan (${reason})`,
+        });
+        notifyError(
+          "Received files were blocked",
+          `The scan command rejected the files from ${senderName} (${reason}), so they were not put on the clipboard.`
+        );
+      }
+      resolve(clean);
+    };
+    child.on("error", (error) => finishScan(false, error.message));
+    child.on("close", (code, signal) => {
+      if (code === 0) {
+        finishScan(true);
+      } else {
+        finishScan(false, signal ? `stopped by ${signal}` : `exit code ${code}`);
+      }
+    });
+  });
+};
+
+// Opens the link right away if the sender is trusted, otherwise offers it
+const handleReceivedLink = (link: string, senderHostname: string) => {
+  link = link.trim();
+  if (config.get("autoOpenLinksFrom", []).includes(senderHostname)) {
+    console.log(`Opening link received from ${getDeviceName(senderHostname)}`);
+    shell.openExternal(link);
+    return;
+  }
+
+  if (!config.get("linkNotifications", true) || isQuiet()) {
+    return;
+  }
+
+  const notification = new Notification({
+    title: `Link received from ${getDeviceName(senderHostname)}`,
+    body: link,
+    icon: getAppIcon(),
+    actions: [{ type: "button", text: "Open link" }],
+  });
+  // Actions are only supported on macOS, clicking works everywhere
+  notification.on("action", () => {
+    shell.openExternal(link);
+  });
+  notification.on("click", () => {
+    shell.openExternal(link);
+  });
+  notification.show();
+};
+
+let skipNextCopy = false;
+let skipCopiesUntil: number = null;
+
+const skipCopies = (seconds?: number) => {
+  if (seconds) {
+    skipCopiesUntil = Date.now() + seconds * 1000;
+    console.log(`Not sending clipboards for ${seconds} seconds`);
+  } else {
+    skipNextCopy = true;
+    console.log("Not sending the next clipboard");
+  }
+  setContextMenu();
+};
+
+const resumeCopies = () => {
+  skipNextCopy = false;
+  skipCopiesUntil = null;
+  setContextMenu();
+};
+
+const isSkippingCopies = () => {
+  return (
+    skipNextCopy || (skipCopiesUntil !== null && Date.now() < skipCopiesUntil)
+  );
+};
+
+const getSkipResendWindowInMs = () => {
+  return config.get("skipResendWithinMinutes", 0) * 60 * 1000;
+};
+
+// Only while it is still the latest clipboard sent or received, so copying
+// A, then B, then A again still sends A
+const wasRecentlySent = (sha256: string) => {
+  const sentTime = state.get("sentSha256", {})[sha256];
+  return (
+    sha256 === lastSyncedSha256 &&
+    sentTime &&
+    Date.now() - sentTime < getSkipResendWindowInMs()
+  );
+};
+
+const setRecentlySent = (sha256: string) => {
+  lastSyncedSha256 = sha256;
+  const minTime = Date.now() - getSkipResendWindowInMs();
+  const sentSha256 = Object.fromEntries(
+    Object.entries(state.get("sentSha256", {})).filter(
+      ([, sentTime]) => sentTime > minTime
+    )
+  );
+  sentSha256[sha256] = Date.now();
+  state.set("sentSha256", sentSha256);
+};
+
+const getToday = () => {
+  return new Date().toISOString().slice(0, 10);
+};
+
+const getBytesWrittenToday = () => {
+  return state.get("bytesWrittenByDay", {})[getToday()] || 0;
+};
+
+// Keeps the bytes written per day for the last month
+const recordBytesWritten = (bytes: number) => {
+  const today = getToday();
+  const oneMonthAgo = new Date(Date.now() - 30 * 24 * 60 * 60 * 1000)
+    .toISOString()
+    .slice(0, 10);
+  const bytesWrittenByDay = Object.fromEntries(
+    Object.entries(state.get("bytesWrittenByDay", {})).filter(
+      ([day]) => day >= oneMonthAgo
+    )
+  );
+  const previousBytesWritten = bytesWrittenByDay[today] || 0;
+  bytesWrittenByDay[today] = previousBytesWritten + bytes;
+  state.set("bytesWrittenByDay", bytesWrittenByDay);
+
+  const dailyWriteBudgetMb = config.get("dailyWriteBudgetMb", 1024);
+  const dailyWriteBudget = dailyWriteBudgetMb * 1024 * 1024;
+  if (
+    previousBytesWritten <= dailyWriteBudget &&
+    bytesWrittenByDay[today] > dailyWriteBudget
+  ) {
+    notifyError(
+      "Daily budget exceeded",
+      `More than ${dailyWriteBudgetMb}MB were written to the folder today.`
+    );
+  }
+};
+
+// Sums the size of everything created by the app in the folder
+const updateFolderUsage = () => {
+  if (!syncFolder || !fs.existsSync(syncFolder)) {
+    return;
+  }
+  const appFiles = fs
+    .readdirSync(syncFolder)
+    .map((file) => path.join(syncFolder, file))
+    .filter(
+      (file) =>
+        parseClipboardFile(file) ||
+        isPinnedFile(file) ||
+        /^[0-9a-zA-Z-]+\.history$/.test(path.basename(file))
+    );
+  const previousFolderUsageInMb = folderUsageInMb;
+  folderUsageInMb = getFilesSizeInMb(appFiles);
+
+  const folderBudgetMb = config.get("folderBudgetMb", 1024);
+  if (
+    (previousFolderUsageInMb === null ||
+      previousFolderUsageInMb <= folderBudgetMb) &&
+    folderUsageInMb > folderBudgetMb
+  ) {
+    notifyError(
+      "Folder budget exceeded",
+      `The files in the folder take ${Math.round(
+        folderUsageInMb
+      )}MB, more than the budget of ${folderBudgetMb}MB.`
+    );
+  }
+  updateToolTip();
+};
+
+let recentTransfers: { sha256: string; time: number }[] = [];
+const quarantinedUntilBySha256 = new Map<string, number>();
+
+// Detects the same clipboard bouncing between computers, like when another
+// clipboard tool touches the clipboards received, and quarantines it
+const trackTransfer = (sha256: string) => {
+  const currentTime = Date.now();
+  recentTransfers = recentTransfers.filter(
+    (transfer) => currentTime - transfer.time < syncLoopWindowMs
+  );
+  recentTransfers.push({ sha256, time: currentTime });
+  if (
+    recentTransfers.filter((transfer) => transfer.sha256 === sha256).length >=
+    syncLoopThreshold
+  ) {
+    quarantinedUntilBySha256.set(sha256, currentTime + syncLoopQuarantineMs);
+    recentTransfers = recentTransfers.filter(
+      (transfer) => transfer.sha256 !== sha256
+    );
+    console.error("Sync loop detected, quarantining clipboard");
+    notifyError(
+      "Sync loop detected",
+      "The same clipboard kept being sent and received, so it will not be synced for 10 minutes. Another clipboard tool may be causing it."
+    );
+  }
+};
+
+const isQuarantined = (sha256: string) => {
+  const quarantinedUntil = quarantinedUntilBySha256.get(sha256);
+  if (quarantinedUntil && Date.now() < quarantinedUntil) {
+    return true;
+  }
+  quarantinedUntilBySha256.delete(sha256);
+  return false;
+};
+
+let windowsCloudClipboardEnabled = false;
+const localClipboardTimeBySha256 = new Map<string, number>();
+const receivedClipboardTimeBySha256 = new Map<string, number>();
+// The content of the latest clipboard sent or received
+let lastSyncedSha256: string = null;
+
+// Whether Windows syncs the clipboard between devices by itself, which is
+// enabled through the clipboard history (Win+V) settings
+const refreshWindowsCloudClipboard = async () => {
+  if (process.platform !== "win32") {
+    return;
+  }
+  const result = await runCommand(
+    "reg",
+    [
+      "query",
+      "HKCU\\Software\\Microsoft\\Clipboard",
+      "/v",
+      "EnableCloudClipboard",
+    ],
+    { timeout: 2000 }
+  );
+  const enabled =
+    result.status === 0 &&
+    /EnableCloudClipboard\s+REG_DWORD\s+0x1\b/.test(result.stdout);
+  if (enabled !== windowsCloudClipboardEnabled) {
+    console.log(`Windows cloud clipboard ${enabled ? "enabled" : "disabled"}`);
+  }
+  windowsCloudClipboardEnabled = enabled;
+};
+
+const rememberClipboardSha256 = (
+  timeBySha256: Map<string, number>,
+  sha256: string
+) => {
+  const minTime = Date.now() - cloudClipboardWindowMs;
+  timeBySha256.forEach((time, otherSha256) => {
+    if (time < minTime) {
+      timeBySha256.delete(otherSha256);
+    }
+  });
+  timeBySha256.set(sha256, Date.now());
+};
+
+const isClipboardSha256Remembered = (
+  timeBySha256: Map<string, number>,
+  sha256: string
+) => {
+  const time = timeBySha256.get(sha256);
+  return time && Date.now() - time < cloudClipboardWindowMs;
+};
+
+let lastTimeChecked: number = null;
+
+// Logs what an app put on the clipboard, so users can report the formats
+// which are not supported
+const traceClipboardFormats = (formats: string[]) => {
+  formats.forEach((format) => {
+    try {
+      const size = clipboard.readBuffer(format).length;
+      console.log(`Clipboard format ${format}: ${size} bytes`);
+    } catch (error) {
+      console.log(
+        `Clipboard format ${format}: unreadable (${getErrorDescription(
+          error
+        )})`
+      );
+    }
+  });
+  try {
+    const filePaths = clipboardEx.readFilePaths();
+    if (filePaths.length > 0) {
+      console.log(`Clipboard file paths: ${filePaths.join(", ")}`);
+    }
+  } catch (error) {
+    console.log(
+      `Clipboard file paths: unreadable (${getErrorDescription(error)})`
+    );
+  }
+};
+
+const clipboardTypeFormats: Record<ClipboardType, string> = {
+  text: "text/plain",
+  image: "image/png",
+  files: "text/uri-list",
+};
+
+// Which type is sent when the clipboard has several, like the text and the
+// image of cells copied from a spreadsheet. The types missing from the
+// setting come last, in the default order.
+const getClipboardTypePriority = () => {
+  const defaultPriority: ClipboardType[] = ["text", "image", "files"];
+  const priority = config
+    .get("clipboardTypePriority", defaultPriority)
+    .filter((type) => defaultPriority.includes(type));
+  return [...new Set([...priority, ...defaultPriority])];
+};
+
+// Forcing skips the checks for duplicates, to send it even if it was already
+const writeClipboardToFile = (force = false) => {
+  // Prevents duplicated clipboard events
+  const currentTime = Date.now();
+  if (
+    !force &&
+    lastTimeChecked &&
+    currentTime - lastTimeChecked < getTimingSetting("clipboardDebounceMs")
+  ) {
+    return;
+  }
+  lastTimeChecked = currentTime;
+
+  let clipboardType: ClipboardType;
+  let clipboardText: string;
+  let clipboardImage: Buffer;
+  let clipboardImageSha256: string;
+  let clipboardFilePaths: string[];
+  let clipboardFilesCount: number;
+  const clipboardFormats = clipboard.availableFormats();
+  const availableType = getClipboardTypePriority().find((type) =>
+    clipboardFormats.includes(clipboardTypeFormats[type])
+  );
+
+  try {
+    if (availableType === "text") {
+      clipboardText = clipboard.readText();
+      clipboardType = "text";
+    } else if (availableType === "image") {
+      const image = readClipboardImage();
+      if (!image || !isImageDimensionAllowed(image.getSize())) {
+        return;
+      }
+      const encodedImage = encodeClipboardImage(image);
+      clipboardImage = encodedImage.png;
+      if (!isImageSizeAllowed(clipboardImage.length)) {
+        return;
+      }
+      clipboardImageSha256 = encodedImage.sha256;
+      clipboardType = "image";
+    } else if (availableType === "files") {
+      clipboardFilePaths = clipboardEx.readFilePaths();
+      clipboardType = "files";
+    }
+  } catch (error) {
+    console.error("Error reading current clipboard");
+  }
+
+  if (!clipboardType) {
+    if (clipboardFormats.length > 0) {
+      console.log("Unknown clipboard format. Skipping...");
+      if (config.get("traceClipboardFormats", false)) {
+        traceClipboardFormats(clipboardFormats);
+      }
+    }
+    return;
+  }
+
+  if (clipboardType === "files" && isZeroKnowledgeMode()) {
+    console.log("Files are not sent in zero-knowledge mode. Skipping...");
+    return;
+  }
+
+  if (clipboardType === "files") {
+    const filesNotAllowed = getFilesNotAllowed(clipboardFilePaths);
+    if (filesNotAllowed.length > 0) {
+      console.log(
+        `Files of a type not allowed: ${filesNotAllowed.join(", ")}. Skipping...`
+      );
+      return;
+    }
+  }
+
+  // Prevent sending the clipboard that was just received
+  const receivedEchoWindowMs = getTimingSetting("receivedEchoWindowMs");
+  if (
+    clipboardType === "text" &&
+    (!clipboardText ||
+      (!force &&
+        lastTimeRead &&
+        currentTime - lastTimeRead < receivedEchoWindowMs &&
+        lastTextRead === clipboardText))
+  ) {
+    return;
+  }
+
+  if (
+    clipboardType === "image" &&
+    (!clipboardImage ||
+      (!force &&
+        lastTimeRead &&
+        currentTime - lastTimeRead < receivedEchoWindowMs &&
+        lastImageSha256Read === clipboardImageSha256))
+  ) {
+    return;
+  }
+
+  if (
+    clipboardType === "files" &&
+    (!clipboardFilePaths ||
+      (!force &&
+        lastTimeRead &&
+        currentTime - lastTimeRead < receivedEchoWindowMs &&
+        isArrayEquals(lastClipboardFilePathsRead, clipboardFilePaths)) ||
+      !isFilesSizeAllowed(clipboardFilePaths))
+  ) {
+    return;
+  }
+
+  if (!force && isSkippingCopies()) {
+    console.log("Skipping clipboard as requested");
+    skipNextCopy = false;
+    setContextMenu();
+    return;
+  }
+
+  if (getHoldReason()) {
+    console.log(`Skipping clipboard while ${getHoldReason()}`);
+    return;
+  }
+
+  if (config.get("suspendSendsWhilePresenting", false) && isPresenting()) {
+    console.log("Skipping clipboard while presenting");
+    return;
+  }
+
+  const accepted = isAcceptedByPlugins(clipboardType, "send");
+  if (accepted instanceof Promise) {
+    // Sent again once the plugins answer
+    accepted.then(() => {
+      lastTimeChecked = null;
+      handleClipboardChange(force);
+    });
+    return;
+  }
+  if (!accepted) {
+    return;
+  }
+
+  if (clipboardType === "text") {
+    const pluginText = runPluginsOnText(clipboardText, "send");
+    if (pluginText instanceof Promise) {
+      // Sent again once the plugins finish
+      pluginText.then(() => {
+        lastTimeChecked = null;
+        handleClipboardChange(force);
+      });
+      return;
+    }
+    clipboardText = pluginText;
+    if (clipboardText) {
+      const filteredText = runFilterCommandOnText(clipboardText, "send");
+      if (filteredText instanceof Promise) {
+        // Sent again once the filter command finishes
+        filteredText.then(() => {
+          lastTimeChecked = null;
+          handleClipboardChange(force);
+        });
+        return;
+      }
+      clipboardText = filteredText;
+    }
+    if (clipboardText) {
+      clipboardText = sanitizeText(clipboardText, [
+        ...new Set([...sequencesSeen.keys(), ...peerPlatforms.keys()]),
+      ]);
+    }
+    if (!clipboardText) {
+      return;
+    }
+  }
+
+  const maxTextSizeKb = config.get("maxTextSizeKb", 0);
+  if (
+    clipboardType === "text" &&
+    maxTextSizeKb > 0 &&
+    Buffer.byteLength(clipboardText, "utf8") > maxTextSizeKb * 1024
+  ) {
+    if (isZeroKnowledgeMode()) {
+      console.log(
+        "Large texts are not sent in zero-knowledge mode, as they are sent as files. Skipping..."
+      );
+      return;
+    }
+    const largeTextFile = writeLargeTextFile(clipboardText);
+    if (!largeTextFile || getFilesNotAllowed([largeTextFile]).length > 0) {
+      return;
+    }
+    clipboardType = "files";
+    clipboardFilePaths = [largeTextFile];
+    clipboardText = undefined;
+  }
+
+  const contentSha256 =
+    clipboardType === "text"
+      ? calculateSha256(Buffer.from(clipboardText, "utf8"))
+      : clipboardType === "image"
+      ? clipboardImageSha256
+      : calculateFilesSha256(clipboardFilePaths);
+  if (!force && wasRecentlySent(contentSha256)) {
+    console.log("Same clipboard was already sent recently. Skipping...");
+    recordActivity({
+      direction: "send",
+      hostname,
+      type: clipboardType,
+      outcome: "skipped",
+      reason: "duplicate, already sent recently",
+    });
+    return;
+  }
+
+  // A clipboard which another computer sent may also arrive through the
+  // Windows cloud clipboard, and then it must not be sent back
+  if (windowsCloudClipboardEnabled && !force) {
+    if (
+      isClipboardSha256Remembered(receivedClipboardTimeBySha256, contentSha256)
+    ) {
+      console.log(
+        "Clipboard came through the Windows cloud clipboard. Skipping..."
+      );
+      return;
+    }
+    rememberClipboardSha256(localClipboardTimeBySha256, contentSha256);
+  }
+
+  // When the Apple device where it was copied is also syncing, it already sent
+  // the clipboard itself
+  if (isUniversalClipboard() && !force) {
+    if (
+      isClipboardSha256Remembered(receivedClipboardTimeBySha256, contentSha256)
+    ) {
+      console.log("Clipboard came through Universal Clipboard. Skipping...");
+      return;
+    }
+    if (!config.get("sendUniversalClipboard", true)) {
+      console.log(
+        "Clipboards from Universal Clipboard are not sent. Skipping..."
+      );
+      return;
+    }
+  }
+
+  if (isQuarantined(contentSha256)) {
+    console.log("Clipboard is quarantined due to a sync loop. Skipping...");
+    recordActivity({
+      direction: "send",
+      hostname,
+      type: clipboardType,
+      outcome: "skipped",
+      reason: "quarantined due to a sync loop",
+    });
+    return;
+  }
+
+  const isSensitive = isSensitiveClipboard();
+  const writeTime = getNextWriteTime();
+  const sendTarget = getSendTarget();
+  const compressText =
+    clipboardType === "text" && shouldCompressText(clipboardText);
+  const archiveFiles =
+    clipboardType === "files" && config.get("archiveFiles", false);
+  const markSensitive =
+    isSensitive && isFeatureSupportedByPeers("sensitive-mark");
+  const fileBaseName = `${writeTime}-${hostname}${getChannelSuffix(
+    getCurrentChannel()
+  )}${getTargetSuffix(sendTarget)}${getSensitiveSuffix(markSensitive)}`;
+  // Listed in the index before being written, so the others can tell what
+  // it is as soon as it shows up
+  const opaqueName = isUsingOpaqueFilenames()
+    ? addToOpaqueIndex({
+        itemNumber: writeTime,
+        hostname,
+        channel: getCurrentChannel(),
+        type: clipboardType,
+        filesCount:
+          clipboardType === "files"
+            ? getTotalNumberOfFiles(clipboardFilePaths)
+            : undefined,
+        sealed: isZeroKnowledgeMode(),
+        target: sendTarget,
+        compressed: compressText || undefined,
+        archived: archiveFiles || undefined,
+        sensitive: isSensitive || undefined,
+      })
+    : null;
+  let destinationPath: string;
+  let bytesWritten = 0;
+  if (clipboardType === "text") {
+    destinationPath = path.join(
+      syncFolder,
+      opaqueName || `${fileBaseName}.${getTextExtension(compressText)}`
+    );
+    if (isZeroKnowledgeMode()) {
+      const sealedText = sealPayload(Buffer.from(clipboardText, "utf8"));
+      bytesWritten = sealedText.length;
+      fs.writeFileSync(destinationPath, sealedText);
+    } else if (compressText) {
+      const compressedText = zlib.brotliCompressSync(
+        Buffer.from(clipboardText, "utf8")
+      );
+      bytesWritten = compressedText.length;
+      fs.writeFileSync(destinationPath, compressedText);
+      console.log(
+        `Text compressed from ${formatSize(
+          Buffer.byteLength(clipboardText, "utf8")
+        )} to ${formatSize(compressedText.length)}`
+      );
+    } else {
+      bytesWritten = Buffer.byteLength(clipboardText, "utf8");
+      fs.writeFileSync(destinationPath, clipboardText, {
+        encoding: "utf8",
+      });
+    }
+    flushToDisk(destinationPath);
+    lastTextWritten = clipboardText;
+    if (!isSensitive) {
+      addToHistory("text", clipboardText, hostname);
+    }
+  } else if (clipboardType === "image") {
+    destinationPath = path.join(
+      syncFolder,
+      opaqueName || `${fileBaseName}.png`
+    );
+    const imageData = isZeroKnowledgeMode()
+      ? sealPayload(clipboardImage)
+      : clipboardImage;
+    bytesWritten = imageData.length;
+    fs.writeFileSync(destinationPath, imageData);
+    flushToDisk(destinationPath);
+    lastImageSha256Written = clipboardImageSha256;
+    if (!isSensitive) {
+      addToHistory("image", clipboardImage, hostname);
+    }
+  } else if (clipboardType === "files") {
+    clipboardFilesCount = getTotalNumberOfFiles(clipboardFilePaths);
+    destinationPath = path.join(
+      syncFolder,
+      opaqueName ||
+        `${fileBaseName}.${clipboardFilesCount}_files${
+          archiveFiles ? ".tar" : ""
+        }`
+    );
+    const filesSha256 = contentSha256;
+    const previousDestinationPath = filesWrittenBySha256.get(filesSha256);
+    if (
+      previousDestinationPath &&
+      fs.existsSync(previousDestinationPath) &&
+      !!parseClipboardFile(previousDestinationPath)?.archived === archiveFiles
+    ) {
+      // Moving is cheaper than uploading the same files again
+      fs.renameSync(previousDestinationPath, destinationPath);
+      console.log(`Reusing files already written to ${previousDestinationPath}`);
+    } else if (archiveFiles) {
+      // Written under a temporary name, so the others never see it partially
+      const partialPath = `${destinationPath}.part`;
+      try {
+        writeTarArchive(clipboardFilePaths, partialPath);
+        flushToDisk(partialPath);
+        fs.renameSync(partialPath, destinationPath);
+        flushToDisk(destinationPath);
+      } catch (error) {
+        fs.rmSync(partialPath, { force: true });
+        throw error;
+      }
+      bytesWritten = fs.statSync(destinationPath).size;
+    } else {
+      bytesWritten = getFilesSizeInMb(clipboardFilePaths) * 1024 * 1024;
+      // Tells the others to not read the folder while it is being populated
+      const lockPath = getLockPath(destinationPath);
+      fs.writeFileSync(lockPath, hostname, { encoding: "utf8" });
+      try {
+        fs.mkdirSync(destinationPath);
+        if (config.get("filesManifest", false)) {
+          fs.writeFileSync(
+            path.join(destinationPath, filesManifestName),
+            JSON.stringify({ files: getFilesManifest(clipboardFilePaths) }),
+            { encoding: "utf8" }
+          );
+        }
+        clipboardFilePaths.forEach((filePath: string) => {
+          const fullDestination = path.join(
+            destinationPath,
+            path.basename(filePath)
+          );
+          if (fs.statSync(filePath).isDirectory()) {
+            copyFolderRecursive(filePath, fullDestination);
+          } else {
+            fs.copyFileSync(filePath, fullDestination);
+          }
+        });
+        flushToDisk(destinationPath);
+      } finally {
+        fs.unlinkSync(lockPath);
+      }
+    }
+    filesWrittenBySha256.forEach((writtenPath, sha256) => {
+      if (!fs.existsSync(writtenPath)) {
+        filesWrittenBySha256.delete(sha256);
+      }
+    });
+    filesWrittenBySha256.set(filesSha256, destinationPath);
+    lastClipboardFilePathsWritten = clipboardFilePaths;
+  }
+  console.log(`Clipboard written to ${destinationPath}`);
+  lastTimeWritten = writeTime;
+  lastPathWritten = destinationPath;
+  writeSequenceFile(writeTime, sendTarget);
+  cleanClipboardsByCount();
+  recordBytesWritten(bytesWritten);
+  updateFolderUsage();
+  lastSentTime = Date.now();
+  clipEvents.emit("clip", {
+    direction: "send",
+    type: clipboardType,
+    hostname,
+    channel: getCurrentChannel(),
+    size: bytesWritten,
+    time: lastSentTime,
+  } as ClipEvent);
+  setRecentlySent(contentSha256);
+  trackTransfer(contentSha256);
+  sendWakeHint();
+  // The transports reach every computer, so the clipboards sent to a single
+  // one only go through the folder
+  if ((clipboardType === "text" || clipboardType === "image") && !sendTarget) {
+    sendToTransports({
+      id: getClipId({
+        itemNumber: writeTime,
+        hostname,
+        channel: getCurrentChannel(),
+        type: clipboardType,
+      }),
+      itemNumber: writeTime,
+      hostname,
+      channel: getCurrentChannel(),
+      type: clipboardType,
+      data:
+        clipboardType === "text"
+          ? Buffer.from(clipboardText, "utf8").toString("base64")
+          : clipboardImage.toString("base64"),
+      time: lastSentTime,
+      sensitive: isSensitive || undefined,
+    });
+  }
+
+  setIconFor5Seconds("clipboard_sent");
+  setMenuBarTitle(
+    "send",
+    hostname,
+    clipboardType === "text"
+      ? `${clipboardText.length} chars`
+      : clipboardType === "image"
+      ? formatSize(clipboardImage.length)
+      : `${clipboardFilePaths.length} files`
+  );
+  setContextMenu();
+};
+
+// While writes to the folder keep failing, like when the disk is full or the
+// share is disconnected, they are retried with an exponential backoff instead
+// of on every clipboard change
+const handleClipboardChange = (force = false) => {
+  if (writeRetryWaiter) {
+    return;
+  }
+  try {
+    writeClipboardToFile(force);
+    writeSharingViolations = 0;
+    if (writeFailures > 0) {
+      console.log("Writing to the folder works again");
+      writeFailures = 0;
+      writeError = null;
+      updateToolTip();
+      setContextMenu();
+    }
+  } catch (error) {
+    if (shouldRetrySharingViolation(error, writeSharingViolations + 1)) {
+      writeSharingViolations++;
+      console.log(`The folder is in use: ${error}. Retrying...`);
+      writeRetryWaiter = setTimeout(() => {
+        writeRetryWaiter = null;
+        handleClipboardChange(force);
+      }, 200 * writeSharingViolations);
+      return;
+    }
+    writeSharingViolations = 0;
+    writeFailures++;
+    writeError = getErrorDescription(error);
+    const retryDelay = Math.min(
+      1000 * 2 ** (writeFailures - 1),
+      maxWriteRetryDelayMs
+    );
+    recordActivity({
+      direction: "send",
+      hostname,
+      outcome: "failed",
+      reason: writeError,
+    });
+    console.error(
+      `Error writing clipboard to folder: ${error}. Retrying in ${
+        retryDelay / 1000
+      } seconds...`
+    );
+    notifyError(
+      "Could not send clipboard",
+      `Error writing to the folder: ${writeError}`
+    );
+    writeRetryWaiter = setTimeout(() => {
+      writeRetryWaiter = null;
+      handleClipboardChange();
+    }, retryDelay);
+    updateToolTip();
+    setContextMenu();
+  }
+};
+
+// The files received in archives are extracted to a temporary folder, which
+// is removed along with the archive by the cleanup
+const extractReceivedArchive = (file: string) => {
+  const extractedFolder = path.join(app.getPath("temp"), `${app.name}-files`);
+  const destination = path.join(extractedFolder, path.basename(file));
+  if (!fs.existsSync(destination)) {
+    fs.mkdirSync(extractedFolder, { recursive: true });
+    fs.readdirSync(extractedFolder).forEach((name) => {
+      if (!fs.existsSync(path.join(syncFolder, name))) {
+        deleteFolderRecursive(path.join(extractedFolder, name));
+      }
+    });
+    // Renamed once complete, so a partial extraction is never reused
+    const partialDestination = `${destination}.part`;
+    deleteFolderRecursive(partialDestination);
+    let extracted: string[];
+    try {
+      extracted = extractTarArchive(file, partialDestination);
+    } finally {
+      if (!extracted) {
+        deleteFolderRecursive(partialDestination);
+      }
+    }
+    if (!extracted) {
+      return null;
+    }
+    fs.renameSync(partialDestination, destination);
+  }
+  return fs
+    .readdirSync(destination)
+    .map((fileName: string) => path.join(destination, fileName));
+};
+
+const readClipboardFromFile = (
+  file: string,
+  channel: string = getCurrentChannel()
+) => {
+  const currentTime = Date.now();
+
+  // Clipboards received over the network are read from the inbox instead
+  if (!isInInbox(file)) {
+    const filename = path.relative(syncFolder, file).split(path.sep)[0];
+    file = path.join(syncFolder, filename);
+  }
+
+  const clipboardFile = parseClipboardFile(file);
+  if (
+    !clipboardFile ||
+    clipboardFile.hostname === hostname ||
+    clipboardFile.channel !== channel ||
+    !isForThisComputer(clipboardFile)
+  ) {
+    return;
+  }
+  const senderName = getDeviceName(clipboardFile.hostname);
+  if (!isHostAllowed(clipboardFile.hostname)) {
+    console.log(`Clipboards from ${senderName} are not accepted. Skipping...`);
+    recordActivity({
+      direction: "receive",
+      hostname: clipboardFile.hostname,
+      type: clipboardFile.type,
+      outcome: "skipped",
+      reason: "computer not allowed",
+    });
+    return;
+  }
+  const currentFileTime = clipboardFile.itemNumber;
+
+  const fileName = path.parse(file).name;
+  const fileClipboardType = clipboardFile.type;
+
+  let currentText: string;
+  let currentImage: Electron.NativeImage;
+  let currentClipboardType: ClipboardType;
+  let currentFilePaths: string[];
+
+  const clipboardFormats = clipboard.availableFormats();
+  try {
+    if (clipboardFormats.includes("text/plain")) {
+      currentText = clipboard.readText();
+      currentClipboardType = "text";
+    } else if (clipboardFormats.includes("image/png")) {
+      currentImage = readClipboardImage();
+      currentClipboardType = "image";
+    } else if (clipboardFormats.includes("text/uri-list")) {
+      currentFilePaths = clipboardEx.readFilePaths();
+      currentClipboardType = "files";
+    }
+  } catch (error) {
+    console.error("Error reading current clipboard");
+  }
+
+  let newText: string;
+  let newImage: Electron.NativeImage;
+  let newImageData: Buffer;
+  let newImageSha256: string;
+  let newFilePaths: string[];
+  let newFilesCount: number;
+  let newFilesManifest: FilesManifestEntry[];
+  try {
+    if (fileClipboardType === "text" && clipboardFile.sealed) {
+      newText = openPayload(fs.readFileSync(file)).toString("utf8");
+    } else if (fileClipboardType === "text" && clipboardFile.compressed) {
+      // Limited, as a few KB can expand to gigabytes, also when the texts
+      // sent have no limit
+      const maxTextSize =
+        (config.get("maxTextSizeKb", 0) || maxDecompressedTextMb * 1024) *
+        1024;
+      try {
+        newText = zlib
+          .brotliDecompressSync(fs.readFileSync(file), {
+            maxOutputLength: maxTextSize,
+          })
+          .toString("utf8");
+      } catch (error) {
+        if ((error as NodeJS.ErrnoException).code !== "ERR_BUFFER_TOO_LARGE") {
+          throw error;
+        }
+        console.log(
+          `Compressed text in ${file} exceeds the maximum size of ${formatSize(
+            maxTextSize
+          )}. Skipping...`
+        );
+        recordActivity({
+          direction: "receive",
+          hostname: clipboardFile.hostname,
+          type: fileClipboardType,
+          outcome: "skipped",
+          reason: "too large once decompressed",
+        });
+        return;
+      }
+    } else if (fileClipboardType === "text") {
+      newText = fs.readFileSync(file, {
+        encoding: "utf8",
+      });
+    } else if (fileClipboardType === "image" && clipboardFile.sealed) {
+      newImageData = openPayload(fs.readFileSync(file));
+      const dimensions = getPngDimensions(newImageData);
+      if (
+        !isImageSizeAllowed(newImageData.length) ||
+        !dimensions ||
+        !isImageDimensionAllowed(dimensions) ||
+        !isImageWithinMemoryBudget(dimensions)
+      ) {
+        return;
+      }
+      newImage = nativeImage.createFromBuffer(newImageData);
+      newImageSha256 = calculateSha256(newImageData);
+    } else if (fileClipboardType === "image") {
+      if (!isImageFileAllowed(file)) {
+        return;
+      }
+      // Decoded natively, so the file is not loaded into JS memory, unless it
+      // is small enough for the history
+      newImage = nativeImage.createFromPath(file);
+      newImageSha256 = calculateFileSha256(file);
+    } else if (fileClipboardType === "files") {
+      newFilesCount = clipboardFile.filesCount;
+      if (clipboardFile.archived) {
+        newFilePaths = extractReceivedArchive(file);
+        if (!newFilePaths) {
+          console.log(`Archive ${file} is still being written. Skipping...`);
+          return;
+        }
+      } else if (isLocked(file)) {
+        console.log(`Folder ${file} is still being written. Skipping...`);
+        return;
+      } else {
+        newFilePaths = fs
+          .readdirSync(file)
+          .filter((fileName: string) => fileName !== filesManifestName)
+          .map((fileName: string) => path.join(file, fileName));
+      }
+      const filesCountInFolder = getTotalNumberOfFiles(newFilePaths);
+      if (newFilesCount !== filesCountInFolder) {
+        console.error(
+          `Not all files are yet present in _files folder. Current: ${filesCountInFolder}, expected: ${newFilesCount}. Skipping...`
+        );
+        return;
+      }
+      const manifestPath = path.join(file, filesManifestName);
+      if (!clipboardFile.archived && fs.existsSync(manifestPath)) {
+        let manifest: unknown;
+        try {
+          manifest = JSON.parse(
+            fs.readFileSync(manifestPath, { encoding: "utf8" })
+          ).files;
+        } catch (error) {
+          console.log(`Manifest of ${file} is not complete yet. Skipping...`);
+          return;
+        }
+        if (!isValidFilesManifest(manifest)) {
+          console.error(`Manifest of ${file} is not valid. Skipping...`);
+          return;
+        }
+        newFilesManifest = manifest;
+        const filesNotMatching = getFilesNotMatchingManifest(file, manifest);
+        if (filesNotMatching.length > 0) {
+          console.log(
+            `Not all files in _files folder match the manifest yet: ${filesNotMatching
+              .map((entry) => entry.path)
+              .join(", ")}. Skipping...`
+          );
+          return;
+        }
+      }
+      const filesNotAllowed = getFilesNotAllowed(newFilePaths);
+      if (filesNotAllowed.length > 0) {
+        console.log(
+          `Received files of a type not allowed: ${filesNotAllowed
+            .map((filePath) => path.relative(file, filePath))
+            .join(", ")}. Skipping...`
+        );
+        return;
+      }
+    }
+  } catch (error) {
+    const attempt = (readSharingViolations.get(file) || 0) + 1;
+    if (shouldRetrySharingViolation(error, attempt)) {
+      readSharingViolations.set(file, attempt);
+      console.log(`File ${fileName} is in use: ${error}. Retrying...`);
+      setTimeout(() => readClipboardFromFile(file, channel), 200 * attempt);
+      return;
+    }
+    readSharingViolations.delete(file);
+    console.error(`Error reading clipboard from file ${fileName}`);
+    recordActivity({
+      direction: "receive",
+      hostname: clipboardFile.hostname,
+      type: fileClipboardType,
+      outcome: "failed",
+      reason: getErrorDescription(error),
+    });
+    notifyError(
+      "Could not receive clipboard",
+      `Error reading from the folder: ${getErrorDescription(error)}`
+    );
+    return;
+  }
+  readSharingViolations.delete(file);
+
+  const accepted = isAcceptedByPlugins(fileClipboardType, "receive");
+  if (accepted instanceof Promise) {
+    // Read again once the plugins answer
+    return accepted.then(() => {
+      readClipboardFromFile(file, channel);
+    });
+  }
+  if (!accepted) {
+    return;
+  }
+
+  if (fileClipboardType === "text" && newText) {
+    newText = sanitizeText(newText, [clipboardFile.hostname]);
+  }
+
+  if (fileClipboardType === "text" && newText) {
+    const pluginText = runPluginsOnText(newText, "receive");
+    if (pluginText instanceof Promise) {
+      // Read again once the plugins finish
+      return pluginText.then(() => {
+        readClipboardFromFile(file, channel);
+      });
+    }
+    newText = pluginText;
+    if (!newText) {
+      recordActivity({
+        direction: "receive",
+        hostname: clipboardFile.hostname,
+        type: fileClipboardType,
+        outcome: "skipped",
+        reason: "skipped by plugin",
+      });
+      return;
+    }
+    const filteredText = runFilterCommandOnText(newText, "receive");
+    if (filteredText instanceof Promise) {
+      // Read again once the filter command finishes
+      return filteredText.then(() => {
+        readClipboardFromFile(file, channel);
+      });
+    }
+    newText = filteredText;
+    if (!newText) {
+      recordActivity({
+        direction: "receive",
+        hostname: clipboardFile.hostname,
+        type: fileClipboardType,
+        outcome: "skipped",
+        reason: "skipped by the filter command",
+      });
+      return;
+    }
+  }
+
+  // Remembered so the same clipboard is not sent back when it also arrives
+  // through the Windows cloud clipboard or Universal Clipboard. Only macOS has
+  // the latter, and it only arrives later, so the clipboard copied right now
+  // can't tell whether it is in use.
+  if (
+    (windowsCloudClipboardEnabled || process.platform === "darwin") &&
+    ((fileClipboardType === "text" && newText) ||
+      (fileClipboardType === "image" && newImageSha256) ||
+      (fileClipboardType === "files" && newFilePaths))
+  ) {
+    const receivedSha256 =
+      fileClipboardType === "text"
+        ? calculateSha256(Buffer.from(newText, "utf8"))
+        : fileClipboardType === "image"
+        ? newImageSha256
+        : calculateFilesSha256(newFilePaths);
+    rememberClipboardSha256(receivedClipboardTimeBySha256, receivedSha256);
+    // The Windows cloud clipboard may have synced the clipboard already, even
+    // if something else was copied since then
+    if (
+      windowsCloudClipboardEnabled &&
+      isClipboardSha256Remembered(localClipboardTimeBySha256, receivedSha256)
+    ) {
+      console.log(
+        "Clipboard was already synced by the Windows cloud clipboard. Skipping..."
+      );
+      return;
+    }
+  }
+
+  if (currentClipboardType === fileClipboardType) {
+    if (
+      currentClipboardType === "text" &&
+      (!newText || currentText === newText)
+    ) {
+      // Prevents writing duplicated text to clipboard
+      recordActivity({
+        direction: "receive",
+        hostname: clipboardFile.hostname,
+        type: fileClipboardType,
+        outcome: "skipped",
+        reason: "duplicate, already on the clipboard",
+      });
+      return;
+    } else if (
+      fileClipboardType === "image" &&
+      (!newImage || newImage.isEmpty() || isSameImage(currentImage, newImage))
+    ) {
+      // Prevents writing duplicated image to clipboard
+      recordActivity({
+        direction: "receive",
+        hostname: clipboardFile.hostname,
+        type: fileClipboardType,
+        outcome: "skipped",
+        reason: "duplicate, already on the clipboard",
+      });
+      return;
+    } else if (
+      fileClipboardType === "files" &&
+      (!newFilePaths || isArrayEquals(currentFilePaths, newFilePaths))
+    ) {
+      // Prevents writing duplicated files to clipboard
+      recordActivity({
+        direction: "receive",
+        hostname: clipboardFile.hostname,
+        type: fileClipboardType,
+        outcome: "skipped",
+        reason: "duplicate, already on the clipboard",
+      });
+      return;
+    }
+  }
+
+  // Skips the read if a newer file was already wrote
+  if (
+    isThereMoreThanOneClipboardFile() &&
+    lastTimeWritten &&
+    currentFileTime < lastTimeWritten
+  ) {
+    return;
+  }
+
+  // Both computers wrote the same item number without seeing the other's
+  const isConflict =
+    isThereMoreThanOneClipboardFile() &&
+    lastTimeWritten &&
+    currentFileTime === lastTimeWritten;
+  const remoteWins =
+    isConflict &&
+    isConflictWonByRemote(
+      clipboardFile.hostname,
+      currentFileTime,
+      fs.statSync(file).mtime.getTime()
+    );
+  const remoteSummary =
+    fileClipboardType === "text"
+      ? `"${getClipboardSummary(newText)}"`
+      : `the ${fileClipboardType} from ${senderName}`;
+  if (isConflict && !remoteWins) {
+    console.log(
+      `Clipboard from ${senderName} was copied at the same time as the one here. Keeping the local one...`
+    );
+    recordActivity({
+      direction: "receive",
+      hostname: clipboardFile.hostname,
+      type: fileClipboardType,
+      outcome: "skipped",
+      reason: "copied at the same time as the one here",
+    });
+    notifyConflict(clipboardFile.hostname, true, remoteSummary, () => {
+      lastTimeRead = Date.now();
+      if (fileClipboardType === "text") {
+        lastTextRead = newText;
+        clipboard.writeText(newText);
+      } else if (fileClipboardType === "image") {
+        lastImageSha256Read = newImageSha256;
+        clipboard.writeImage(newImage);
+      } else if (fileClipboardType === "files") {
+        lastClipboardFilePathsRead = newFilePaths;
+        clipboardEx.writeFilePaths(newFilePaths);
+      }
+    });
+    return;
+  }
+
+  const contentSha256 =
+    fileClipboardType === "text"
+      ? calculateSha256(Buffer.from(newText, "utf8"))
+      : fileClipboardType === "image"
+      ? newImageSha256
+      : calculateFilesSha256(newFilePaths);
+  if (isQuarantined(contentSha256)) {
+    console.log("Clipboard is quarantined due to a sync loop. Skipping...");
+    recordActivity({
+      direction: "receive",
+      hostname: clipboardFile.hostname,
+      type: fileClipboardType,
+      outcome: "skipped",
+      reason: "quarantined due to a sync loop",
+    });
+    return;
+  }
+
+  if (
+    fileClipboardType === "files" &&
+    config.get("receiveScanCommand") &&
+    !filesScannedClean.has(file)
+  ) {
+    if (!filesBeingScanned.has(file)) {
+      scanReceivedFiles(file, clipboardFile.hostname).then((clean) => {
+        if (clean) {
+          readClipboardFromFile(file, channel);
+        }
+      });
+    }
+    return;
+  }
+
+  if (
+    fileClipboardType === "files" &&
+    config.get("confirmExecutableFiles", true) &&
+    !executableFilesAllowed.has(file)
+  ) {
+    const executableFiles = getExecutableFiles(newFilePaths);
+    if (executableFiles.length > 0) {
+      if (!executableFilesAsked.has(file)) {
+        confirmExecutableFiles(
+          file,
+          clipboardFile.hostname,
+          executableFiles
+        ).then((confirmed) => {
+          if (confirmed) {
+            readClipboardFromFile(file, channel);
+          } else {
+            filesScannedClean.delete(file);
+          }
+        });
+      }
+      return;
+    }
+  }
+  // Only forgotten now, so allowing the executables doesn't scan them again
+  filesScannedClean.delete(file);
+  executableFilesAllowed.delete(file);
+
+  lastSyncedSha256 = contentSha256;
+  if (fileClipboardType === "text") {
+    clipboard.writeText(newText);
+    lastTextRead = newText;
+    if (!clipboardFile.sensitive) {
+      addToHistory("text", newText, clipboardFile.hostname);
+    }
+    forwardToKdeConnect();
+    if (isSingleUrl(newText)) {
+      handleReceivedLink(newText, clipboardFile.hostname);
+    }
+  } else if (fileClipboardType === "image") {
+    clipboard.writeImage(newImage);
+    lastImageSha256Read = newImageSha256;
+    if (config.get("ocrCommand")) {
+      let imageFile = file;
+      let temporaryFolder: string;
+      if (newImageData) {
+        // Sealed, so it is decrypted to a temporary file only readable by
+        // this user, removed once the command finishes
+        try {
+          temporaryFolder = fs.mkdtempSync(
+            path.join(app.getPath("temp"), `${app.name}-ocr-`)
+          );
+          imageFile = path.join(temporaryFolder, "image.png");
+          fs.writeFileSync(imageFile, newImageData, { mode: 0o600 });
+        } catch (error) {
+          console.error(`Error writing image for the OCR command: ${error}`);
+          if (temporaryFolder) {
+            fs.rmSync(temporaryFolder, { recursive: true, force: true });
+          }
+          imageFile = null;
+        }
+      }
+      if (imageFile) {
+        addOcrText(imageFile, newImage, newImageSha256, temporaryFolder);
+      }
+    }
+    const imageSize = newImageData
+      ? newImageData.length
+      : fs.statSync(file).size;
+    if (clipboardFile.sensitive) {
+      console.log("Image is sensitive, so it is not added to the history");
+    } else if (imageSize <= maxHistoryImageSizeMb * 1024 * 1024) {
+      addToHistory(
+        "image",
+        newImageData || fs.readFileSync(file),
+        clipboardFile.hostname
+      );
+    } else {
+      console.log("Image is too large to be added to the history");
+    }
+  } else if (fileClipboardType === "files") {
+    markFilesFromInternet(newFilePaths, newFilesManifest);
+    const smallText = readSmallTextFile(newFilePaths);
+    lastClipboardFilePathsRead = newFilePaths;
+    if (smallText !== null) {
+      // Pasting into an editor just works, and the file is still offered
+      clipboard.writeText(smallText);
+      lastTextRead = smallText;
+      offerAlternativeClipboard(
+        `Text file received from ${senderName}`,
+        `${path.basename(
+          newFilePaths[0]
+        )} was copied as text. Click to copy it as a file instead.`,
+        "Copy as file",
+        () => {
+          lastTimeRead = Date.now();
+          clipboardEx.writeFilePaths(newFilePaths);
+        }
+      );
+    } else {
+      clipboardEx.writeFilePaths(newFilePaths);
+      if (isLargeTextFile(newFilePaths)) {
+        offerLargeTextAsText(newFilePaths[0], clipboardFile.hostname);
+      }
+    }
+  }
+  console.log(`Clipboard was read from ${file}`);
+  archiveClipboard(file, clipboardFile, newText, newImageData);
+  if (config.get("receiveNotifications", false) && !isQuiet()) {
+    new Notification({
+      title: `${
+        fileClipboardType === "text"
+          ? "Text"
+          : fileClipboardType === "image"
+          ? "Image"
+          : "Files"
+      } received from ${senderName}`,
+      body:
+        fileClipboardType === "text"
+          ? getClipboardSummary(newText)
+          : fileClipboardType === "image"
+          ? `${newImage.getSize().width}×${newImage.getSize().height}`
+          : newFilePaths.map((filePath) => path.basename(filePath)).join(", "),
+      icon:
+        fileClipboardType === "image" ? getThumbnail(newImage) : getAppIcon(),
+      silent: true,
+    }).show();
+  }
+  if (isConflict) {
+    console.log(
+      `Clipboard from ${senderName} was copied at the same time as the one here, and replaced it`
+    );
+  }
+  if (isConflict && currentClipboardType) {
+    notifyConflict(
+      clipboardFile.hostname,
+      false,
+      currentClipboardType === "text"
+        ? `"${getClipboardSummary(currentText)}"`
+        : `the ${currentClipboardType} copied here`,
+      () => {
+        if (currentClipboardType === "text") {
+          clipboard.writeText(currentText);
+        } else if (currentClipboardType === "image") {
+          clipboard.writeImage(currentImage);
+        } else if (currentClipboardType === "files") {
+          clipboardEx.writeFilePaths(currentFilePaths);
+        }
+      }
+    );
+  }
+  lastTimeRead = currentTime;
+  trackTransfer(contentSha256);
+  lastReceivedTime = currentTime;
+  clipEvents.emit("clip", {
+    direction: "receive",
+    type: fileClipboardType,
+    hostname: clipboardFile.hostname,
+    channel: clipboardFile.channel,
+    size:
+      fileClipboardType === "text"
+        ? Buffer.byteLength(newText, "utf8")
+        : fileClipboardType === "image"
+        ? fs.statSync(file).size
+        : getFilesSizeInMb(newFilePaths) * 1024 * 1024,
+    time: currentTime,
+    latency: Math.max(0, currentTime - fs.statSync(file).mtime.getTime()),
+  } as ClipEvent);
+  markClipApplied(getClipId(clipboardFile));
+  writeDeliveryAck(clipboardFile);
+
+  setIconFor5Seconds("clipboard_received");
+  setMenuBarTitle(
+    "receive",
+    clipboardFile.hostname,
+    fileClipboardType === "text"
+      ? `${newText.length} chars`
+      : fileClipboardType === "image"
+      ? formatSize(fs.statSync(file).size)
+      : `${newFilePaths.length} files`
+  );
+  setContextMenu();
+};
+
+// Applies the newest clipboard of the other computers, for when the folder
+// was synced while this one was suspended and no event was noticed
+const fetchLatestClipboard = () => {
+  if (!syncFolder || !fs.existsSync(syncFolder)) {
+    return;
+  }
+  const currentChannel = getCurrentChannel();
+  let newestFile: ClipboardFile & { file: string } = null;
+  fs.readdirSync(syncFolder).forEach((filename) => {
+    const file = path.join(syncFolder, filename);
+    const clipboardFile = parseClipboardFile(file);
+    if (
+      clipboardFile &&
+      clipboardFile.hostname !== hostname &&
+      isHostAllowed(clipboardFile.hostname) &&
+      isForThisComputer(clipboardFile) &&
+      clipboardFile.channel === currentChannel &&
+      (!newestFile || clipboardFile.itemNumber > newestFile.itemNumber)
+    ) {
+      newestFile = { ...clipboardFile, file };
+    }
+  });
+  if (!newestFile) {
+    console.log("No clipboard of the other computers in the folder to fetch");
+    new Notification({
+      title: "No clipboard to fetch",
+      body: "The other computers have no clipboard in the folder right now.",
+      icon: getAppIcon(),
+    }).show();
+    return;
+  }
+  console.log(`Fetching the latest clipboard ${newestFile.file}`);
+  try {
+    readClipboardFromFile(newestFile.file);
+  } catch (error) {
+    console.error(`Error reading clipboard from ${newestFile.file}: ${error}`);
+    notifyError(
+      "Could not fetch clipboard",
+      `Error reading from the folder: ${getErrorDescription(error)}`
+    );
+  }
+};
+
+// Cloud providers emit several events for each file written, and _files
+// folders get one for each file inside them, so the events are coalesced until
+// the folder settles. When several clipboard files arrive together, like after
+// resuming, only the newest of each type is applied.
+const handleFolderEvent = (file: string) => {
+  setPeerLastSeenTime(getPeerOfFile(file), Date.now());
+  if (isRoamingSettingsFile(file)) {
+    pendingSettingsRefresh = true;
+  } else if (isControlMessageFile(file)) {
+    pendingControlMessageFiles.add(file);
+  } else if (isPairingFile(file)) {
+    pendingPairingFiles.add(file);
+  } else if (isProbeFile(file)) {
+    pendingProbeFiles.add(file);
+  } else if (isResendRequestFile(file)) {
+    pendingResendFiles.add(file);
+  } else if (isDeliveryFile(file)) {
+    pendingDeliveryFiles.add(file);
+  } else if (isSequenceFile(file)) {
+    // Checked along with the others on every minute
+    return;
+  } else if (isOpaqueIndexFile(file)) {
+    pendingIndexRefresh = true;
+  } else if (isSharedMenuFile(file)) {
+    pendingMenuRefresh = true;
+  } else {
+    pendingClipboardFiles.add(
+      path.relative(syncFolder, file).split(path.sep)[0]
+    );
+  }
+  if (folderEventsWaiter) {
+    clearTimeout(folderEventsWaiter);
+  }
+  folderEventsWaiter = setTimeout(
+    processFolderEvents,
+    getTimingSetting("folderEventDelayMs")
+  );
+};
+
+const processFolderEvents = () => {
+  folderEventsWaiter = null;
+
+  if (pendingMenuRefresh) {
+    pendingMenuRefresh = false;
+    setContextMenu();
+  }
+
+  if (pendingIndexRefresh) {
+    pendingIndexRefresh = false;
+    // The files may have shown up before the index listing them
+    const knownNames = new Set(opaqueIndex.keys());
+    loadOpaqueIndex();
+    opaqueIndex.forEach((entry, name) => {
+      if (!knownNames.has(name) && fs.existsSync(path.join(syncFolder, name))) {
+        pendingClipboardFiles.add(name);
+      }
+    });
+  }
+
+  const currentChannel = getCurrentChannel();
+  const newestFiles = new Map<
+    ClipboardType,
+    ClipboardFile & { file: string }
+  >();
+  let receivedFiles = 0;
+  pendingClipboardFiles.forEach((filename) => {
+    const file = path.join(syncFolder, filename);
+    const clipboardFile = parseClipboardFile(file);
+    if (clipboardFile) {
+      recordItemNumberSeen(clipboardFile);
+    }
+    if (
+      !clipboardFile ||
+      clipboardFile.hostname === hostname ||
+      clipboardFile.channel !== currentChannel ||
+      !isForThisComputer(clipboardFile) ||
+      appliedClipIds.has(getClipId(clipboardFile))
+    ) {
+      return;
+    }
+    receivedFiles++;
+    const newestFile = newestFiles.get(clipboardFile.type);
+    if (!newestFile || clipboardFile.itemNumber > newestFile.itemNumber) {
+      newestFiles.set(clipboardFile.type, { ...clipboardFile, file });
+    }
+  });
+  if (receivedFiles > newestFiles.size) {
+    console.log(
+      `Applying only the newest of ${receivedFiles} clipboard files received together`
+    );
+  }
+  pendingClipboardFiles.clear();
+  if (getHoldReason() && newestFiles.size > 0) {
+    console.log(
+      `Skipping ${newestFiles.size} clipboard files received while ${getHoldReason()}`
+    );
+    newestFiles.clear();
+  }
+
+  [...newestFiles.values()]
+    .sort((a, b) => a.itemNumber - b.itemNumber)
+    .forEach(({ file }) => {
+      try {
+        readClipboardFromFile(file);
+      } catch (error) {
+        console.error(`Error reading clipboard from ${file}: ${error}`);
+        notifyError(
+          "Could not receive clipboard",
+          `Error reading from the folder: ${getErrorDescription(error)}`
+        );
+      }
+    });
+  if (receivedFiles > 0) {
+    cleanClipboardsByCount();
+  }
+
+  pendingControlMessageFiles.forEach(handleControlMessageFile);
+  pendingControlMessageFiles.clear();
+  pendingPairingFiles.forEach(handlePairingFile);
+  pendingPairingFiles.clear();
+  pendingProbeFiles.forEach(handleProbeFile);
+  pendingProbeFiles.clear();
+  pendingResendFiles.forEach(handleResendRequestFile);
+  pendingResendFiles.clear();
+  pendingDeliveryFiles.forEach(handleDeliveryFile);
+  pendingDeliveryFiles.clear();
+
+  if (pendingSettingsRefresh) {
+    pendingSettingsRefresh = false;
+    if (applyRoamingSettings()) {
+      reload();
+    }
+  }
+};
+
+const readLatestClipboardFromChannel = (channel: string) => {
+  let latestFile: string = null;
+  let latestItemNumber = 0;
+  fs.readdirSync(syncFolder).forEach((file) => {
+    file = path.join(syncFolder, file);
+    const clipboardFile = parseClipboardFile(file);
+    if (
+      clipboardFile &&
+      clipboardFile.hostname !== hostname &&
+      clipboardFile.channel === channel &&
+      isForThisComputer(clipboardFile) &&
+      clipboardFile.itemNumber > latestItemNumber
+    ) {
+      latestFile = file;
+      latestItemNumber = clipboardFile.itemNumber;
+    }
+  });
+  if (latestFile) {
+    readClipboardFromFile(latestFile, channel);
+  } else {
+    console.log(`No clipboard found in channel ${channel}`);
+  }
+};
+
+const setCurrentChannel = (channel: string) => {
+  config.set("channel", channel);
+  console.log(`Switched to channel ${channel}`);
+  setContextMenu();
+};
+
+const registerHotkey = (accelerator: string, callback: () => void) => {
+  if (!accelerator) {
+    return;
+  }
+  try {
+    if (!globalShortcut.register(accelerator, callback)) {
+      console.error(`Could not register hotkey ${accelerator}`);
+    }
+  } catch (error) {
+    console.error(`Invalid hotkey ${accelerator}: ${error}`);
+  }
+};
+
+const registerChannelHotkeys = () => {
+  const channels = getChannels();
+  Object.entries(config.get("channelHotkeys", {})).forEach(
+    ([channel, accelerator]) => {
+      if (!channels.includes(channel)) {
+        console.error(`Unknown channel ${channel} in channelHotkeys`);
+        return;
+      }
+      registerHotkey(accelerator, () => setCurrentChannel(channel));
+    }
+  );
+};
+
+const getHistoryFolder = () => {
+  return path.join(app.getPath("userData"), "history");
+};
+
+const getHistoryImagePath = (id: string) => {
+  return path.join(getHistoryFolder(), `${id}.png`);
+};
+
+const isHistoryEncrypted = () => {
+  return config.get("encryptHistory", false) && encryptionKey !== null;
+};
+
+const writeHistoryImage = (id: string, image: Buffer) => {
+  fs.mkdirSync(getHistoryFolder(), { recursive: true });
+  fs.writeFileSync(
+    getHistoryImagePath(id),
+    isHistoryEncrypted() ? encryptBuffer(image, encryptionKey) : image
+  );
+};
+
+const readHistoryImage = (id: string) => {
+  const image = fs.readFileSync(getHistoryImagePath(id));
+  return isHistoryEncrypted() ? decryptBuffer(image, encryptionKey) : image;
+};
+
+// Larger images received are left out of the history, which would need them
+// loaded fully into memory
+const maxHistoryImageSizeMb = 10;
+
+// Decoded once, as the menu is rebuilt often
+const historyThumbnails = new Map<string, Electron.NativeImage>();
+
+const getThumbnail = (image: Electron.NativeImage, size = 64) => {
+  const { width, height } = image.getSize();
+  return width >= height
+    ? image.resize({ width: Math.min(width, size) })
+    : image.resize({ height: Math.min(height, size) });
+};
+
+const getHistoryThumbnail = (id: string) => {
+  if (!historyThumbnails.has(id)) {
+    try {
+      historyThumbnails.set(
+        id,
+        getThumbnail(nativeImage.createFromBuffer(readHistoryImage(id)))
+      );
+    } catch (error) {
+      return null;
+    }
+  }
+  return historyThumbnails.get(id);
+};
+
+const getHistoryPath = () => {
+  return path.join(app.getPath("userData"), "history.json");
+};
+
+// Encrypted with AES-GCM like the clipboards, so it can't be read or changed
+// without the key derived from the passphrase
+const readHistoryFile = (encrypted: boolean): HistoryType => {
+  if (!fs.existsSync(getHistoryPath())) {
+    return { entries: [] };
+  }
+  let data = fs.readFileSync(getHistoryPath());
+  if (encrypted) {
+    try {
+      data = decryptBuffer(data, encryptionKey);
+    } catch (error) {
+      data = decryptLegacyHistory(data, encryptionKey);
+    }
+  }
+  const stored = JSON.parse(data.toString("utf8"));
+  if (!stored || !Array.isArray(stored.entries)) {
+    throw new Error("No entries found");
+  }
+  return stored;
+};
+
+// Written to another file first, which then replaces the history, so it is
+// never left half written
+const writeHistoryFile = (value: HistoryType, encrypted: boolean) => {
+  const data = Buffer.from(JSON.stringify(value, null, 2), "utf8");
+  const partialPath = `${getHistoryPath()}.partial`;
+  fs.writeFileSync(
+    partialPath,
+    encrypted ? encryptBuffer(data, encryptionKey) : data
+  );
+  fs.renameSync(partialPath, getHistoryPath());
+};
+
+// Opens the history, asking for the passphrase if it is encrypted. The
+// history stays unavailable if the passphrase is not given, or if it is not
+// kept at all, which is the default.
+const openHistory = async () => {
+  history = null;
+  if (!config.get("keepHistory", false)) {
+    return;
+  }
+  const encrypted = config.get("encryptHistory", false);
+  if (encrypted) {
+    encryptionKey = encryptionKey || (await loadEncryptionKey());
+    if (!encryptionKey) {
+      console.error("History is locked, the passphrase was not given");
+      return;
+    }
+  }
+  try {
+    history = readHistoryFile(encrypted);
+  } catch (error) {
+    // Like when it was left in the other format, or the passphrase changed.
+    // Kept aside instead of removed, in case it can still be recovered.
+    const unreadablePath = path.join(
+      app.getPath("userData"),
+      `history-unreadable-${Date.now()}.json`
+    );
+    console.error(
+      `History could not be read, moving it to ${unreadablePath}: ${error}`
+    );
+    fs.renameSync(getHistoryPath(), unreadablePath);
+    history = { entries: [] };
+    notifyError(
+      "Clipboard history could not be read",
+      `It was moved to ${unreadablePath} and a new one was started.`
+    );
+  }
+  // Also replaces the format of the older versions
+  if (encrypted) {
+    try {
+      writeHistoryFile(history, encrypted);
+    } catch (error) {
+      console.error(`Error writing history: ${error}`);
+    }
+  }
+};
+
+// Rewrites the history and its images with or without encryption. The
+// history is replaced at once, so it is never left in another format than
+// the setting says.
+const setHistoryEncryption = async (encrypt: boolean) => {
+  if (!history) {
+    return;
+  }
+  const entries = getHistoryEntries();
+  const images = entries
+    .filter((entry) => entry.type === "image")
+    .map((entry) => {
+      try {
+        return { id: entry.id, image: readHistoryImage(entry.id) };
+      } catch (error) {
+        return null;
+      }
+    })
+    .filter((image) => image !== null);
+
+  writeHistoryFile({ entries }, encrypt);
+  config.set("encryptHistory", encrypt);
+  await openHistory();
+  if (!history) {
+    return;
+  }
+  images.forEach(({ id, image }) => writeHistoryImage(id, image));
+  setHistoryEntries(entries);
+};
+
+const handleEncryptHistoryPress = async () => {
+  if (config.get("encryptHistory", false)) {
+    await setHistoryEncryption(false);
+  } else {
+    if (!encryptionKey) {
+      const passphrase = await askForText({
+        title: "Encryption passphrase",
+        message: "Choose the passphrase to encrypt the clipboard history",
+        password: true,
+      });
+      if (!passphrase) {
+        return;
+      }
+      encryptionKey = deriveEncryptionKey(passphrase);
+      config.set("passphraseCheck", getPassphraseCheck(encryptionKey));
+      storeEncryptionKey(encryptionKey);
+    }
+    await setHistoryEncryption(true);
+  }
+  writePinnedFile();
+  setContextMenu();
+};
+
+const getHistoryEntrySize = (entry: HistoryEntry) => {
+  if (entry.type === "text") {
+    return Buffer.byteLength(entry.text, "utf8");
+  }
+  try {
+    return fs.statSync(getHistoryImagePath(entry.id)).size;
+  } catch (error) {
+    return 0;
+  }
+};
+
+const pruneHistory = (entries: HistoryEntry[]) => {
+  const keptEntries = getHistoryEntriesToKeep(
+    entries,
+    {
+      maxItems: config.get("historyMaxItems", 30),
+      maxAgeDays: config.get("historyMaxAgeDays", 7),
+      maxSizeMb: config.get("historyMaxSizeMb", 50),
+    },
+    getHistoryEntrySize
+  );
+
+  const historyFolder = getHistoryFolder();
+  if (fs.existsSync(historyFolder)) {
+    const keptImages = keptEntries
+      .filter((entry) => entry.type === "image")
+      .map((entry) => `${entry.id}.png`);
+    fs.readdirSync(historyFolder).forEach((file) => {
+      if (!keptImages.includes(file)) {
+        fs.unlinkSync(path.join(historyFolder, file));
+      }
+    });
+  }
+
+  return keptEntries;
+};
+
+const getSharedHistoryFolder = (historyHostname: string) => {
+  return path.join(syncFolder, `${historyHostname}.history`);
+};
+
+// Shares the entries copied on this computer, so the others can restore them.
+// Not while the history is encrypted, as the others don't have its key.
+const writeSharedHistory = (entries: HistoryEntry[]) => {
+  if (!syncFolder || !folderAvailable || !history) {
+    return;
+  }
+  const sharedHistoryFolder = getSharedHistoryFolder(hostname);
+  try {
+    if (
+      !config.get("syncHistory", false) ||
+      isZeroKnowledgeMode() ||
+      config.get("encryptHistory", false)
+    ) {
+      deleteFolderRecursive(sharedHistoryFolder);
+      return;
+    }
+    const ownEntries = entries
+      .filter((entry) => entry.hostname === hostname)
+      .map((entry) => ({ ...entry, pinned: false }));
+    fs.mkdirSync(sharedHistoryFolder, { recursive: true });
+    const sharedImages = ownEntries
+      .filter((entry) => entry.type === "image")
+      .map((entry) => `${entry.id}.png`);
+    fs.readdirSync(sharedHistoryFolder).forEach((file) => {
+      if (file !== "index.json" && !sharedImages.includes(file)) {
+        fs.unlinkSync(path.join(sharedHistoryFolder, file));
+      }
+    });
+    sharedImages.forEach((file) => {
+      const destination = path.join(sharedHistoryFolder, file);
+      if (!fs.existsSync(destination)) {
+        fs.writeFileSync(destination, readHistoryImage(path.parse(file).name));
+      }
+    });
+    fs.writeFileSync(
+      path.join(sharedHistoryFolder, "index.json"),
+      JSON.stringify(ownEntries, null, 2),
+      { encoding: "utf8" }
+    );
+  } catch (error) {
+    console.error(`Error writing shared history: ${error}`);
+  }
+};
+
+// returns the entries shared by each of the other computers
+const readOthersSharedHistory = () => {
+  const sharedHistory: Record<string, HistoryEntry[]> = {};
+  if (
+    !config.get("syncHistory", false) ||
+    !syncFolder ||
+    !fs.existsSync(syncFolder)
+  ) {
+    return sharedHistory;
+  }
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const match = file.match(/^([0-9a-zA-Z-]+)\.history$/);
+    if (!match || match[1] === hostname) {
+      return;
+    }
+    try {
+      const entries = JSON.parse(
+        fs.readFileSync(path.join(syncFolder, file, "index.json"), {
+          encoding: "utf8",
+        })
+      );
+      if (Array.isArray(entries)) {
+        sharedHistory[match[1]] = entries.filter(
+          (entry) =>
+            (entry.type === "text" && typeof entry.text === "string") ||
+            (entry.type === "image" && /^[0-9a-f]+$/.test(entry.id))
+        );
+      }
+    } catch (error) {
+      console.error(`Error reading shared history from ${file}`);
+    }
+  });
+  return sharedHistory;
+};
+
+const getHistoryEntries = () => {
+  return history ? history.entries : [];
+};
+
+const setHistoryEntries = (entries: HistoryEntry[]) => {
+  if (!history) {
+    return;
+  }
+  history.entries = entries;
+  try {
+    writeHistoryFile(history, config.get("encryptHistory", false));
+  } catch (error) {
+    console.error(`Error writing history: ${error}`);
+  }
+  writeSharedHistory(entries);
+};
+
+const pruneHistoryNow = () => {
+  if (!history) {
+    return;
+  }
+  const entries = getHistoryEntries();
+  const keptEntries = pruneHistory(entries);
+  if (keptEntries.length !== entries.length) {
+    setHistoryEntries(keptEntries);
+    setContextMenu();
+  }
+};
+
+const isSensitiveClipboard = () => {
+  return sensitiveClipboardFormats.some((format) => {
+    try {
+      return clipboard.has(format);
+    } catch (error) {
+      return false;
+    }
+  });
+};
+
+const isUniversalClipboard = () => {
+  if (process.platform !== "darwin") {
+    return false;
+  }
+  try {
+    return clipboard.has(universalClipboardFormat);
+  } catch (error) {
+    return false;
+  }
+};
+
+const addToHistory = (
+  type: HistoryEntry["type"],
+  data: string | Buffer,
+  senderHostname: string
+) => {
+  if (!history) {
+    return;
+  }
+  try {
+    const id =
+      typeof data === "string"
+        ? calculateSha256(Buffer.from(data, "utf8"))
+        : calculateSha256(data);
+    const entries = getHistoryEntries();
+    const existingEntry = entries.find((entry) => entry.id === id);
+
+    if (type === "image" && !fs.existsSync(getHistoryImagePath(id))) {
+      writeHistoryImage(id, data as Buffer);
+    }
+
+    const newEntry: HistoryEntry = {
+      id,
+      type,
+      time: Date.now(),
+      hostname: senderHostname,
+      pinned: existingEntry ? existingEntry.pinned : false,
+    };
+    if (typeof data === "string") {
+      newEntry.text = data;
+    }
+
+    setHistoryEntries(
+      pruneHistory([newEntry, ...entries.filter((entry) => entry.id !== id)])
+    );
+  } catch (error) {
+    console.error(`Error adding clipboard to history: ${error}`);
+  }
+};
+
+const copyHistoryEntry = (entry: HistoryEntry, fromHostname?: string) => {
+  if (entry.type === "text") {
+    clipboard.writeText(entry.text);
+  } else {
+    clipboard.writeImage(
+      fromHostname
+        ? nativeImage.createFromPath(
+            path.join(getSharedHistoryFolder(fromHostname), `${entry.id}.png`)
+          )
+        : nativeImage.createFromBuffer(readHistoryImage(entry.id))
+    );
+  }
+};
+
+const clearHistory = () => {
+  setHistoryEntries(
+    pruneHistory(getHistoryEntries().filter((entry) => entry.pinned))
+  );
+  setContextMenu();
+};
+
+// Simulates the paste shortcut on the focused application
+const pasteClipboard = async () => {
+  const [command, args]: [string, string[]] =
+    process.platform === "win32"
+      ? [
+          "powershell.exe",
+          [
+            "-NoProfile",
+            "-Command",
+            "(New-Object -ComObject WScript.Shell).SendKeys('^v')",
+          ],
+        ]
+      : process.platform === "darwin"
+      ? [
+          "osascript",
+          [
+            "-e",
+            'tell application "System Events" to keystroke "v" using command down',
+          ],
+        ]
+      : ["xdotool", ["key", "--clearmodifiers", "ctrl+v"]];
+  const result = await runCommand(command, args);
+  if (result.status !== 0) {
+    console.error(`Error pasting clipboard: ${result.stderr}`);
+  }
+};
+
+const showHistorySearch = () => {
+  if (!history) {
+    return;
+  }
+  if (historySearchWindow) {
+    historySearchWindow.focus();
+    return;
+  }
+
+  const entries = getHistoryEntries();
+  const items = entries.map((entry) => ({
+    id: entry.id,
+    label: getHistoryEntryLabel(entry),
+    text: entry.type === "text" ? entry.text : "",
+    pinned: entry.pinned,
+    thumbnail:
+      entry.type === "image"
+        ? getHistoryThumbnail(entry.id)?.toDataURL() || null
+        : null,
+  }));
+  const channel = `history-search-${Date.now()}`;
+  let selectedEntry: HistoryEntry = null;
+
+  historySearchWindow = new BrowserWindow({
+    width: 500,
+    height: 360,
+    frame: false,
+    resizable: false,
+    alwaysOnTop: true,
+    skipTaskbar: true,
+    show: false,
+    webPreferences: getWindowWebPreferences(channel),
+  });
+  historySearchWindow.once("ready-to-show", () => historySearchWindow.show());
+  historySearchWindow.on("blur", () => historySearchWindow.close());
+  ipcMain.once(channel, (event, id: string) => {
+    selectedEntry = entries.find((entry) => entry.id === id) || null;
+    historySearchWindow.close();
+  });
+  historySearchWindow.on("closed", () => {
+    historySearchWindow = null;
+    ipcMain.removeAllListeners(channel);
+    if (selectedEntry) {
+      copyHistoryEntry(selectedEntry);
+      if (config.get("pasteFromHistorySearch", false)) {
+        // Gives time for the focus to return to the previous application
+        setTimeout(pasteClipboard, 200);
+      }
+    }
+  });
+
+  const html = `<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>Search history</title>
+    <style>
+      body { font-family: sans-serif; font-size: 14px; margin: 0; }
+      input { width: 100%; box-sizing: border-box; padding: 8px; font-size: 16px; border: none; border-bottom: 1px solid #ccc; outline: none; }
+      ul { list-style: none; margin: 0; padding: 0; height: 318px; overflow-y: auto; }
+      li { padding: 6px 8px; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; cursor: pointer; }
+      li.selected { background: #0078d7; color: white; }
+      li img { max-width: 64px; max-height: 32px; margin-right: 8px; vertical-align: middle; }
+    </style>
+  </head>
+  <body>
+    <input id="search" placeholder="Search history" autofocus />
+    <ul id="results"></ul>
+    <script>
+      const items = ${JSON.stringify(items).replace(/</g, "\\u003c")};
+      const search = document.getElementById("search");
+      const results = document.getElementById("results");
+      let matches = items;
+      let selected = 0;
+
+      // Matches the characters of the query in order, preferring closer ones
+      const score = (item, query) => {
+        const text = (item.text || item.label).toLowerCase();
+        let position = -1;
+        let total = 0;
+        for (const character of query.toLowerCase()) {
+          const next = text.indexOf(character, position + 1);
+          if (next === -1) {
+            return null;
+          }
+          total += next - position;
+          position = next;
+        }
+        return total - (item.pinned ? 1 : 0);
+      };
+
+      const render = () => {
+        results.innerHTML = "";
+        matches.forEach((item, index) => {
+          const element = document.createElement("li");
+          if (item.thumbnail) {
+            const thumbnail = document.createElement("img");
+            thumbnail.src = item.thumbnail;
+            element.appendChild(thumbnail);
+          }
+          element.appendChild(
+            document.createTextNode((item.pinned ? "📌 " : "") + item.label)
+          );
+          element.className = index === selected ? "selected" : "";
+          element.addEventListener("click", () => choose(index));
+          results.appendChild(element);
+        });
+        const selectedElement = results.children[selected];
+        if (selectedElement) {
+          selectedElement.scrollIntoView({ block: "nearest" });
+        }
+      };
+
+      const choose = (index) => {
+        if (matches[index]) {
+          window.clipboardSync.send(matches[index].id);
+        }
+      };
+
+      search.addEventListener("input", () => {
+        const query = search.value.trim();
+        matches = query
+          ? items
+              .map((item) => ({ item, score: score(item, query) }))
+              .filter((match) => match.score !== null)
+              .sort((a, b) => a.score - b.score)
+              .map((match) => match.item)
+          : items;
+        selected = 0;
+        render();
+      });
+
+      document.addEventListener("keydown", (event) => {
+        if (event.key === "ArrowDown") {
+          selected = Math.min(selected + 1, matches.length - 1);
+          render();
+        } else if (event.key === "ArrowUp") {
+          selected = Math.max(selected - 1, 0);
+          render();
+        } else if (event.key === "Enter") {
+          choose(selected);
+        } else if (event.key === "Escape") {
+          window.close();
+        } else {
+          return;
+        }
+        event.preventDefault();
+      });
+
+      render();
+    </script>
+  </body>
+</html>`;
+  historySearchWindow.loadURL(
+    `data:text/html;charset=utf-8,${encodeURIComponent(html)}`
+  );
+};
+
+const showActivity = () => {
+  if (activityWindow) {
+    activityWindow.focus();
+    return;
+  }
+  activityWindow = new BrowserWindow({
+    width: 720,
+    height: 420,
+    title: "Activity",
+    autoHideMenuBar: true,
+    show: false,
+    webPreferences: getWindowWebPreferences(activityChannel),
+  });
+  activityWindow.once("ready-to-show", () => activityWindow.show());
+  activityWindow.on("closed", () => {
+    activityWindow = null;
+  });
+
+  const html = `<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>Activity</title>
+    <style>
+      body { font-family: sans-serif; font-size: 13px; margin: 0; }
+      table { width: 100%; border-collapse: collapse; }
+      th { position: sticky; top: 0; background: #f0f0f0; text-align: left; }
+      th, td { padding: 4px 8px; border-bottom: 1px solid #e0e0e0; white-space: nowrap; }
+      td.reason { white-space: normal; }
+      .failed { color: #c00000; }
+      .skipped { color: #808080; }
+      p { padding: 8px; color: #808080; }
+    </style>
+  </head>
+  <body>
+    <table>
+      <thead>
+        <tr><th>Time</th><th></th><th>Computer</th><th>Type</th><th>Size</th><th>Outcome</th></tr>
+      </thead>
+      <tbody id="entries"></tbody>
+    </table>
+    <p id="empty">Nothing was sent or received since ${escapeHtml(
+      app.name
+    )} started.</p>
+    <script>
+      const entries = document.getElementById("entries");
+      const empty = document.getElementById("empty");
+
+      const formatSize = (size) =>
+        size === undefined
+          ? ""
+          : size >= 1024 * 1024
+          ? (size / (1024 * 1024)).toFixed(1) + "MB"
+          : Math.ceil(size / 1024) + "KB";
+
+      const render = (activityLog) => {
+        entries.innerHTML = "";
+        empty.style.display = activityLog.length > 0 ? "none" : "block";
+        activityLog.forEach((entry) => {
+          const row = document.createElement("tr");
+          row.className = entry.outcome;
+          [
+            new Date(entry.time).toLocaleTimeString(),
+            entry.direction === "send" ? "↑" : "↓",
+            entry.hostname,
+            entry.type || "",
+            formatSize(entry.size),
+            entry.outcome + (entry.reason ? ": " + entry.reason : ""),
+          ].forEach((value, index) => {
+            const cell = document.createElement("td");
+            cell.textContent = value;
+            if (index === 5) {
+              cell.className = "reason";
+            }
+            row.appendChild(cell);
+          });
+          entries.appendChild(row);
+        });
+      };
+
+      window.clipboardSync.onUpdate((activityLog) => render(activityLog));
+      render(${JSON.stringify(activityLog).replace(/</g, "\\u003c")});
+    </script>
+  </body>
+</html>`;
+  activityWindow.loadURL(
+    `data:text/html;charset=utf-8,${encodeURIComponent(html)}`
+  );
+};
+
+const registerHotkeys = () => {
+  registerHotkey(config.get("historySearchHotkey"), showHistorySearch);
+  registerHotkey(config.get("skipNextCopyHotkey"), () => skipCopies());
+  registerHotkey(config.get("sendNowHotkey"), sendClipboardNow);
+  registerHotkey(config.get("pauseHotkey"), () => setPaused(!paused));
+};
+
+const getPinnedFilePath = () => {
+  return path.join(syncFolder, `${hostname}.pinned.json`);
+};
+
+// Only pinned texts are synced, images are kept on this computer. Like the
+// shared history, not while the history is encrypted.
+const writePinnedFile = () => {
+  if (!history) {
+    return;
+  }
+  const pinnedClipboards: PinnedClipboard[] =
+    isZeroKnowledgeMode() || config.get("encryptHistory", false)
+      ? []
+      : getHistoryEntries()
+          .filter((entry) => entry.pinned && entry.type === "text")
+          .map((entry) => ({ text: entry.text, time: entry.time }));
+  try {
+    if (pinnedClipboards.length > 0) {
+      fs.writeFileSync(
+        getPinnedFilePath(),
+        JSON.stringify(pinnedClipboards, null, 2),
+        { encoding: "utf8" }
+      );
+    } else if (fs.existsSync(getPinnedFilePath())) {
+      fs.unlinkSync(getPinnedFilePath());
+    }
+  } catch (error) {
+    console.error(`Error writing pinned clipboards: ${error}`);
+  }
+};
+
+const isPinnedFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.pinned\.json$/.test(path.basename(file));
+};
+
+// Files shared by the other computers which are shown in the tray menu
+const isSharedMenuFile = (file: string) => {
+  return (
+    isPinnedFile(file) ||
+    /^[0-9a-zA-Z-]+\.history$/.test(path.basename(path.dirname(file)))
+  );
+};
+
+const readOthersPinnedClipboards = () => {
+  const pinnedClipboards: PinnedClipboard[] = [];
+  if (!syncFolder || !fs.existsSync(syncFolder)) {
+    return pinnedClipboards;
+  }
+  fs.readdirSync(syncFolder).forEach((file) => {
+    if (!isPinnedFile(file) || file === path.basename(getPinnedFilePath())) {
+      return;
+    }
+    try {
+      const parsedPinnedClipboards = JSON.parse(
+        fs.readFileSync(path.join(syncFolder, file), { encoding: "utf8" })
+      );
+      if (Array.isArray(parsedPinnedClipboards)) {
+        parsedPinnedClipboards.forEach((pinnedClipboard) => {
+          if (typeof pinnedClipboard.text === "string") {
+            pinnedClipboards.push(pinnedClipboard);
+          }
+        });
+      }
+    } catch (error) {
+      console.error(`Error reading pinned clipboards from ${file}`);
+    }
+  });
+  return pinnedClipboards;
+};
+
+const setPinned = (id: string, pinned: boolean) => {
+  setHistoryEntries(
+    pruneHistory(
+      getHistoryEntries()
+        .map((entry) => (entry.id === id ? { ...entry, pinned } : entry))
+    )
+  );
+  writePinnedFile();
+  setContextMenu();
+};
+
+const getLockPath = (file: string) => {
+  return path.join(path.dirname(file), `${path.parse(file).name}.lock`);
+};
+
+// Locks left behind by writers which crashed are ignored after a while
+const isLocked = (file: string) => {
+  try {
+    return (
+      Date.now() - fs.statSync(getLockPath(file)).mtime.getTime() < staleLockMs
+    );
+  } catch (error) {
+    return false;
+  }
+};
+
+// Leftovers of clipboard files which can't be read, like partial writes,
+// temporary files and the copies made by cloud providers on conflicts, e.g.
+// "1-HOST (conflicted copy).txt" or "1-HOST.sync-conflict-20220101.txt".
+// Only the exact shapes are matched, and only for the computers known to
+// write to the folder, so files of the user are never taken for them.
+const junkFilePatterns = (() => {
+  const name =
+    "(?:0|[1-9][0-9]*)-([0-9a-zA-Z-]+)(?:@[0-9a-zA-Z-]+)?(?:\\.to-[0-9a-zA-Z-]+)?(?:\\.sensitive)?";
+  const extension =
+    "\\.(?:txt|txt\\.br|png|(?:0|[1-9][0-9]*)_files(?:\\.tar)?)";
+  return [
+    // Partial writes and temporary files
+    new RegExp(`^${name}${extension}(?:\\.part|\\.tmp|~)$`),
+    // Dropbox, Google Drive and Nextcloud conflict copies
+    new RegExp(`^${name} \\([^()]*\\)${extension}$`),
+    // Syncthing conflict copies
+    new RegExp(`^${name}\\.sync-conflict-[0-9a-zA-Z-]+${extension}$`),
+  ];
+})();
+
+const isJunkFile = (file: string) => {
+  const base = path.basename(file);
+  if (parseClipboardFile(file)) {
+    return false;
+  }
+  return junkFilePatterns.some((pattern) => {
+    const match = base.match(pattern);
+    return (
+      match &&
+      (match[1] === hostname ||
+        itemNumbersSeen.has(match[1]) ||
+        sequencesSeen.has(match[1]))
+    );
+  });
+};
+
+const getCleanableFileKind = (filePath: string) => {
+  if (getItemNumber(filePath) || isOpaqueFile(filePath)) {
+    return "clipboards";
+  } else if (isControlMessageFile(filePath)) {
+    return "commands";
+  } else if (isPairingFile(filePath)) {
+    return "pairing requests";
+  } else if (isProbeFile(filePath)) {
+    return "probes and acks";
+  } else if (isResendRequestFile(filePath)) {
+    return "resend requests";
+  } else if (isDeliveryFile(filePath)) {
+    return "delivery acks";
+  } else if (isJunkFile(filePath)) {
+    return "leftovers";
+  }
+  return null;
+};
+
+// Returns how many files of each kind were removed. The deep clean also
+// removes the locks left behind by writers which crashed.
+// When maxAgeMs is not given, each clipboard uses the max age set for the
+// computer which sent it.
+const cleanFiles = (maxAgeMs?: number, deep = false) => {
+  const removedByKind = new Map<string, number>();
+  if (!fs.existsSync(syncFolder)) {
+    return removedByKind;
+  }
+  const isCountPolicy = config.get("cleanupPolicy", "age") === "count";
+  if (isCountPolicy) {
+    const removed = cleanClipboardsByCount();
+    if (removed > 0) {
+      removedByKind.set("clipboards", removed);
+    }
+  }
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const filePath = path.join(syncFolder, file);
+    const kind =
+      getCleanableFileKind(filePath) ||
+      (deep && isLockFile(filePath) ? "stale locks" : null);
+    if (!kind || (isCountPolicy && kind === "clipboards")) {
+      return;
+    }
+    try {
+      const fileStat = fs.lstatSync(filePath);
+      let maxFileAgeMs =
+        kind === "stale locks"
+          ? staleLockMs
+          : maxAgeMs ??
+            getCleanupMaxAgeMs(parseClipboardFile(filePath)?.hostname);
+      // Kept longer while the computers online did not get it yet
+      if (maxAgeMs === undefined && isAwaitingDelivery(filePath)) {
+        maxFileAgeMs *= 3;
+      }
+      const minAgeTime = Date.now() - maxFileAgeMs;
+      if (fileStat.ctime.getTime() <= minAgeTime) {
+        if (kind === "leftovers" || kind === "stale locks") {
+          console.log(`Removing ${kind} ${filePath}`);
+        }
+        if (fileStat.isDirectory()) {
+          deleteFolderRecursive(filePath);
+        } else {
+          fs.unlinkSync(filePath);
+        }
+        removedByKind.set(kind, (removedByKind.get(kind) || 0) + 1);
+      }
+    } catch (error) {
+      console.error(`Error removing ${filePath}: ${error}`);
+    }
+  });
+  return removedByKind;
+};
+
+// With the count cleanup policy, only the latest keepLastClips clipboards of
+// each computer are kept, whatever their age. Returns how many were removed.
+const cleanClipboardsByCount = () => {
+  if (
+    config.get("cleanupPolicy", "age") !== "count" ||
+    !config.get("autoCleanup", true) ||
+    !fs.existsSync(syncFolder)
+  ) {
+    return 0;
+  }
+  const filesByHostname = new Map<
+    string,
+    { filePath: string; clipboardFile: ClipboardFile }[]
+  >();
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const filePath = path.join(syncFolder, file);
+    const clipboardFile = parseClipboardFile(filePath);
+    if (!clipboardFile) {
+      return;
+    }
+    if (!filesByHostname.has(clipboardFile.hostname)) {
+      filesByHostname.set(clipboardFile.hostname, []);
+    }
+    filesByHostname
+      .get(clipboardFile.hostname)
+      .push({ filePath, clipboardFile });
+  });
+  let removed = 0;
+  filesByHostname.forEach((files, fileHostname) => {
+    const keepLastClips = Math.max(
+      1,
+      getPeerSettings(fileHostname).keepLastClips ??
+        config.get("keepLastClips", 5)
+    );
+    files
+      .sort((a, b) => b.clipboardFile.itemNumber - a.clipboardFile.itemNumber)
+      .slice(keepLastClips)
+      .forEach(({ filePath, clipboardFile }) => {
+        try {
+          if (clipboardFile.type === "files" && !clipboardFile.archived) {
+            deleteFolderRecursive(filePath);
+          } else {
+            fs.unlinkSync(filePath);
+          }
+          removed++;
+        } catch (error) {
+          console.error(`Error removing ${filePath}: ${error}`);
+        }
+      });
+  });
+  return removed;
+};
+
+// So the last clipboards copied here don't stay in the folder after quitting
+const cleanOwnFiles = () => {
+  if (
+    !config.get("cleanOwnFilesOnExit", false) ||
+    !syncFolder ||
+    !fs.existsSync(syncFolder)
+  ) {
+    return;
+  }
+  // The folder is flushed first, so the clipboard files can't be lost on a
+  // power loss while the removals are kept
+  if (config.get("durableWrites", false)) {
+    try {
+      fsyncPath(syncFolder);
+    } catch (error) {
+      console.error(`Error flushing ${syncFolder} to the disk: ${error}`);
+    }
+  }
+  let removed = 0;
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const filePath = path.join(syncFolder, file);
+    const clipboardFile = parseClipboardFile(filePath);
+    if (!clipboardFile || clipboardFile.hostname !== hostname) {
+      return;
+    }
+    try {
+      if (clipboardFile.type === "files" && !clipboardFile.archived) {
+        deleteFolderRecursive(filePath);
+      } else {
+        fs.unlinkSync(filePath);
+      }
+      removed++;
+    } catch (error) {
+      console.error(`Error removing ${filePath}: ${error}`);
+    }
+  });
+  if (removed > 0) {
+    console.log(`Removed ${removed} clipboard files written by this computer`);
+  }
+};
+
+// Only the clipboards of trusted computers are received, e.g. in a folder
+// shared with the family
+const isHostAllowed = (host: string) => {
+  const isListed = (hosts: string[]) =>
+    hosts.some((listedHost) => listedHost.toLowerCase() === host.toLowerCase());
+  const allowedHosts = config.get("allowedHosts", []);
+  return (
+    (allowedHosts.length === 0 || isListed(allowedHosts)) &&
+    !isListed(config.get("blockedHosts", []))
+  );
+};
+
+const getPeerSettings = (peer?: string): PeerSettings => {
+  return (peer && config.get("peerSettings", {})[peer]) || {};
+};
+
+const getDeviceName = (peer: string) => {
+  return getPeerSettings(peer).name || peer;
+};
+
+const getCleanupMaxAgeMs = (peer?: string) => {
+  const maxAgeMinutes =
+    getPeerSettings(peer).cleanupMaxAgeMinutes ??
+    config.get("cleanupMaxAgeMinutes", 5);
+  return Math.max(1, maxAgeMinutes) * 60000;
+};
+
+const deepClean = () => {
+  const removedByKind = cleanFiles(undefined, true);
+  const summary = [...removedByKind.entries()].map(
+    ([kind, count]) => `${count} ${kind}`
+  );
+  console.log(
+    `Deep clean removed ${summary.length > 0 ? summary.join(", ") : "nothing"}`
+  );
+  dialog.showMessageBox({
+    type: "info",
+    title: "Deep clean",
+    message:
+      summary.length > 0 ? "Removed from the folder:" : "Nothing to remove",
+    detail:
+      summary.length > 0
+        ? summary.join("\n")
+        : `No files older than ${
+            getCleanupMaxAgeMs() / 60000
+          } minutes, leftovers or stale locks were found.`,
+  });
+  updateFolderUsage();
+};
+
+const isProbeFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.[0-9a-f]+\.(probe|ack)\.json$/.test(
+    path.basename(file)
+  );
+};
+
+const writeProbeMessage = (
+  kind: "probe" | "ack",
+  id: string,
+  to?: string
+) => {
+  const message: ProbeMessage = {
+    id,
+    from: hostname,
+    time: Date.now(),
+    to,
+    version: app.getVersion(),
+    formatVersion: folderFormatVersion,
+    features: folderFeatures,
+  };
+  const probePath = path.join(syncFolder, `${hostname}.${id}.${kind}.json`);
+  fs.writeFileSync(probePath, JSON.stringify(message, null, 2), {
+    encoding: "utf8",
+  });
+  flushToDisk(probePath);
+};
+
+// Messages are accepted up to controlMessageMaxAgeMs away from the clock here,
+// either way, so they are remembered for twice as long
+const rememberHandledMessage = (handled: Map<string, number>, key: string) => {
+  const minTime = Date.now() - 2 * controlMessageMaxAgeMs;
+  handled.forEach((time, otherKey) => {
+    if (time < minTime) {
+      handled.delete(otherKey);
+    }
+  });
+  handled.set(key, Date.now());
+};
+
+// Each computer only writes the files prefixed by its own hostname, so the
+// messages claiming to be from another one are ignored
+const isWrittenBy = (file: string, from: unknown) => {
+  return path.basename(file).split(".")[0] === from;
+};
+
+const handleProbeFile = (file: string) => {
+  let message: ProbeMessage;
+  try {
+    message = JSON.parse(fs.readFileSync(file, { encoding: "utf8" }));
+  } catch (error) {
+    // Removed by the cleanup meanwhile
+    return;
+  }
+  if (
+    !message ||
+    typeof message.id !== "string" ||
+    !/^[0-9a-f]+$/.test(message.id) ||
+    message.from === hostname ||
+    !isWrittenBy(file, message.from)
+  ) {
+    return;
+  }
+  // Probes keep flowing while nothing is copied, unlike the sequence files
+  checkPeerCompatibility({
+    hostname: message.from,
+    version: message.version,
+    formatVersion: message.formatVersion,
+  });
+  if (Array.isArray(message.features)) {
+    peerFeatures.set(message.from, message.features);
+  }
+  if (file.endsWith(".probe.json")) {
+    if (
+      handledProbes.has(message.id) ||
+      Date.now() - message.time > controlMessageMaxAgeMs
+    ) {
+      return;
+    }
+    rememberHandledMessage(handledProbes, message.id);
+    try {
+      writeProbeMessage("ack", message.id, message.from);
+    } catch (error) {
+      console.error(`Error acknowledging probe: ${error}`);
+    }
+    return;
+  }
+  const sentTime = sentProbes.get(message.id);
+  if (message.to !== hostname || sentTime === undefined) {
+    return;
+  }
+  const roundTrip = Date.now() - sentTime;
+  console.log(`Probe acknowledged by ${message.from} in ${roundTrip}ms`);
+  setPeerLastSeenTime(message.from, Date.now());
+  recordPeerPath(message.from, "folder", roundTrip);
+  // The ack was written around the middle of the round trip. The most precise
+  // estimate of the last hour is kept.
+  const clockOffset = peerClockOffsets.get(message.from);
+  if (
+    typeof message.time === "number" &&
+    (!clockOffset ||
+      roundTrip / 2 <= clockOffset.uncertaintyMs ||
+      Date.now() - clockOffset.time > keepAliveWindowMs)
+  ) {
+    peerClockOffsets.set(message.from, {
+      offsetMs: message.time - (sentTime + roundTrip / 2),
+      uncertaintyMs: roundTrip / 2,
+      time: Date.now(),
+    });
+  }
+  probeAcks.emit("ack", message.id, message.from, roundTrip);
+};
+
+// Returns the ID of the probe written, or null if it could not be written
+const sendProbe = () => {
+  if (!syncFolder || !folderAvailable) {
+    return null;
+  }
+  const id = randomBytes(8).toString("hex");
+  try {
+    writeProbeMessage("probe", id);
+  } catch (error) {
+    console.error(`Error writing probe: ${error}`);
+    return null;
+  }
+  sentProbes.set(id, Date.now());
+  setTimeout(() => sentProbes.delete(id), controlMessageMaxAgeMs);
+  return id;
+};
+
+// Writes a probe and reports how long each computer took to acknowledge it,
+// which covers the upload by the cloud client here and the download there
+const measureSyncLatency = async () => {
+  if (!config.get("receive", true)) {
+    dialog.showMessageBox({
+      type: "info",
+      title: "Measure sync latency",
+      message: "Receiving must be enabled to get the acknowledgements.",
+    });
+    return;
+  }
+  const id = sendProbe();
+  if (!id) {
+    notifyError(
+      "Could not measure sync latency",
+      "The folder is not available"
+    );
+    return;
+  }
+  const results = new Map<string, number>();
+  const handleAck = (ackId: string, from: string, roundTrip: number) => {
+    if (ackId === id) {
+      results.set(from, roundTrip);
+    }
+  };
+  probeAcks.on("ack", handleAck);
+  console.log("Measuring sync latency...");
+  new Notification({
+    title: "Measuring sync latency",
+    body: `Waiting ${
+      latencyBenchmarkDurationMs / 1000
+    } seconds for the other computers to answer...`,
+    icon: getAppIcon(),
+  }).show();
+  await new Promise((resolve) =>
+    setTimeout(resolve, latencyBenchmarkDurationMs)
+  );
+  probeAcks.off("ack", handleAck);
+
+  const peersInFolder = getPeersInFolder();
+  const lines = [...results.entries()]
+    .sort((a, b) => a[1] - b[1])
+    .map(
+      ([peer, roundTrip]) =>
+        `${getDeviceName(peer)}: ${(roundTrip / 1000).toFixed(1)} s`
+    );
+  peersInFolder.forEach((peer) => {
+    if (!results.has(peer)) {
+      lines.push(`${getDeviceName(peer)}: no answer`);
+    }
+  });
+  dialog.showMessageBox({
+    type: "info",
+    title: "Measure sync latency",
+    message:
+      results.size > 0
+        ? "Round-trip time through the folder"
+        : "No computer answered",
+    detail:
+      lines.length > 0
+        ? lines.join("\n")
+        : "Make sure the others are running and receiving from the folder.",
+  });
+};
+
+// Written every minute, so the latency and reliability of each computer are
+// known without measuring them explicitly
+const getKeepAliveIntervalMs = () => {
+  return getTimingSetting("keepAliveIntervalMinutes") * 60000;
+};
+
+const sendKeepAliveProbe = () => {
+  const currentTime = Date.now();
+  keepAliveProbes.forEach((time, id) => {
+    if (currentTime - time > keepAliveWindowMs) {
+      keepAliveProbes.delete(id);
+      peerRoundTrips.forEach((roundTrips) => roundTrips.delete(id));
+    }
+  });
+  if (!config.get("peerKeepAlive", false) || !config.get("receive", true)) {
+    return;
+  }
+  // The timer ticks every minute, give it some slack
+  const lastProbeTime = Math.max(0, ...keepAliveProbes.values());
+  if (currentTime - lastProbeTime < getKeepAliveIntervalMs() - 5000) {
+    return;
+  }
+  const id = sendProbe();
+  if (id) {
+    keepAliveProbes.set(id, currentTime);
+  }
+};
+
+probeAcks.on("ack", (id: string, from: string, roundTrip: number) => {
+  if (!keepAliveProbes.has(id)) {
+    return;
+  }
+  if (!peerRoundTrips.has(from)) {
+    peerRoundTrips.set(from, new Map());
+  }
+  peerRoundTrips.get(from).set(id, roundTrip);
+  setContextMenu();
+});
+
+// The typical round trip, and the share of the keep-alive probes answered
+// since the peer first answered one
+const getPeerLinkStats = (peer: string) => {
+  const roundTrips = peerRoundTrips.get(peer);
+  if (!roundTrips || roundTrips.size === 0) {
+    return null;
+  }
+  const firstAnsweredTime = Math.min(
+    ...[...roundTrips.keys()].map((id) => keepAliveProbes.get(id))
+  );
+  const currentTime = Date.now();
+  let expected = 0;
+  keepAliveProbes.forEach((time, id) => {
+    if (
+      time >= firstAnsweredTime &&
+      (roundTrips.has(id) || currentTime - time > keepAliveAckTimeoutMs)
+    ) {
+      expected++;
+    }
+  });
+  const sortedRoundTrips = [...roundTrips.values()].sort((a, b) => a - b);
+  return {
+    latency: sortedRoundTrips[Math.floor(sortedRoundTrips.length / 2)],
+    reliability: roundTrips.size / expected,
+  };
+};
+
+const getSequenceFilePath = () => {
+  return path.join(syncFolder, `${hostname}.sequence.json`);
+};
+
+const isSequenceFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.sequence\.json$/.test(path.basename(file));
+};
+
+const isResendRequestFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.[0-9a-zA-Z-]+\.resend\.json$/.test(
+    path.basename(file)
+  );
+};
+
+// It names this computer, so it is not written in zero-knowledge mode
+const writeSequenceFile = (itemNumber: number, target?: string) => {
+  if (isZeroKnowledgeMode()) {
+    return;
+  }
+  const sequence = state.get("sendSequence", 0) + 1;
+  state.set("sendSequence", sequence);
+  const message: SequenceMessage = {
+    hostname,
+    sequence,
+    itemNumber,
+    channel: getCurrentChannel(),
+    time: Date.now(),
+    version: app.getVersion(),
+    formatVersion: folderFormatVersion,
+    features: folderFeatures,
+    platform: process.platform,
+    target,
+  };
+  try {
+    fs.writeFileSync(
+      getSequenceFilePath(),
+      JSON.stringify(message, null, 2),
+      { encoding: "utf8" }
+    );
+  } catch (error) {
+    console.error(`Error writing sequence file: ${error}`);
+  }
+};
+
+const recordItemNumberSeen = (clipboardFile: ClipboardFile) => {
+  if (
+    clipboardFile.itemNumber >
+    (itemNumbersSeen.get(clipboardFile.hostname) || 0)
+  ) {
+    itemNumbersSeen.set(clipboardFile.hostname, clipboardFile.itemNumber);
+  }
+};
+
+// The files already in the folder when it starts being watched don't trigger
+// events, and are not missed
+const setPeerLastSeenTime = (peer: string, time: number) => {
+  if (
+    peer &&
+    peer !== hostname &&
+    time > (peersLastSeenTime.get(peer) || 0)
+  ) {
+    peersLastSeenTime.set(peer, time);
+  }
+};
+
+// The computer which wrote a clipboard or sequence file, if it is one
+const getPeerOfFile = (file: string) => {
+  const clipboardFile = parseClipboardFile(file);
+  if (clipboardFile) {
+    return clipboardFile.hostname;
+  }
+  return isSequenceFile(file) ? path.basename(file).split(".")[0] : null;
+};
+
+// Kept up to date from the folder events afterwards, so the menu doesn't need
+// to scan the folder every time it is rebuilt
+const recordPeersLastSeenInFolder = () => {
+  peersLastSeenTime.clear();
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const filePath = path.join(syncFolder, file);
+    try {
+      setPeerLastSeenTime(
+        getPeerOfFile(filePath),
+        fs.statSync(filePath).mtime.getTime()
+      );
+    } catch (error) {
+      // Removed meanwhile
+    }
+  });
+};
+
+const recordItemNumbersInFolder = () => {
+  fs.readdirSync(syncFolder).forEach((file) => {
+    const clipboardFile = parseClipboardFile(path.join(syncFolder, file));
+    if (clipboardFile) {
+      recordItemNumberSeen(clipboardFile);
+    }
+  });
+};
+
+// Tells which computer to update, instead of failing to read each other's
+// files after a format change
+const checkPeerCompatibility = (
+  message: Pick<SequenceMessage, "hostname" | "version" | "formatVersion">
+) => {
+  const peerFormatVersion = message.formatVersion ?? 1;
+  if (peerFormatVersion === folderFormatVersion) {
+    if (incompatiblePeers.delete(message.hostname)) {
+      console.log(`${message.hostname} is compatible again`);
+      setContextMenu();
+    }
+    return;
+  }
+  if (incompatiblePeers.get(message.hostname) === peerFormatVersion) {
+    return;
+  }
+  incompatiblePeers.set(message.hostname, peerFormatVersion);
+  const outdatedHostname =
+    peerFormatVersion < folderFormatVersion ? message.hostname : hostname;
+  console.error(
+    `${getDeviceName(message.hostname)} runs ${app.name} ${
+      message.version || "(unknown version)"
+    } with folder format ${peerFormatVersion}, while this computer runs ${app.getVersion()} with folder format ${folderFormatVersion}`
+  );
+  new Notification({
+    title: `Update ${getDeviceName(outdatedHostname)}`,
+    body: `${getDeviceName(message.hostname)} runs ${app.name} ${
+      message.version || "in an older version"
+    }, which can't exchange clipboards with ${app.getVersion()} here. Update ${
+      outdatedHostname === hostname
+        ? "this computer"
+        : getDeviceName(outdatedHostname)
+    } to keep syncing.`,
+    icon: getAppIcon(),
+  }).show();
+  setContextMenu();
+};
+
+// Recovers from the events which the watcher dropped: when a computer
+// announced a clipboard which was never seen, it is read if it is in the
+// folder, or asked again otherwise
+const checkSequenceGaps = () => {
+  if (!clipboardFilesWatcher || !fs.existsSync(syncFolder)) {
+    return;
+  }
+  const currentTime = Date.now();
+  const files = fs.readdirSync(syncFolder);
+  files.filter(isSequenceFile).forEach((file) => {
+    let message: SequenceMessage;
+    try {
+      message = JSON.parse(
+        fs.readFileSync(path.join(syncFolder, file), { encoding: "utf8" })
+      );
+    } catch (error) {
+      console.error(`Error reading sequence file ${file}: ${error}`);
+      return;
+    }
+    if (
+      !message ||
+      typeof message.itemNumber !== "number" ||
+      typeof message.sequence !== "number" ||
+      message.hostname === hostname ||
+      !isWrittenBy(file, message.hostname)
+    ) {
+      return;
+    }
+    checkPeerCompatibility(message);
+    peerFeatures.set(
+      message.hostname,
+      Array.isArray(message.features) ? message.features : []
+    );
+    peerChannels.set(message.hostname, message.channel);
+    if (typeof message.platform === "string") {
+      peerPlatforms.set(message.hostname, message.platform);
+    }
+    if (message.channel !== getCurrentChannel()) {
+      return;
+    }
+    const previousSequence = sequencesSeen.get(message.hostname);
+    sequencesSeen.set(message.hostname, message.sequence);
+    if (
+      previousSequence !== undefined &&
+      message.sequence > previousSequence + 1
+    ) {
+      console.log(
+        `Missed ${message.sequence - previousSequence - 1} clipboards from ${
+          message.hostname
+        }`
+      );
+    }
+    const age = currentTime - message.time;
+    // The clipboards sent to another computer are never received here
+    if (
+      !isForThisComputer(message) ||
+      (itemNumbersSeen.get(message.hostname) || 0) >= message.itemNumber ||
+      age < sequenceGapGraceMs ||
+      age > controlMessageMaxAgeMs
+    ) {
+      return;
+    }
+    const missedFile = files.find((other) => {
+      const clipboardFile = parseClipboardFile(path.join(syncFolder, other));
+      return (
+        clipboardFile &&
+        clipboardFile.hostname === message.hostname &&
+        clipboardFile.itemNumber === message.itemNumber
+      );
+    });
+    if (missedFile) {
+      // Older versions don't name the target in the sequence files
+      const missedPath = path.join(syncFolder, missedFile);
+      if (!isForThisComputer(parseClipboardFile(missedPath))) {
+        return;
+      }
+      console.log(`Reading ${missedFile}, which the watcher missed`);
+      handleFolderEvent(missedPath);
+      return;
+    }
+    const resendKey = `${message.hostname}:${message.itemNumber}`;
+    if (resendsRequested.has(resendKey)) {
+      return;
+    }
+    rememberHandledMessage(resendsRequested, resendKey);
+    const request: ResendRequest = {
+      from: hostname,
+      to: message.hostname,
+      itemNumber: message.itemNumber,
+      time: currentTime,
+    };
+    try {
+      fs.writeFileSync(
+        path.join(syncFolder, `${hostname}.${message.hostname}.resend.json`),
+        JSON.stringify(request, null, 2),
+        { encoding: "utf8" }
+      );
+      console.log(`Asked ${message.hostname} to send its clipboard again`);
+    } catch (error) {
+      console.error(`Error asking for the clipboard again: ${error}`);
+    }
+  });
+};
+
+// Sends the latest clipboard again under a new item number, as long as
+// nothing was sent since the one which went missing
+const handleResendRequestFile = (file: string) => {
+  let request: ResendRequest;
+  try {
+    request = JSON.parse(fs.readFileSync(file, { encoding: "utf8" }));
+  } catch (error) {
+    // Removed by the cleanup meanwhile
+    return;
+  }
+  if (
+    !request ||
+    request.to !== hostname ||
+    !isWrittenBy(file, request.from) ||
+    request.itemNumber !== lastTimeWritten ||
+    Date.now() - request.time > controlMessageMaxAgeMs ||
+    handledResendRequests.has(`${request.from}:${request.itemNumber}`)
+  ) {
+    return;
+  }
+  rememberHandledMessage(
+    handledResendRequests,
+    `${request.from}:${request.itemNumber}`
+  );
+  sendLastClipboardAgain(`as asked by ${request.from}`);
+};
+
+// Renames the latest clipboard written to a new item number, so the others
+// notice it again
+const sendLastClipboardAgain = (reason: string) => {
+  const clipboardFile = lastPathWritten && parseClipboardFile(lastPathWritten);
+  if (!clipboardFile || isOpaqueFile(lastPathWritten)) {
+    console.log(`Can't send the clipboard again ${reason}`);
+    return false;
+  }
+  const writeTime = getNextWriteTime();
+  const extension =
+    clipboardFile.type === "text"
+      ? getTextExtension(clipboardFile.compressed)
+      : clipboardFile.type === "image"
+      ? "png"
+      : `${clipboardFile.filesCount}_files${
+          clipboardFile.archived ? ".tar" : ""
+        }`;
+  const destinationPath = path.join(
+    syncFolder,
+    `${writeTime}-${hostname}${getChannelSuffix(
+      clipboardFile.channel
+    )}${getTargetSuffix(clipboardFile.target)}${getSensitiveSuffix(
+      clipboardFile.sensitive
+    )}.${extension}`
+  );
+  try {
+    // Under a new name, so the cloud client uploads it again
+    fs.renameSync(lastPathWritten, destinationPath);
+  } catch (error) {
+    console.error(`Error sending the clipboard again: ${error}`);
+    return false;
+  }
+  console.log(`Clipboard sent again to ${destinationPath}, ${reason}`);
+  lastTimeWritten = writeTime;
+  lastPathWritten = destinationPath;
+  writeSequenceFile(writeTime, clipboardFile.target);
+  sendWakeHint();
+  return true;
+};
+
+const isDeliveryFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.[0-9a-f]+\.delivered\.json$/.test(
+    path.basename(file)
+  );
+};
+
+// Tells the sender the clipboard was applied here. Not in zero-knowledge
+// mode, where it would reveal who sent what.
+const writeDeliveryAck = (clipboardFile: ClipboardFile) => {
+  if (!syncFolder || !folderAvailable || isZeroKnowledgeMode()) {
+    return;
+  }
+  const clipId = getClipId(clipboardFile);
+  const ack: DeliveryAck = {
+    clipId,
+    from: hostname,
+    to: clipboardFile.hostname,
+    time: Date.now(),
+  };
+  try {
+    fs.writeFileSync(
+      path.join(
+        syncFolder,
+        `${hostname}.${calculateSha256(Buffer.from(clipId, "utf8")).substring(
+          0,
+          16
+        )}.delivered.json`
+      ),
+      JSON.stringify(ack, null, 2),
+      { encoding: "utf8" }
+    );
+  } catch (error) {
+    console.error(`Error writing delivery ack: ${error}`);
+  }
+};
+
+const getLastClipId = () => {
+  const clipboardFile = lastPathWritten && parseClipboardFile(lastPathWritten);
+  return clipboardFile ? getClipId(clipboardFile) : null;
+};
+
+const handleDeliveryFile = (file: string) => {
+  let ack: DeliveryAck;
+  try {
+    ack = JSON.parse(fs.readFileSync(file, { encoding: "utf8" }));
+  } catch (error) {
+    // Removed by the cleanup meanwhile
+    return;
+  }
+  if (
+    !ack ||
+    ack.to !== hostname ||
+    typeof ack.clipId !== "string" ||
+    Date.now() - ack.time > controlMessageMaxAgeMs
+  ) {
+    return;
+  }
+  deliveringPeers.add(ack.from);
+  if (!deliveries.has(ack.clipId)) {
+    if (ack.clipId !== getLastClipId()) {
+      return;
+    }
+    deliveries.set(ack.clipId, new Set());
+    // Only the latest ones are needed
+    [...deliveries.keys()]
+      .slice(0, -20)
+      .forEach((clipId) => deliveries.delete(clipId));
+  }
+  const deliveredTo = deliveries.get(ack.clipId);
+  if (deliveredTo.has(ack.from)) {
+    return;
+  }
+  deliveredTo.add(ack.from);
+  console.log(`Clipboard ${ack.clipId} was delivered to ${ack.from}`);
+  if (config.get("deliveryNotifications", false) && !isQuiet()) {
+    new Notification({
+      title: `Delivered to ${deliveredTo.size} computer${
+        deliveredTo.size === 1 ? "" : "s"
+      }`,
+      body: [...deliveredTo].map(getDeviceName).join(", "),
+      icon: getAppIcon(),
+      silent: true,
+    }).show();
+  }
+  setContextMenu();
+};
+
+// Whether the file is the latest clipboard sent, which no computer applied
+// yet while some are online
+// The computers online which should get the last clipboard: the ones known
+// to receive from this one, on its channel, and its target if any
+const getExpectedRecipients = () => {
+  const clipboardFile = lastPathWritten && parseClipboardFile(lastPathWritten);
+  if (!clipboardFile) {
+    return [];
+  }
+  return [...getOnlinePeers()].filter(
+    (peer) =>
+      deliveringPeers.has(peer) &&
+      (!clipboardFile.target ||
+        clipboardFile.target.toLowerCase() === peer.toLowerCase()) &&
+      (peerChannels.get(peer) ?? clipboardFile.channel) ===
+        clipboardFile.channel
+  );
+};
+
+const isAwaitingDelivery = (file: string) => {
+  return (
+    file === lastPathWritten &&
+    !deliveries.has(getLastClipId()) &&
+    getExpectedRecipients().length > 0
+  );
+};
+
+// Three times the usual latency of the slowest one, as cloud clients can take
+// longer than the minimum
+const getDeliveryWaitMs = (peers: string[]) => {
+  return Math.max(
+    deliveryRetryMs,
+    ...peers.map((peer) => 3 * (getPeerLinkStats(peer)?.latency || 0))
+  );
+};
+
+const getDeliveryCount = () => {
+  return deliveries.get(getLastClipId())?.size || 0;
+};
+
+const checkDeliveries = () => {
+  if (
+    !config.get("resendUndelivered", false) ||
+    !lastPathWritten ||
+    !lastSentTime ||
+    deliveryRetriedItemNumber === lastTimeWritten ||
+    !isAwaitingDelivery(lastPathWritten) ||
+    Date.now() - lastSentTime < getDeliveryWaitMs(getExpectedRecipients())
+  ) {
+    return;
+  }
+  if (sendLastClipboardAgain("as no computer got it yet")) {
+    deliveryRetriedItemNumber = lastTimeWritten;
+  }
+};
+
+// Keeps a copy of each clipboard received, in a folder for each day, so they
+// can be found with other tools after the cleanup removed them
+const archiveClipboard = (
+  file: string,
+  clipboardFile: ClipboardFile,
+  text?: string,
+  imageData?: Buffer
+) => {
+  const archiveFolder = config.get("archiveFolder");
+  // Like the history, the clipboards copied from password managers are never
+  // kept
+  if (!archiveFolder || clipboardFile.sensitive) {
+    return;
+  }
+  const now = new Date();
+  const pad = (value: number) => String(value).padStart(2, "0");
+  const dayFolder = path.join(
+    archiveFolder,
+    `${now.getFullYear()}-${pad(now.getMonth() + 1)}-${pad(now.getDate())}`
+  );
+  // With the item number, as several clipboards can arrive in the same second
+  const baseName = `${pad(now.getHours())}${pad(now.getMinutes())}${pad(
+    now.getSeconds()
+  )}-${clipboardFile.itemNumber}-${clipboardFile.hostname}`;
+  try {
+    fs.mkdirSync(dayFolder, { recursive: true });
+    if (clipboardFile.type === "text") {
+      fs.writeFileSync(path.join(dayFolder, `${baseName}.txt`), text, {
+        encoding: "utf8",
+      });
+    } else if (clipboardFile.type === "image") {
+      // Sealed images are archived decrypted
+      if (imageData) {
+        fs.writeFileSync(path.join(dayFolder, `${baseName}.png`), imageData);
+      } else {
+        fs.copyFileSync(file, path.join(dayFolder, `${baseName}.png`));
+      }
+    } else if (clipboardFile.archived) {
+      fs.copyFileSync(file, path.join(dayFolder, `${baseName}_files.tar`));
+    } else {
+      copyFolderRecursive(file, path.join(dayFolder, `${baseName}_files`));
+    }
+  } catch (error) {
+    console.error(`Error archiving clipboard: ${error}`);
+  }
+};
+
+const handleArchiveCheckBox = (checkBox: Electron.MenuItem) => {
+  if (!checkBox.checked) {
+    config.delete("archiveFolder");
+    setContextMenu();
+    return;
+  }
+  const foldersSelected = dialog.showOpenDialogSync({
+    title: "Select folder to archive the clipboards received",
+    properties: ["openDirectory", "createDirectory"],
+  });
+  if (foldersSelected) {
+    config.set("archiveFolder", foldersSelected[0]);
+  }
+  setContextMenu();
+};
+
+const askForFolder = () => {
+  let previousFolder = config.get("folder");
+
+  const foldersSelected = dialog.showOpenDialogSync({
+    title: "Select folder to save and read clipboard files",
+    properties: ["openDirectory"],
+    defaultPath: previousFolder,
+  });
+
+  let folderSelected;
+  if (foldersSelected) {
+    folderSelected = foldersSelected[0];
+  }
+
+  if (!folderSelected && !previousFolder) {
+    dialog.showErrorBox(
+      "Folder was not selected",
+      "Please start the application again to select a folder."
+    );
+    finish(1);
+    return;
+  } else if (!folderSelected) {
+    return;
+  }
+  syncFolder = folderSelected;
+  config.set("folder", folderSelected);
+
+  if (folderSelected !== previousFolder) {
+    reload();
+  }
+};
+
+const startFolderWatcher = () => {
+  if (!config.get("receive", true)) {
+    return;
+  }
+  recordItemNumbersInFolder();
+  recordPeersLastSeenInFolder();
+  // Watches for files and reads clipboard from it. Network shares don't
+  // deliver change notifications reliably, so they are polled instead.
+  clipboardFilesWatcher = chokidar
+    .watch(syncFolder, {
+      ignoreInitial: true,
+      disableGlobbing: true,
+      usePolling: config.get("networkShareMode", false),
+      interval: 1000,
+      binaryInterval: 1000,
+    })
+    .on("add", handleFolderEvent)
+    .on("change", handleFolderEvent)
+    .on("unlink", (file) => {
+      if (isSharedMenuFile(file)) {
+        handleFolderEvent(file);
+      } else if (isLockFile(file)) {
+        // The folder it was locking is now complete
+        const lockedName = path.parse(file).name;
+        fs.readdirSync(syncFolder)
+          .filter(
+            (other) =>
+              other === lockedName || other.startsWith(`${lockedName}.`)
+          )
+          .forEach((other) => handleFolderEvent(path.join(syncFolder, other)));
+      }
+    });
+};
+
+const stopFolderWatcher = () => {
+  if (clipboardFilesWatcher) {
+    clipboardFilesWatcher.close();
+    clipboardFilesWatcher = null;
+  }
+};
+
+// In network share mode, the share disappearing and coming back only restarts
+// the watcher, instead of requiring a full reload
+const checkFolderAvailability = () => {
+  if (!config.get("networkShareMode", false)) {
+    return;
+  }
+  let available = false;
+  try {
+    available = fs.statSync(syncFolder).isDirectory();
+  } catch (error) {
+    available = false;
+  }
+  if (available === folderAvailable) {
+    return;
+  }
+  folderAvailable = available;
+  stopFolderWatcher();
+  if (available) {
+    console.log(`Folder ${syncFolder} is available again`);
+    startFolderWatcher();
+  } else {
+    console.error(`Folder ${syncFolder} is not available, waiting for it...`);
+  }
+  updateToolTip();
+};
+
+const getGrpcStatusMessage = () => {
+  return encodeProtobuf([
+    [1, config.get("send", true)],
+    [2, config.get("receive", true)],
+    [3, syncFolder],
+    [4, hostname],
+    [5, app.getVersion()],
+    [6, lastSentTime],
+    [7, lastReceivedTime],
+    [8, getCurrentChannel()],
+  ]);
+};
+
+const getGrpcClipEventMessage = (clipEvent: ClipEvent) => {
+  return encodeProtobuf([
+    [1, clipEvent.direction],
+    [2, clipEvent.type],
+    [3, clipEvent.hostname],
+    [4, clipEvent.channel],
+    [5, clipEvent.size],
+    [6, clipEvent.time],
+  ]);
+};
+
+// Serves the service described in proto/clipboard_sync.proto, without TLS and
+// only on localhost
+const startGrpcServer = () => {
+  const port = config.get("grpcPort", 0);
+  if (!port) {
+    return;
+  }
+
+  const handleUnary = (request: Buffer, method: string): Buffer => {
+    const enabled = decodeProtobuf(request).get(1) === 1;
+    switch (method) {
+      case "GetStatus":
+        return getGrpcStatusMessage();
+      case "SetSending":
+        config.set("send", enabled);
+        setImmediate(reload);
+        return getGrpcStatusMessage();
+      case "SetReceiving":
+        config.set("receive", enabled);
+        setImmediate(reload);
+        return getGrpcStatusMessage();
+      case "Reload":
+        setImmediate(reload);
+        return Buffer.alloc(0);
+      default:
+        return null;
+    }
+  };
+
+  grpcServer = http2.createServer();
+  grpcServer.on("session", (session) => {
+    grpcSessions.add(session);
+    session.on("close", () => grpcSessions.delete(session));
+  });
+  grpcServer.on("stream", (stream, headers) => {
+    const method = String(headers[":path"]).replace(
+      /^\/clipboardsync\.ClipboardSync\//,
+      ""
+    );
+    const chunks: Buffer[] = [];
+    stream.on("data", (chunk: Buffer) => chunks.push(chunk));
+    stream.on("end", () => {
+      const body = Buffer.concat(chunks);
+      const request = readGrpcMessage(body);
+
+      if (method === "WatchClips") {
+        stream.respond({
+          ":status": 200,
+          "content-type": "application/grpc",
+        });
+        const onClip = (clipEvent: ClipEvent) => {
+          stream.write(frameGrpcMessage(getGrpcClipEventMessage(clipEvent)));
+        };
+        clipEvents.on("clip", onClip);
+        stream.on("close", () => clipEvents.off("clip", onClip));
+        return;
+      }
+
+      const response = handleUnary(request, method);
+      if (response === null) {
+        stream.respond(
+          {
+            ":status": 200,
+            "content-type": "application/grpc",
+            "grpc-status": "12",
+            "grpc-message": `Method ${method} is not implemented`,
+          },
+          { endStream: true }
+        );
+        return;
+      }
+      stream.respond(
+        { ":status": 200, "content-type": "application/grpc" },
+        { waitForTrailers: true }
+      );
+      stream.on("wantTrailers", () => {
+        stream.sendTrailers({ "grpc-status": "0" });
+      });
+      stream.end(frameGrpcMessage(response));
+    });
+  });
+  grpcServer.on("error", (error) => {
+    console.error(`Error on gRPC server: ${error}`);
+  });
+  grpcServer.listen(port, "127.0.0.1", () => {
+    console.log(`gRPC server listening on 127.0.0.1:${port}`);
+  });
+};
+
+const stopGrpcServer = () => {
+  if (grpcServer) {
+    // Sessions are kept open by WatchClips, so they are closed too
+    grpcSessions.forEach((session) => session.destroy());
+    grpcSessions.clear();
+    grpcServer.close();
+    grpcServer = null;
+  }
+};
+
+// The other computers which have clipboard files in the folder
+const getPeersInFolder = () => {
+  const peers = new Set<string>();
+  if (syncFolder && fs.existsSync(syncFolder)) {
+    fs.readdirSync(syncFolder).forEach((file) => {
+      const clipboardFile = parseClipboardFile(path.join(syncFolder, file));
+      if (clipboardFile && clipboardFile.hostname !== hostname) {
+        peers.add(clipboardFile.hostname);
+      }
+    });
+  }
+  return peers;
+};
+
+const getMetrics = () => {
+  const lines = [
+    "# HELP clipboard_sync_clips_total Clipboards sent or received.",
+    "# TYPE clipboard_sync_clips_total counter",
+  ];
+  stats.clips.forEach((count, key) => {
+    const [direction, type] = key.split(":");
+    lines.push(
+      `clipboard_sync_clips_total{direction="${direction}",type="${type}"} ${count}`
+    );
+  });
+  lines.push(
+    "# HELP clipboard_sync_bytes_total Bytes of the clipboards sent or received.",
+    "# TYPE clipboard_sync_bytes_total counter"
+  );
+  stats.bytes.forEach((bytes, direction) => {
+    lines.push(
+      `clipboard_sync_bytes_total{direction="${direction}"} ${Math.round(
+        bytes
+      )}`
+    );
+  });
+  lines.push(
+    "# HELP clipboard_sync_errors_total Errors notified.",
+    "# TYPE clipboard_sync_errors_total counter",
+    `clipboard_sync_errors_total ${stats.errors}`,
+    "# HELP clipboard_sync_peers Other computers with clipboard files in the folder.",
+    "# TYPE clipboard_sync_peers gauge",
+    `clipboard_sync_peers ${getPeersInFolder().size}`,
+    "# HELP clipboard_sync_folder_latency_seconds Time between a clipboard file being written and received.",
+    "# TYPE clipboard_sync_folder_latency_seconds summary",
+    `clipboard_sync_folder_latency_seconds_sum ${stats.latencySum / 1000}`,
+    `clipboard_sync_folder_latency_seconds_count ${stats.latencyCount}`,
+    "# HELP clipboard_sync_last_clip_timestamp_seconds When the last clipboard was sent or received.",
+    "# TYPE clipboard_sync_last_clip_timestamp_seconds gauge"
+  );
+  if (lastSentTime) {
+    lines.push(
+      `clipboard_sync_last_clip_timestamp_seconds{direction="send"} ${
+        lastSentTime / 1000
+      }`
+    );
+  }
+  if (lastReceivedTime) {
+    lines.push(
+      `clipboard_sync_last_clip_timestamp_seconds{direction="receive"} ${
+        lastReceivedTime / 1000
+      }`
+    );
+  }
+  return `${lines.join("\n")}\n`;
+};
+
+const startMetricsServer = () => {
+  const port = config.get("metricsPort", 0);
+  if (!port) {
+    return;
+  }
+  metricsServer = http.createServer((request, response) => {
+    if (request.url !== "/metrics") {
+      response.writeHead(404).end();
+      return;
+    }
+    response.writeHead(200, {
+      "Content-Type": "text/plain; version=0.0.4; charset=utf-8",
+    });
+    response.end(getMetrics());
+  });
+  metricsServer.on("error", (error) => {
+    console.error(`Error on metrics server: ${error}`);
+  });
+  metricsServer.listen(port, "127.0.0.1", () => {
+    console.log(`Metrics served on http://127.0.0.1:${port}/metrics`);
+  });
+};
+
+const stopMetricsServer = () => {
+  if (metricsServer) {
+    metricsServer.close();
+    metricsServer = null;
+  }
+};
+
+const setPaused = (newPaused: boolean) => {
+  if (paused === newPaused) {
+    return;
+  }
+  paused = newPaused;
+  config.set("paused", paused);
+  console.log(paused ? "Paused syncing" : "Resumed syncing");
+  if (appIcon) {
+    appIcon.setImage(getIdleTrayIcon());
+  }
+  updateToolTip();
+  setContextMenu();
+};
+
+// Sends the current clipboard even if no change was noticed, or it was
+// already sent
+const sendClipboardNow = () => {
+  console.log("Sending the clipboard now");
+  handleClipboardChange(true);
+};
+
+// Runs a command of the control channel, or of another instance started
+// with "--command"
+const runControlCommand = (command: string) => {
+  console.log(`Running control command ${command}`);
+  switch (command) {
+    case "pause":
+      setPaused(true);
+      break;
+    case "resume":
+      setPaused(false);
+      break;
+    case "reload":
+      // After answering, since it restarts everything
+      setImmediate(reload);
+      break;
+    case "send-now":
+      sendClipboardNow();
+      break;
+    case "status":
+      break;
+    default:
+      return { ok: false, error: `Unknown command: ${command}` };
+  }
+  return { ok: true, status: { ...getHealth(), paused } };
+};
+
+const getControlSocketPath = () => {
+  return process.platform === "win32"
+    ? `\\\\.\\pipe\\clipboard-sync-${os.userInfo().username}${
+        profile ? `-${profile}` : ""
+      }`
+    : path.join(app.getPath("userData"), "control.sock");
+};
+
+// Lets local tools control the running instance, with a command per line,
+// each answered with a line of JSON
+const startControlServer = () => {
+  const socketPath = getControlSocketPath();
+  if (process.platform !== "win32" && fs.existsSync(socketPath)) {
+    // Left behind by a previous instance, the single instance lock tells
+    // none is running anymore
+    fs.unlinkSync(socketPath);
+  }
+  controlServer = net.createServer((socket) => {
+    let buffer = "";
+    socket.setEncoding("utf8");
+    socket.on("data", (data: string) => {
+      buffer += data;
+      let newLineIndex: number;
+      while ((newLineIndex = buffer.indexOf("\n")) !== -1) {
+        const command = buffer.substring(0, newLineIndex).trim();
+        buffer = buffer.substring(newLineIndex + 1);
+        if (command) {
+          socket.write(`${JSON.stringify(runControlCommand(command))}\n`);
+        }
+      }
+    });
+    socket.on("error", (error) => {
+      console.error(`Error on control channel connection: ${error}`);
+    });
+  });
+  controlServer.on("error", (error) => {
+    console.error(`Error on control channel: ${error}`);
+  });
+  controlServer.listen(socketPath, () => {
+    console.log(`Control channel listening on ${socketPath}`);
+  });
+};
+
+const stopControlServer = () => {
+  if (controlServer) {
+    controlServer.close();
+    controlServer = null;
+  }
+};
+
+const getClipId = (clipboardFile: ClipboardFile) => {
+  return `${clipboardFile.itemNumber}-${
+    clipboardFile.hostname
+  }${getChannelSuffix(clipboardFile.channel)}`;
+};
+
+// Remembered for as long as the sealed clipboards are accepted, so none can
+// be replayed by a relay or NATS server
+const markClipApplied = (id: string) => {
+  const now = Date.now();
+  appliedClipIds.forEach((time, appliedId) => {
+    if (now - time > envelopeMaxAgeMs) {
+      appliedClipIds.delete(appliedId);
+    }
+  });
+  appliedClipIds.set(id, now);
+};
+
+const getInboxFolder = () => {
+  return path.join(app.getPath("userData"), "inbox");
+};
+
+const isInInbox = (file: string) => {
+  return path.dirname(file) === getInboxFolder();
+};
+
+// Goes through the same checks, filters and plugins as the clipboards read
+// from the folder, by writing it to the inbox as a clipboard file
+const applyClipEnvelope = (envelope: ClipEnvelope, transportName: string) => {
+  if (
+    !config.get("receive", true) ||
+    envelope.hostname === hostname ||
+    envelope.channel !== getCurrentChannel() ||
+    appliedClipIds.has(envelope.id) ||
+    !/^[0-9a-zA-Z-]+$/.test(envelope.hostname) ||
+    !isValidChannel(envelope.channel)
+  ) {
+    return;
+  }
+  // Like the folder, the other transports don't apply anything while paused,
+  // out of the schedule or suspended
+  if (getHoldReason()) {
+    console.log(
+      `Skipping clipboard ${envelope.id} received through ${transportName} while ${getHoldReason()}`
+    );
+    return;
+  }
+  const inboxFile = path.join(
+    getInboxFolder(),
+    `${getClipId({ ...envelope })}${getSensitiveSuffix(envelope.sensitive)}.${
+      envelope.type === "text" ? "txt" : "png"
+    }`
+  );
+  const removeInboxFile = () => {
+    if (fs.existsSync(inboxFile)) {
+      fs.unlinkSync(inboxFile);
+    }
+  };
+  try {
+    fs.mkdirSync(getInboxFolder(), { recursive: true });
+    fs.writeFileSync(inboxFile, Buffer.from(envelope.data, "base64"));
+    // Dated when it was sent, so the latency is measured like for the folder
+    const sentDate = new Date(Math.min(envelope.time, Date.now()));
+    fs.utimesSync(inboxFile, sentDate, sentDate);
+    markClipApplied(envelope.id);
+    console.log(`Clipboard ${envelope.id} received through ${transportName}`);
+    const reading = readClipboardFromFile(inboxFile, getCurrentChannel());
+    // Kept until it is read again once the filter command finishes
+    if (reading) {
+      reading.then(removeInboxFile, removeInboxFile);
+      return;
+    }
+  } catch (error) {
+    console.error(`Error applying clipboard ${envelope.id}: ${error}`);
+  }
+  removeInboxFile();
+};
+
+// Keeps the latest round trip measured through each path, per computer. The
+// folder ones come from the probes, the transport ones from the answers to
+// the clipboards sent, both timed with the clock of this computer.
+const recordPeerPath = (peer: string, via: string, roundTrip: number) => {
+  if (!peerPaths.has(peer)) {
+    peerPaths.set(peer, new Map());
+  }
+  peerPaths.get(peer).set(via, { via, roundTrip, time: Date.now() });
+};
+
+// Paths not used for an hour are not considered anymore
+const getFastestPeerPath = (peer: string) => {
+  const recentTime = Date.now() - 60 * 60 * 1000;
+  return [...(peerPaths.get(peer) || new Map<string, PeerPath>()).values()]
+    .filter((peerPath) => peerPath.time >= recentTime)
+    .sort((a, b) => a.roundTrip - b.roundTrip)[0];
+};
+
+// Only the transports which are the fastest way to reach some computer are
+// used, or all the available ones while the fastest way to some computer
+// online is not known or is down. The folder always gets the clipboard.
+const getTransportsToUse = () => {
+  const availableTransports = clipTransports.filter((transport) =>
+    transport.isAvailable()
+  );
+  if ([...getOnlinePeers()].some((peer) => !getFastestPeerPath(peer))) {
+    return availableTransports;
+  }
+  const fastestPaths = [...peerPaths.keys()]
+    .map(getFastestPeerPath)
+    .filter((fastestPath) => fastestPath && fastestPath.via !== "folder");
+  if (
+    fastestPaths.length === 0 ||
+    fastestPaths.some(
+      (fastestPath) =>
+        !availableTransports.some(
+          (transport) => transport.name === fastestPath.via
+        )
+    )
+  ) {
+    return availableTransports;
+  }
+  return availableTransports.filter((transport) =>
+    fastestPaths.some((fastestPath) => fastestPath.via === transport.name)
+  );
+};
+
+// The paths which didn't answer in time are forgotten, so the next clipboard
+// goes again through all the transports available
+const sendToTransports = (envelope: ClipEnvelope) => {
+  const sentTime = Date.now();
+  const transports = getTransportsToUse();
+  clipTransports
+    .filter((transport) => !transports.includes(transport))
+    .forEach((transport) => {
+      console.log(
+        `Skipping ${transport.name}, which is ${
+          transport.isAvailable() ? "slower" : "not available"
+        }`
+      );
+    });
+  transports.forEach((transport) => {
+    try {
+      transport.send(envelope);
+    } catch (error) {
+      console.error(
+        `Error sending clipboard through ${transport.name}: ${error}`
+      );
+    }
+  });
+  sentEnvelopeTimes.set(envelope.id, sentTime);
+  setTimeout(() => {
+    sentEnvelopeTimes.delete(envelope.id);
+    peerPaths.forEach((paths, peer) => {
+      transports.forEach((transport) => {
+        const peerPath = paths.get(transport.name);
+        if (peerPath && peerPath.time < sentTime) {
+          console.log(
+            `${getDeviceName(peer)} did not answer through ${
+              transport.name
+            } in time`
+          );
+          paths.delete(transport.name);
+        }
+      });
+    });
+  }, transportAckTimeoutMs);
+};
+
+// Answers through the same transport, also the copies arriving after the
+// clipboard was applied, so the sender knows how fast each path is. Not to
+// the versions which would apply the answer as an empty clipboard.
+const sendTransportAck = (envelope: ClipEnvelope, transportName: string) => {
+  const transport = clipTransports.find(
+    (clipTransport) => clipTransport.name === transportName
+  );
+  if (
+    envelope.hostname === hostname ||
+    Date.now() - envelope.time > transportAckTimeoutMs ||
+    !transport ||
+    !transport.isAvailable() ||
+    !isFeatureSupportedByPeers("transport-acks")
+  ) {
+    return;
+  }
+  try {
+    transport.send({
+      id: randomBytes(8).toString("hex"),
+      itemNumber: 0,
+      hostname,
+      channel: envelope.channel,
+      type: "text",
+      data: "",
+      time: Date.now(),
+      ackOf: envelope.id,
+    });
+  } catch (error) {
+    console.error(
+      `Error answering clipboard ${envelope.id} through ${transportName}: ${error}`
+    );
+  }
+};
+
+const handleTransportAck = (ack: ClipEnvelope, transportName: string) => {
+  const sentTime = sentEnvelopeTimes.get(ack.ackOf);
+  if (ack.hostname === hostname || sentTime === undefined) {
+    return;
+  }
+  recordPeerPath(ack.hostname, transportName, Date.now() - sentTime);
+};
+
+const sealClipEnvelope = (envelope: ClipEnvelope) => {
+  return sealEnvelope(envelope, getControlKeyId(controlKey), controlKey);
+};
+
+// Throws if it was not sealed with an accepted key
+const openClipEnvelope = (message: Buffer) => {
+  return openEnvelope(
+    message,
+    (keyId) => getControlKeyFor({ keyId } as ControlMessage),
+    envelopeMaxAgeMs
+  );
+};
+
+const handleSealedEnvelope = (message: Buffer, transportName: string) => {
+  let envelope: ClipEnvelope;
+  try {
+    envelope = openClipEnvelope(message);
+  } catch (error) {
+    console.log(`Ignoring message from ${transportName}: ${error}`);
+    return false;
+  }
+  if (envelope.ackOf) {
+    handleTransportAck(envelope, transportName);
+    return true;
+  }
+  sendTransportAck(envelope, transportName);
+  applyClipEnvelope(envelope, transportName);
+  return true;
+};
+
+// Returns the addresses of this computer and of the online peers in the
+// tailnet, or null if Tailscale is not running
+const getTailscaleStatus = async () => {
+  const result = await runCommand("tailscale", ["status", "--json"]);
+  if (result.status !== 0) {
+    return null;
+  }
+  try {
+    const status = JSON.parse(result.stdout);
+    const getIPv4 = (addresses: string[]) =>
+      (addresses || []).find((address) => !address.includes(":"));
+    return {
+      selfAddress: getIPv4(status.Self && status.Self.TailscaleIPs),
+      peerAddresses: Object.values(status.Peer || {})
+        .filter((peer: { Online?: boolean }) => peer.Online)
+        .map((peer: { TailscaleIPs?: string[] }) => getIPv4(peer.TailscaleIPs))
+        .filter((address) => address),
+    };
+  } catch (error) {
+    console.error(`Error reading the Tailscale status: ${error}`);
+    return null;
+  }
+};
+
+// WireGuard interfaces are usually named wg0, wg1...
+const getWireGuardAddresses = () => {
+  const addresses: string[] = [];
+  Object.entries(os.networkInterfaces()).forEach(([name, interfaces]) => {
+    if (/^wg/.test(name)) {
+      (interfaces || [])
+        .filter((networkInterface) => networkInterface.family === "IPv4")
+        .forEach((networkInterface) =>
+          addresses.push(networkInterface.address)
+        );
+    }
+  });
+  return addresses;
+};
+
+const getHelloProof = (key: Buffer, nonce: string) => {
+  return createHmac("sha256", getTransportKey(key))
+    .update(`hello:${nonce}`)
+    .digest();
+};
+
+// Asks the peer to prove it has one of the paired keys, answering a random
+// challenge, before any clipboard is sent to it
+const verifyDirectPeer = (peerAddress: string) => {
+  const [peerHost, peerPort] = peerAddress.split(":");
+  const nonce = randomBytes(16).toString("hex");
+  return new Promise<boolean>((resolve) => {
+    const request = http.get(
+      {
+        host: peerHost,
+        port: parseInt(peerPort),
+        path: `/hello?nonce=${nonce}`,
+        timeout: 3000,
+      },
+      (response) => {
+        let body = "";
+        response.on("data", (chunk) => (body += chunk));
+        response.on("end", () => {
+          try {
+            const { keyId, proof } = JSON.parse(body);
+            const key = getControlKeyFor({ keyId } as ControlMessage);
+            const actual = Buffer.from(String(proof), "hex");
+            const expected = key && getHelloProof(key, nonce);
+            resolve(
+              !!expected &&
+                expected.length === actual.length &&
+                timingSafeEqual(expected, actual)
+            );
+          } catch (error) {
+            resolve(false);
+          }
+        });
+      }
+    );
+    request.on("timeout", () => request.destroy());
+    request.on("error", () => resolve(false));
+  });
+};
+
+// The peers listed in directPeers are trusted as they are, the ones found in
+// the tailnet only once verified
+const refreshDirectPeers = async () => {
+  if (!clipTransports.some((transport) => transport.name === "direct")) {
+    return;
+  }
+  const port = config.get("directPort", 47130);
+  const tailscaleStatus = await getTailscaleStatus();
+  const tailscalePeers = (
+    tailscaleStatus ? tailscaleStatus.peerAddresses : []
+  ).map((address) => `${address}:${port}`);
+  await Promise.all(
+    tailscalePeers
+      .filter((peerAddress) => !verifiedDirectPeers.has(peerAddress))
+      .map(async (peerAddress) => {
+        if (await verifyDirectPeer(peerAddress)) {
+          console.log(`Verified ${peerAddress} for direct transfers`);
+          verifiedDirectPeers.add(peerAddress);
+        }
+      })
+  );
+  if (!clipTransports.some((transport) => transport.name === "direct")) {
+    return;
+  }
+  directPeerAddresses = [
+    ...tailscalePeers.filter((peerAddress) =>
+      verifiedDirectPeers.has(peerAddress)
+    ),
+    ...config.get("directPeers", []),
+  ];
+};
+
+// Listens only on the tunnel interfaces, so no port gets opened to the
+// networks the computer is on
+const startDirectTransport = async () => {
+  const port = config.get("directPort", 47130);
+  const tailscaleStatus = await getTailscaleStatus();
+  // Stopped while the status was read
+  if (!clipTransports.some((transport) => transport.name === "direct")) {
+    return;
+  }
+  const listenAddresses = [
+    ...(tailscaleStatus && tailscaleStatus.selfAddress
+      ? [tailscaleStatus.selfAddress]
+      : []),
+    ...getWireGuardAddresses(),
+  ];
+  if (listenAddresses.length === 0) {
+    console.log(
+      "No Tailscale or WireGuard interface found for direct transfers"
+    );
+  }
+  directServers = listenAddresses.map((address) => {
+    const server = http.createServer((request, response) => {
+      const helloMatch = (request.url || "").match(
+        /^\/hello\?nonce=([0-9a-f]{32})$/
+      );
+      if (request.method === "GET" && helloMatch && controlKey) {
+        response.writeHead(200, { "Content-Type": "application/json" });
+        response.end(
+          JSON.stringify({
+            keyId: getControlKeyId(controlKey),
+            proof: getHelloProof(controlKey, helloMatch[1]).toString("hex"),
+          })
+        );
+        return;
+      }
+      if (request.method !== "POST" || request.url !== "/clip") {
+        response.writeHead(404).end();
+        return;
+      }
+      const maxBodySize = config.get("maxImageSizeMb", 50) * 1024 * 1024 * 1.5;
+      const chunks: Buffer[] = [];
+      let bodySize = 0;
+      request.on("data", (chunk: Buffer) => {
+        bodySize += chunk.length;
+        if (bodySize > maxBodySize) {
+          response.writeHead(413).end();
+          request.destroy();
+          return;
+        }
+        chunks.push(chunk);
+      });
+      request.on("end", () => {
+        if (
+          !controlKey ||
+          !handleSealedEnvelope(Buffer.concat(chunks), "direct")
+        ) {
+          response.writeHead(401).end();
+          return;
+        }
+        response.writeHead(204).end();
+      });
+    });
+    server.on("error", (error) => {
+      console.error(`Error on direct transfers server: ${error}`);
+    });
+    server.listen(port, address, () => {
+      console.log(`Direct transfers served on ${address}:${port}`);
+    });
+    return server;
+  });
+  refreshDirectPeers();
+};
+
+const stopDirectTransport = () => {
+  directServers.forEach((server) => server.close());
+  directServers = [];
+  directPeerAddresses = [];
+  verifiedDirectPeers.clear();
+};
+
+// The folder still gets the clipboard, so failures are only logged
+const sendDirectly = (envelope: ClipEnvelope) => {
+  const body = sealClipEnvelope(envelope);
+  directPeerAddresses.forEach((peerAddress) => {
+    const [peerHost, peerPort] = peerAddress.split(":");
+    const request = http.request(
+      {
+        host: peerHost,
+        port: parseInt(peerPort) || config.get("directPort", 47130),
+        path: "/clip",
+        method: "POST",
+        timeout: 3000,
+        headers: {
+          "Content-Type": "application/json",
+          "Content-Length": Buffer.byteLength(body),
+        },
+      },
+      (response) => response.resume()
+    );
+    request.on("timeout", () => request.destroy());
+    request.on("error", (error) => {
+      console.log(
+        `Could not send directly to ${peerAddress}: ${error.message}`
+      );
+    });
+    request.end(body);
+  });
+};
+
+const isSameSecret = (a: string, b: string) => {
+  return timingSafeEqual(
+    createHash("sha256").update(a).digest(),
+    createHash("sha256").update(b).digest()
+  );
+};
+
+let relayServer: net.Server = null;
+let relayPingInterval: NodeJS.Timeout = null;
+const relayConnections = new Set<WebSocketConnection>();
+
+let relayConnection: WebSocketConnection = null;
+let relayRequest: http.ClientRequest = null;
+let relayReconnectWaiter: NodeJS.Timeout = null;
+let relayClientPingInterval: NodeJS.Timeout = null;
+let relayFailures = 0;
+let relayClientStopped = true;
+
+const maxRelayReconnectDelayMs = 60000;
+
+let wakeHintSocket: dgram.Socket = null;
+// Tells our own hints apart, without naming this computer
+const wakeHintInstanceId = randomBytes(8).toString("hex");
+const wakeHintPort = 47132;
+// The hints usually arrive before the cloud client downloads the clipboard,
+// so the folder is scanned again a few times after each one
+const folderScanDelaysMs = [1000, 3000, 10000, 30000];
+let folderScanWaiter: NodeJS.Timeout = null;
+
+let ntfyRequest: http.ClientRequest = null;
+let ntfyReconnectWaiter: NodeJS.Timeout = null;
+let ntfyFailures = 0;
+let ntfyStopped = true;
+
+let natsSocket: net.Socket = null;
+let natsReconnectWaiter: NodeJS.Timeout = null;
+let natsFailures = 0;
+let natsStopped = true;
+let natsMaxPayload = 1024 * 1024;
+
+// Forwards every message from a client to all the other clients connected
+// with the same token. The clipboards are only relayed, never stored.
+const startRelayServer = () => {
+  if (process.platform === "darwin") {
+    app.dock.hide();
+  }
+  const token =
+    getArgValue("relay-token") || process.env.CLIPBOARD_SYNC_RELAY_TOKEN;
+  if (!token) {
+    console.error(
+      "A token is required, pass it with --relay-token or CLIPBOARD_SYNC_RELAY_TOKEN"
+    );
+    app.exit(1);
+    return;
+  }
+  const port = parseInt(getArgValue("relay-port")) || 47131;
+  const host = getArgValue("relay-host") || "0.0.0.0";
+  const certFile = getArgValue("relay-cert");
+  const keyFile = getArgValue("relay-key");
+
+  const handleRequest = (
+    request: http.IncomingMessage,
+    response: http.ServerResponse
+  ) => {
+    response.writeHead(426, { "Content-Type": "text/plain" });
+    response.end(`${app.name} relay, connect with a WebSocket to /ws\n`);
+  };
+  relayServer =
+    certFile && keyFile
+      ? createHttpsServer(
+          { cert: fs.readFileSync(certFile), key: fs.readFileSync(keyFile) },
+          handleRequest
+        )
+      : http.createServer(handleRequest);
+
+  const handleUpgrade = (
+    request: http.IncomingMessage,
+    socket: net.Socket,
+    head: Buffer
+  ) => {
+    const url = new URL(request.url, "http://relay");
+    const authorization = request.headers.authorization || "";
+    const clientToken = authorization.startsWith("Bearer ")
+      ? authorization.substring("Bearer ".length)
+      : url.searchParams.get("token") || "";
+    const key = request.headers["sec-websocket-key"];
+    if (url.pathname !== "/ws" || !key) {
+      socket.end("HTTP/1.1 404 Not Found\r\n\r\n");
+      return;
+    }
+    if (!isSameSecret(clientToken, token)) {
+      socket.end("HTTP/1.1 401 Unauthorized\r\n\r\n");
+      return;
+    }
+    socket.write(
+      [
+        "HTTP/1.1 101 Switching Protocols",
+        "Upgrade: websocket",
+        "Connection: Upgrade",
+        `Sec-WebSocket-Accept: ${getWebSocketAccept(key)}`,
+        "",
+        "",
+      ].join("\r\n")
+    );
+    const connection = createWebSocketConnection(
+      socket,
+      false,
+      // Forwarded as received, the relay can't read the sealed envelopes
+      (message, opcode) => {
+        relayConnections.forEach((other) => {
+          if (other !== connection) {
+            other.send(message, opcode);
+          }
+        });
+      },
+      () => {
+        relayConnections.delete(connection);
+        console.log(`Client disconnected, ${relayConnections.size} left`);
+      }
+    );
+    relayConnections.add(connection);
+    if (head && head.length > 0) {
+      socket.unshift(head);
+    }
+    console.log(
+      `Client connected from ${request.socket.remoteAddress}, ${relayConnections.size} connected`
+    );
+  };
+  relayServer.on("upgrade", handleUpgrade);
+
+  // Drops the clients which stopped answering
+  relayPingInterval = setInterval(() => {
+    relayConnections.forEach((connection) => {
+      if (!connection.alive) {
+        connection.close();
+        relayConnections.delete(connection);
+        return;
+      }
+      connection.alive = false;
+      connection.ping();
+    });
+  }, 60000);
+
+  relayServer.on("error", (error) => {
+    console.error(`Error on relay server: ${error}`);
+    app.exit(1);
+  });
+  relayServer.listen(port, host, () => {
+    const protocol = certFile && keyFile ? "wss" : "ws";
+    console.log(`Relay listening on ${protocol}://${host}:${port}/ws`);
+  });
+};
+
+const stopRelayServer = () => {
+  if (relayPingInterval) {
+    clearInterval(relayPingInterval);
+    relayPingInterval = null;
+  }
+  relayConnections.forEach((connection) => connection.close());
+  relayConnections.clear();
+  if (relayServer) {
+    relayServer.close();
+    relayServer = null;
+  }
+};
+
+const scheduleRelayReconnect = () => {
+  if (relayClientStopped || relayReconnectWaiter) {
+    return;
+  }
+  relayFailures++;
+  const delay = Math.min(
+    1000 * 2 ** (relayFailures - 1),
+    maxRelayReconnectDelayMs
+  );
+  console.log(`Reconnecting to the relay in ${delay / 1000} seconds...`);
+  relayReconnectWaiter = setTimeout(() => {
+    relayReconnectWaiter = null;
+    connectToRelay();
+  }, delay);
+};
+
+const connectToRelay = () => {
+  if (relayClientStopped) {
+    return;
+  }
+  let url: URL;
+  try {
+    url = new URL(config.get("relayUrl"));
+  } catch (error) {
+    console.error(`Invalid relay URL: ${error}`);
+    return;
+  }
+  const key = randomBytes(16).toString("base64");
+  const secure = url.protocol === "wss:" || url.protocol === "https:";
+  const requestOptions = {
+    host: url.hostname,
+    port: url.port || (secure ? 443 : 80),
+    path: `${url.pathname}${url.search}`,
+    timeout: 10000,
+    headers: {
+      Connection: "Upgrade",
+      Upgrade: "websocket",
+      "Sec-WebSocket-Key": key,
+      "Sec-WebSocket-Version": "13",
+      ...(config.get("relayToken")
+        ? { Authorization: `Bearer ${config.get("relayToken")}` }
+        : {}),
+    },
+  };
+  const request = secure
+    ? httpsRequest(requestOptions)
+    : http.request(requestOptions);
+  relayRequest = request;
+  request.on("upgrade", (response, socket, head) => {
+    relayRequest = null;
+    if (response.headers["sec-websocket-accept"] !== getWebSocketAccept(key)) {
+      console.error("The relay answered with an invalid handshake");
+      socket.destroy();
+      scheduleRelayReconnect();
+      return;
+    }
+    relayConnection = createWebSocketConnection(
+      socket,
+      true,
+      (message) => handleSealedEnvelope(message, "relay"),
+      () => {
+        relayConnection = null;
+        console.log("Disconnected from the relay");
+        setContextMenu();
+        scheduleRelayReconnect();
+      }
+    );
+    if (head && head.length > 0) {
+      socket.unshift(head);
+    }
+    relayFailures = 0;
+    console.log(`Connected to the relay at ${url.host}`);
+    setContextMenu();
+  });
+  request.on("response", (response) => {
+    relayRequest = null;
+    console.error(`The relay refused the connection: ${response.statusCode}`);
+    response.resume();
+    scheduleRelayReconnect();
+  });
+  request.on("timeout", () => request.destroy());
+  request.on("error", (error) => {
+    relayRequest = null;
+    console.error(`Could not connect to the relay: ${error.message}`);
+    scheduleRelayReconnect();
+  });
+  request.end();
+};
+
+const startRelayClient = () => {
+  relayClientStopped = false;
+  relayFailures = 0;
+  connectToRelay();
+  // Reconnects when the relay stops answering, like after resuming
+  relayClientPingInterval = setInterval(() => {
+    if (!relayConnection) {
+      return;
+    }
+    if (!relayConnection.alive) {
+      relayConnection.close();
+      return;
+    }
+    relayConnection.alive = false;
+    relayConnection.ping();
+  }, 30000);
+};
+
+const stopRelayClient = () => {
+  relayClientStopped = true;
+  if (relayReconnectWaiter) {
+    clearTimeout(relayReconnectWaiter);
+    relayReconnectWaiter = null;
+  }
+  if (relayClientPingInterval) {
+    clearInterval(relayClientPingInterval);
+    relayClientPingInterval = null;
+  }
+  if (relayRequest) {
+    relayRequest.destroy();
+    relayRequest = null;
+  }
+  if (relayConnection) {
+    relayConnection.close();
+    relayConnection = null;
+  }
+};
+
+// Clipboards sent while disconnected still go through the folder
+const sendToRelay = (envelope: ClipEnvelope) => {
+  if (relayConnection) {
+    relayConnection.send(sealClipEnvelope(envelope));
+  }
+};
+
+// Looks for clipboard files which just showed up, without waiting for the
+// watcher, which may be polling
+const scanFolderNow = () => {
+  if (!syncFolder || !folderAvailable || !config.get("receive", true)) {
+    return;
+  }
+  const recentTime = Date.now() - 60000;
+  try {
+    fs.readdirSync(syncFolder).forEach((file) => {
+      const filePath = path.join(syncFolder, file);
+      const clipboardFile = parseClipboardFile(filePath);
+      if (
+        clipboardFile &&
+        clipboardFile.hostname !== hostname &&
+        fs.statSync(filePath).mtime.getTime() >= recentTime
+      ) {
+        handleFolderEvent(filePath);
+      }
+    });
+  } catch (error) {
+    console.error(`Error scanning the folder: ${error}`);
+  }
+};
+
+// Scans the folder now and again after each of folderScanDelaysMs, starting
+// over when another hint arrives meanwhile
+const scanFolderSoon = (attempt = 0) => {
+  if (folderScanWaiter) {
+    clearTimeout(folderScanWaiter);
+    folderScanWaiter = null;
+  }
+  scanFolderNow();
+  if (attempt >= folderScanDelaysMs.length) {
+    return;
+  }
+  folderScanWaiter = setTimeout(() => {
+    folderScanWaiter = null;
+    scanFolderSoon(attempt + 1);
+  }, folderScanDelaysMs[attempt]);
+};
+
+// Announces on the LAN that a clipboard was written, so the others scan the
+// folder right away. The clipboard itself still goes through the folder.
+const startWakeHints = () => {
+  if (!config.get("lanWakeHints", false)) {
+    return;
+  }
+  const socket = dgram.createSocket({ type: "udp4", reuseAddr: true });
+  socket.on("message", (message) => {
+    try {
+      const hint = JSON.parse(message.toString("utf8"));
+      if (
+        hint.app === "clipboard-sync" &&
+        hint.instance !== wakeHintInstanceId
+      ) {
+        scanFolderSoon();
+      }
+    } catch (error) {
+      // Not ours
+    }
+  });
+  socket.on("error", (error) => {
+    console.error(`Error on LAN wake hints: ${error}`);
+  });
+  socket.bind(wakeHintPort, () => {
+    socket.setBroadcast(true);
+  });
+  wakeHintSocket = socket;
+};
+
+const stopWakeHints = () => {
+  if (wakeHintSocket) {
+    wakeHintSocket.close();
+    wakeHintSocket = null;
+  }
+};
+
+const sendLanWakeHint = () => {
+  if (!wakeHintSocket) {
+    return;
+  }
+  const hint = Buffer.from(
+    JSON.stringify({ app: "clipboard-sync", instance: wakeHintInstanceId }),
+    "utf8"
+  );
+  wakeHintSocket.send(hint, wakeHintPort, "255.255.255.255", (error) => {
+    if (error) {
+      console.error(`Error sending LAN wake hint: ${error}`);
+    }
+  });
+};
+
+// The reachable phones paired with KDE Connect, through its D-Bus API
+const getKdeConnectDevices = async () => {
+  const result = await runCommand(
+    "gdbus",
+    [
+      "call",
+      "--session",
+      "--dest",
+      "org.kde.kdeconnect",
+      "--object-path",
+      "/modules/kdeconnect",
+      "--method",
+      "org.kde.kdeconnect.daemon.devices",
+      "true",
+      "true",
+    ],
+    { timeout: 2000 }
+  );
+  if (result.status !== 0) {
+    return [];
+  }
+  // Prints like "(['0123abcd', '4567ef01'],)"
+  return (result.stdout.match(/'[^']+'/g) || []).map((id) =>
+    id.replace(/'/g, "")
+  );
+};
+
+// The object paths of the phones known to GSConnect, the KDE Connect
+// implementation for GNOME, which has its own D-Bus API
+const getGsConnectDevices = async () => {
+  const result = await runCommand(
+    "gdbus",
+    [
+      "call",
+      "--session",
+      "--dest",
+      "org.gnome.Shell.Extensions.GSConnect",
+      "--object-path",
+      "/org/gnome/Shell/Extensions/GSConnect",
+      "--method",
+      "org.freedesktop.DBus.ObjectManager.GetManagedObjects",
+    ],
+    { timeout: 2000 }
+  );
+  if (result.status !== 0) {
+    return [];
+  }
+  return [
+    ...new Set(
+      result.stdout.match(
+        /\/org\/gnome\/Shell\/Extensions\/GSConnect\/Device\/[0-9A-Za-z_]+/g
+      ) || []
+    ),
+  ];
+};
+
+const callGdbusMethod = async (
+  destination: string,
+  objectPath: string,
+  method: string,
+  args: string[] = []
+) => {
+  const result = await runCommand("gdbus", [
+    "call",
+    "--session",
+    "--dest",
+    destination,
+    "--object-path",
+    objectPath,
+    "--method",
+    method,
+    ...args,
+  ]);
+  if (result.status !== 0) {
+    console.error(
+      `Error forwarding clipboard to ${objectPath}: ${
+        result.stderr.trim() || "gdbus failed"
+      }`
+    );
+  }
+};
+
+// Sends the clipboard which was just received to the phones, through KDE
+// Connect or GSConnect, whichever is running. Their clipboards reach this
+// computer's clipboard through them, and from there the other computers like
+// any other copy.
+const forwardToKdeConnect = async () => {
+  if (process.platform !== "linux" || !config.get("kdeConnect", false)) {
+    return;
+  }
+  const [kdeConnectDevices, gsConnectDevices] = await Promise.all([
+    getKdeConnectDevices(),
+    getGsConnectDevices(),
+  ]);
+  kdeConnectDevices.forEach((device) =>
+    callGdbusMethod(
+      "org.kde.kdeconnect",
+      `/modules/kdeconnect/devices/${device}/clipboard`,
+      "org.kde.kdeconnect.device.clipboard.sendClipboard"
+    )
+  );
+  gsConnectDevices.forEach((devicePath) =>
+    callGdbusMethod(
+      "org.gnome.Shell.Extensions.GSConnect",
+      devicePath,
+      "org.gtk.Actions.Activate",
+      ["clipboardPush", "[]", "{}"]
+    )
+  );
+};
+
+// Push-like latency across the internet for the folder: a ping is published
+// to an ntfy topic after each write, and the others scan the folder when they
+// get it. The ping only has an ID to tell our own apart, no clipboard content.
+const getNtfyTopicUrl = (suffix = "") => {
+  return new URL(
+    `${config.get("ntfyServer", "https://ntfy.sh").replace(/\/+$/, "")}/${
+      config.get("ntfyTopic")
+    }${suffix}`
+  );
+};
+
+const scheduleNtfyReconnect = () => {
+  if (ntfyStopped || ntfyReconnectWaiter) {
+    return;
+  }
+  ntfyFailures++;
+  const delay = Math.min(
+    1000 * 2 ** (ntfyFailures - 1),
+    maxRelayReconnectDelayMs
+  );
+  ntfyReconnectWaiter = setTimeout(() => {
+    ntfyReconnectWaiter = null;
+    subscribeToNtfy();
+  }, delay);
+};
+
+const subscribeToNtfy = () => {
+  if (ntfyStopped) {
+    return;
+  }
+  const url = getNtfyTopicUrl("/json");
+  const handleResponse = (response: http.IncomingMessage) => {
+    if (response.statusCode !== 200) {
+      console.error(`ntfy refused the subscription: ${response.statusCode}`);
+      response.resume();
+      ntfyRequest = null;
+      scheduleNtfyReconnect();
+      return;
+    }
+    ntfyFailures = 0;
+    console.log(`Subscribed to ntfy topic at ${url.host}`);
+    let buffered = "";
+    response.setEncoding("utf8");
+    response.on("data", (chunk: string) => {
+      buffered += chunk;
+      const lines = buffered.split("\n");
+      buffered = lines.pop();
+      lines.forEach((line) => {
+        try {
+          const event = JSON.parse(line);
+          if (
+            event.event === "message" &&
+            event.message !== wakeHintInstanceId
+          ) {
+            scanFolderSoon();
+          }
+        } catch (error) {
+          console.error(`Error reading ntfy event: ${error}`);
+        }
+      });
+    });
+    response.on("end", () => {
+      ntfyRequest = null;
+      scheduleNtfyReconnect();
+    });
+  };
+  const request =
+    url.protocol === "https:"
+      ? httpsRequest(url, handleResponse)
+      : http.request(url, handleResponse);
+  // ntfy sends keepalive events every 45 seconds
+  request.setTimeout(120000, () => request.destroy());
+  request.on("error", (error) => {
+    ntfyRequest = null;
+    console.error(`Could not subscribe to ntfy: ${error.message}`);
+    scheduleNtfyReconnect();
+  });
+  request.end();
+  ntfyRequest = request;
+};
+
+const startNtfy = () => {
+  if (!config.get("ntfyTopic")) {
+    return;
+  }
+  ntfyStopped = false;
+  ntfyFailures = 0;
+  subscribeToNtfy();
+};
+
+const stopNtfy = () => {
+  ntfyStopped = true;
+  if (ntfyReconnectWaiter) {
+    clearTimeout(ntfyReconnectWaiter);
+    ntfyReconnectWaiter = null;
+  }
+  if (ntfyRequest) {
+    ntfyRequest.destroy();
+    ntfyRequest = null;
+  }
+};
+
+const sendNtfyWakeHint = () => {
+  if (ntfyStopped) {
+    return;
+  }
+  const url = getNtfyTopicUrl();
+  const options = { method: "POST", timeout: 10000 };
+  const handleResponse = (response: http.IncomingMessage) => response.resume();
+  const request =
+    url.protocol === "https:"
+      ? httpsRequest(url, options, handleResponse)
+      : http.request(url, options, handleResponse);
+  request.on("timeout", () => request.destroy());
+  request.on("error", (error) => {
+    console.error(`Could not publish to ntfy: ${error.message}`);
+  });
+  request.end(wakeHintInstanceId);
+};
+
+const sendWakeHint = () => {
+  sendLanWakeHint();
+  sendNtfyWakeHint();
+};
+
+// JetStream keeps the clipboards for a while, so the computers which were
+// offline get them once back
+const getNatsStreamName = () => {
+  return `CLIPBOARD_SYNC_${config
+    .get("natsSubject", "clipboard-sync.default")
+    .replace(/[^0-9a-zA-Z_-]/g, "_")}`;
+};
+
+const sendNatsCommand = (command: string, payload?: string) => {
+  if (!natsSocket) {
+    return;
+  }
+  natsSocket.write(encodeNatsCommand(command, payload));
+};
+
+// The JetStream API is called through requests, answered to an inbox subject
+const setUpNatsJetStream = (deliverSubject: string, inbox: string) => {
+  const subject = config.get("natsSubject", "clipboard-sync.default");
+  const streamName = getNatsStreamName();
+  sendNatsCommand(
+    `PUB $JS.API.STREAM.CREATE.${streamName} ${inbox}.stream`,
+    JSON.stringify({
+      name: streamName,
+      subjects: [subject],
+      storage: "file",
+      // In nanoseconds
+      max_age: 24 * 60 * 60 * 1e9,
+    })
+  );
+  const durableName = `clipboard-sync-${hostname}`;
+  sendNatsCommand(
+    `PUB $JS.API.CONSUMER.DURABLE.CREATE.${streamName}.${durableName} ${inbox}.consumer`,
+    JSON.stringify({
+      stream_name: streamName,
+      config: {
+        durable_name: durableName,
+        deliver_subject: deliverSubject,
+        deliver_policy: "new",
+        ack_policy: "explicit",
+      },
+    })
+  );
+};
+
+const connectToNats = () => {
+  if (natsStopped) {
+    return;
+  }
+  let url: URL;
+  try {
+    url = new URL(config.get("natsUrl"));
+  } catch (error) {
+    console.error(`Invalid NATS URL: ${error}`);
+    return;
+  }
+  const subject = config.get("natsSubject", "clipboard-sync.default");
+  const jetStream = config.get("natsJetStream", false);
+  const inbox = `_INBOX.${randomBytes(8).toString("hex")}`;
+  const deliverSubject = `_INBOX.clipboard-sync.${hostname}`;
+  let socket: net.Socket = net.connect({
+    host: url.hostname,
+    port: parseInt(url.port) || 4222,
+  });
+  let connected = false;
+
+  const handleData: (chunk: Buffer) => void = createNatsParser(
+    (operation, args, payload) => {
+      if (operation === "MSG") {
+        const replyTo = args.length === 4 ? args[2] : null;
+        if (args[0].startsWith(inbox)) {
+          try {
+            const answer = JSON.parse(payload.toString("utf8"));
+            if (answer.error) {
+              console.error(`JetStream error: ${answer.error.description}`);
+            }
+          } catch (error) {
+            console.error(`Error reading JetStream answer: ${error}`);
+          }
+        } else {
+          handleSealedEnvelope(payload, "NATS");
+          if (replyTo) {
+            // Acknowledges it to JetStream
+            sendNatsCommand(`PUB ${replyTo}`, "+ACK");
+          }
+        }
+      } else if (operation === "INFO") {
+        const info = JSON.parse(args.join(" "));
+        natsMaxPayload = info.max_payload || natsMaxPayload;
+        if (!connected && (url.protocol === "tls:" || info.tls_required)) {
+          // The connection is upgraded to TLS after the first INFO
+          socket.removeListener("data", handleData);
+          socket = tls.connect({ socket, servername: url.hostname });
+          natsSocket = socket;
+          socket.on("data", handleData);
+          socket.on("error", handleError);
+        }
+        if (!connected) {
+          connected = true;
+          sendNatsCommand(
+            `CONNECT ${JSON.stringify({
+              verbose: false,
+              pedantic: false,
+              name: app.name,
+              lang: "node",
+              version: app.getVersion(),
+              protocol: 1,
+              ...(url.username && !url.password
+                ? { auth_token: decodeURIComponent(url.username) }
+                : {}),
+              ...(url.username && url.password
+                ? {
+                    user: decodeURIComponent(url.username),
+                    pass: decodeURIComponent(url.password),
+                  }
+                : {}),
+            })}`
+          );
+          if (jetStream) {
+            sendNatsCommand(`SUB ${inbox}.* 1`);
+            sendNatsCommand(`SUB ${deliverSubject} 2`);
+            setUpNatsJetStream(deliverSubject, inbox);
+          } else {
+            sendNatsCommand(`SUB ${subject} 2`);
+          }
+          natsFailures = 0;
+          console.log(`Connected to NATS at ${url.host}`);
+        }
+      } else if (operation === "PING") {
+        sendNatsCommand("PONG");
+      } else if (operation === "-ERR") {
+        console.error(`NATS error: ${args.join(" ")}`);
+      }
+    }
+  );
+  const handleError = (error: Error) => {
+    console.error(`NATS connection error: ${error.message}`);
+  };
+
+  natsSocket = socket;
+  socket.on("data", handleData);
+  socket.on("error", handleError);
+  socket.on("close", () => {
+    if (natsSocket !== socket && natsSocket !== null) {
+      return;
+    }
+    natsSocket = null;
+    if (natsStopped || natsReconnectWaiter) {
+      return;
+    }
+    natsFailures++;
+    const delay = Math.min(
+      1000 * 2 ** (natsFailures - 1),
+      maxRelayReconnectDelayMs
+    );
+    console.log(`Reconnecting to NATS in ${delay / 1000} seconds...`);
+    natsReconnectWaiter = setTimeout(() => {
+      natsReconnectWaiter = null;
+      connectToNats();
+    }, delay);
+  });
+};
+
+const startNatsTransport = () => {
+  natsStopped = false;
+  natsFailures = 0;
+  connectToNats();
+};
+
+const stopNatsTransport = () => {
+  natsStopped = true;
+  if (natsReconnectWaiter) {
+    clearTimeout(natsReconnectWaiter);
+    natsReconnectWaiter = null;
+  }
+  if (natsSocket) {
+    natsSocket.destroy();
+    natsSocket = null;
+  }
+};
+
+const sendToNats = (envelope: ClipEnvelope) => {
+  const message = sealClipEnvelope(envelope);
+  if (Buffer.byteLength(message) > natsMaxPayload) {
+    console.log(
+      "Clipboard is larger than the NATS maximum payload. Skipping..."
+    );
+    return;
+  }
+  sendNatsCommand(
+    `PUB ${config.get("natsSubject", "clipboard-sync.default")}`,
+    message
+  );
+};
+
+// The transports need the key shared by paired computers to authenticate
+const startTransports = () => {
+  if (!controlKey) {
+    return;
+  }
+  if (config.get("directTransport", false)) {
+    clipTransports.push({
+      name: "direct",
+      start: startDirectTransport,
+      stop: stopDirectTransport,
+      send: sendDirectly,
+      isAvailable: () =>
+        directServers.length > 0 && directPeerAddresses.length > 0,
+    });
+  }
+  if (config.get("relayUrl")) {
+    clipTransports.push({
+      name: "relay",
+      start: startRelayClient,
+      stop: stopRelayClient,
+      send: sendToRelay,
+      isAvailable: () => relayConnection !== null,
+    });
+  }
+  if (config.get("natsUrl")) {
+    clipTransports.push({
+      name: "NATS",
+      start: startNatsTransport,
+      stop: stopNatsTransport,
+      send: sendToNats,
+      isAvailable: () => natsSocket !== null,
+    });
+  }
+  clipTransports.forEach((transport) => transport.start());
+};
+
+const stopTransports = () => {
+  clipTransports.forEach((transport) => transport.stop());
+  clipTransports = [];
+};
+
+const getRoamingSettingsFilePath = () => {
+  return path.join(syncFolder, "shared.settings.json");
+};
+
+const isRoamingSettingsFile = (file: string) => {
+  return path.basename(file) === "shared.settings.json";
+};
+
+const getSharedSettingKeys = () => {
+  const localSettings = config.get("localSettings", []);
+  return roamingSettings.filter((key) => !localSettings.includes(key));
+};
+
+const readRoamingSettings = () => {
+  if (!syncFolder || !fs.existsSync(getRoamingSettingsFilePath())) {
+    return null;
+  }
+  try {
+    const roamed = JSON.parse(
+      fs.readFileSync(getRoamingSettingsFilePath(), { encoding: "utf8" })
+    );
+    if (roamed && typeof roamed.settings === "object") {
+      return roamed.settings as Partial<ConfigType>;
+    }
+  } catch (error) {
+    console.error(`Error reading the shared settings: ${error}`);
+  }
+  return null;
+};
+
+// The settings kept locally are left as they were in the file, so the other
+// computers keep their values
+const writeRoamingSettings = () => {
+  if (!syncFolder || !folderAvailable || isZeroKnowledgeMode()) {
+    return;
+  }
+  const settings: Record<string, unknown> = { ...readRoamingSettings() };
+  getSharedSettingKeys().forEach((key) => {
+    settings[key] = config.get(key);
+  });
+  try {
+    fs.writeFileSync(
+      getRoamingSettingsFilePath(),
+      JSON.stringify(
+        { updatedBy: hostname, updatedTime: Date.now(), settings },
+        null,
+        2
+      ),
+      { encoding: "utf8" }
+    );
+  } catch (error) {
+    console.error(`Error writing the shared settings: ${error}`);
+  }
+};
+
+// Returns whether any setting changed
+const applyRoamingSettings = () => {
+  if (!config.get("roamSettings", false)) {
+    return false;
+  }
+  const settings = readRoamingSettings();
+  if (!settings) {
+    return false;
+  }
+  let changed = false;
+  applyingRoamingSettings = true;
+  try {
+    getSharedSettingKeys().forEach((key) => {
+      if (!(key in settings)) {
+        return;
+      }
+      if (!isValidRoamingValue(key, settings[key])) {
+        console.error(
+          `Ignoring the shared setting ${key}, which has an invalid value`
+        );
+        return;
+      }
+      if (JSON.stringify(settings[key]) !== JSON.stringify(config.get(key))) {
+        console.log(`Applying the shared setting ${key}`);
+        config.set(key, settings[key]);
+        changed = true;
+      }
+    });
+  } finally {
+    applyingRoamingSettings = false;
+  }
+  return changed;
+};
+
+config.onDidAnyChange((newConfig, oldConfig) => {
+  if (
+    applyingRoamingSettings ||
+    !newConfig?.roamSettings ||
+    !oldConfig?.roamSettings
+  ) {
+    return;
+  }
+  const changed = getSharedSettingKeys().some(
+    (key) => JSON.stringify(newConfig[key]) !== JSON.stringify(oldConfig[key])
+  );
+  if (changed) {
+    writeRoamingSettings();
+  }
+});
+
+const getHealthFilePath = () => {
+  return path.join(app.getPath("userData"), "health.json");
+};
+
+const getSharedHealthFilePath = () => {
+  return path.join(syncFolder, `${hostname}.health.json`);
+};
+
+const getHealth = (running = true) => {
+  let state = "stopped";
+  if (running) {
+    state = !folderAvailable || writeError ? "degraded" : "running";
+  }
+  return {
+    state,
+    hostname,
+    version: app.getVersion(),
+    folder: syncFolder,
+    folderAvailable,
+    sending: config.get("send", true),
+    receiving: config.get("receive", true),
+    channel: getCurrentChannel(),
+    lastSentTime,
+    lastReceivedTime,
+    errors: stats.errors,
+    lastError: stats.lastError,
+    writeError,
+    peers: folderAvailable ? [...getPeersInFolder()].sort() : [],
+    updatedTime: Date.now(),
+  };
+};
+
+// Lets scripts and monitoring agents check whether syncing is alive
+const writeHealthFile = (running = true) => {
+  const health = JSON.stringify(getHealth(running), null, 2);
+  try {
+    fs.writeFileSync(getHealthFilePath(), health, { encoding: "utf8" });
+    // The last state written before exiting must survive a power loss
+    if (!running) {
+      flushToDisk(getHealthFilePath());
+    }
+  } catch (error) {
+    console.error(`Error writing the health file: ${error}`);
+  }
+  if (!syncFolder || !folderAvailable) {
+    return;
+  }
+  try {
+    if (config.get("shareHealthFile", false) && !isZeroKnowledgeMode()) {
+      fs.writeFileSync(getSharedHealthFilePath(), health, { encoding: "utf8" });
+      if (!running) {
+        flushToDisk(getSharedHealthFilePath());
+      }
+    } else if (fs.existsSync(getSharedHealthFilePath())) {
+      fs.unlinkSync(getSharedHealthFilePath());
+    }
+  } catch (error) {
+    console.error(`Error writing the shared health file: ${error}`);
+  }
+};
+
+const protectControlKey = (key: Buffer) => {
+  return safeStorage.encryptString(key.toString("hex")).toString("base64");
+};
+
+const unprotectControlKey = (protectedKey: string) => {
+  if (!protectedKey || !safeStorage.isEncryptionAvailable()) {
+    return null;
+  }
+  try {
+    return Buffer.from(
+      safeStorage.decryptString(Buffer.from(protectedKey, "base64")),
+      "hex"
+    );
+  } catch (error) {
+    console.error("Error decrypting a stored remote control key");
+    return null;
+  }
+};
+
+// When changing the key, the previous one keeps being accepted for a while, so
+// the commands sent by the computers which were not changed yet still work
+const confirmControlKeyChange = async () => {
+  if (!controlKey) {
+    return true;
+  }
+  const { response } = await dialog.showMessageBox({
+    type: "question",
+    title: "Change remote control key",
+    message: "Change the remote control key?",
+    detail: `Commands signed with the current key will still be accepted for ${
+      previousControlKeyGraceMs / (24 * 60 * 60 * 1000)
+    } days. Change it on your other computers meanwhile.`,
+    buttons: ["Change", "Cancel"],
+    defaultId: 0,
+    cancelId: 1,
+  });
+  return response === 0;
+};
+
+const setControlKey = (newControlKey: Buffer) => {
+  if (controlKey && !controlKey.equals(newControlKey)) {
+    previousControlKey = controlKey;
+    config.set(
+      "previousControlKeyExpiry",
+      Date.now() + previousControlKeyGraceMs
+    );
+    if (safeStorage.isEncryptionAvailable()) {
+      config.set(
+        "protectedPreviousControlKey",
+        protectControlKey(previousControlKey)
+      );
+    }
+    console.log(
+      `Rotated remote control key ${getControlKeyId(
+        previousControlKey
+      )} to ${getControlKeyId(newControlKey)}`
+    );
+  }
+  controlKey = newControlKey;
+  if (safeStorage.isEncryptionAvailable()) {
+    config.set("protectedControlKey", protectControlKey(controlKey));
+  } else {
+    // Never stored unprotected, so it only lasts until the app exits
+    new Notification({
+      title: "Remote control key not saved",
+      body: "The operating system can't protect the key, so it is only kept until the app exits. Set it again after restarting.",
+      icon: getAppIcon(),
+    }).show();
+  }
+  setContextMenu();
+};
+
+// The same passphrase must be set on every computer
+const setControlPassphrase = async () => {
+  if (!(await confirmControlKeyChange())) {
+    return;
+  }
+  const passphrase = await askForText({
+    title: "Remote control passphrase",
+    message:
+      "Enter the passphrase used to sign the commands sent between your computers. Use the same one on all of them.",
+    password: true,
+  });
+  if (!passphrase) {
+    return;
+  }
+  setControlKey(scryptSync(passphrase, "clipboard-sync-remote-control", 32));
+};
+
+// Hides the hostnames, channels, times and types from the storage provider. The
+// files are written under random names, and each computer lists its own in an
+// index encrypted with the shared key.
+const isUsingOpaqueFilenames = () => {
+  return (
+    (config.get("opaqueFilenames", false) ||
+      config.get("zeroKnowledgeMode", false)) &&
+    controlKey !== null
+  );
+};
+
+// For folders in storage which is not trusted: on top of the opaque names,
+// the clipboards are encrypted and padded so their sizes don't tell much, and
+// nothing naming this computer is shared, like the pinned clipboards, the
+// shared history and the health file. Files can't be sent.
+const isZeroKnowledgeMode = () => {
+  return config.get("zeroKnowledgeMode", false) && controlKey !== null;
+};
+
+const getPayloadKey = (key: Buffer) => {
+  return createHmac("sha256", key).update("clipboard-sync-payload").digest();
+};
+
+const sealPayload = (data: Buffer) => {
+  let paddedSize = minSealedPayloadSize;
+  while (paddedSize < data.length + 4) {
+    paddedSize *= 2;
+  }
+  const padded = Buffer.alloc(paddedSize);
+  padded.writeUInt32BE(data.length, 0);
+  data.copy(padded, 4);
+  return encryptBuffer(padded, getPayloadKey(controlKey));
+};
+
+// Throws if it was not sealed with any of the keys
+const openPayload = (sealed: Buffer) => {
+  const keys = [controlKey, previousControlKey].filter((key) => key !== null);
+  for (const key of keys) {
+    let padded: Buffer;
+    try {
+      padded = decryptBuffer(sealed, getPayloadKey(key));
+    } catch (error) {
+      continue;
+    }
+    return padded.subarray(4, 4 + padded.readUInt32BE(0));
+  }
+  throw new Error("Clipboard was sealed with an unknown key");
+};
+
+const isOpaqueFile = (file: string) => {
+  return /^[0-9a-f]{32}$/.test(path.basename(file));
+};
+
+const isOpaqueIndexFile = (file: string) => {
+  return /^[0-9a-f]{16}\.index$/.test(path.basename(file));
+};
+
+const getOpaqueIndexKey = (key: Buffer) => {
+  return createHmac("sha256", key).update("clipboard-sync-index").digest();
+};
+
+const getOwnOpaqueIndexPath = () => {
+  const indexId = createHmac("sha256", controlKey)
+    .update(`clipboard-sync-index-${hostname}`)
+    .digest("hex")
+    .substring(0, 16);
+  return path.join(syncFolder, `${indexId}.index`);
+};
+
+const loadOpaqueIndex = () => {
+  opaqueIndex = new Map();
+  opaqueIndexLoadedTime = Date.now();
+  if (!controlKey || !syncFolder || !fs.existsSync(syncFolder)) {
+    return;
+  }
+  const keys = [controlKey, previousControlKey].filter((key) => key !== null);
+  fs.readdirSync(syncFolder)
+    .filter(isOpaqueIndexFile)
+    .forEach((file) => {
+      let data: Buffer;
+      try {
+        data = fs.readFileSync(path.join(syncFolder, file));
+      } catch (error) {
+        return;
+      }
+      // Indexes of computers paired with another key are left alone
+      for (const key of keys) {
+        try {
+          const entries: OpaqueIndexEntry[] = JSON.parse(
+            decryptBuffer(data, getOpaqueIndexKey(key)).toString("utf8")
+          );
+          entries.forEach((entry) => opaqueIndex.set(entry.name, entry));
+          return;
+        } catch (error) {
+          continue;
+        }
+      }
+    });
+};
+
+// Unknown names are looked up again, but not more than once a second, as the
+// folder may have many of them
+const getOpaqueIndexEntry = (name: string) => {
+  if (
+    !opaqueIndex.has(name) &&
+    (opaqueIndexLoadedTime === null ||
+      Date.now() - opaqueIndexLoadedTime > 1000)
+  ) {
+    loadOpaqueIndex();
+  }
+  return opaqueIndex.get(name);
+};
+
+// Returns the name to write the file to
+const addToOpaqueIndex = (clipboardFile: ClipboardFile) => {
+  const name = randomBytes(16).toString("hex");
+  if (opaqueIndexLoadedTime === null) {
+    loadOpaqueIndex();
+  }
+  const entries = [...opaqueIndex.values()].filter(
+    (entry) =>
+      entry.hostname === hostname &&
+      fs.existsSync(path.join(syncFolder, entry.name))
+  );
+  const newEntry = { ...clipboardFile, name };
+  entries.push(newEntry);
+  opaqueIndex.set(name, newEntry);
+  fs.writeFileSync(
+    getOwnOpaqueIndexPath(),
+    encryptBuffer(
+      Buffer.from(JSON.stringify(entries), "utf8"),
+      getOpaqueIndexKey(controlKey)
+    )
+  );
+  return name;
+};
+
+const base32Alphabet = "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// The key in base32, in groups of 4, which is easy to read out and type
+const getPairingCode = (key: Buffer) => {
+  let bits = "";
+  key.forEach((byte) => {
+    bits += byte.toString(2).padStart(8, "0");
+  });
+  let code = "";
+  for (let i = 0; i < bits.length; i += 5) {
+    const value = parseInt(bits.substring(i, i + 5).padEnd(5, "0"), 2);
+    code += base32Alphabet[value];
+  }
+  return code.match(/.{1,4}/g).join("-");
+};
+
+// Returns null if the code is mistyped
+const parsePairingCode = (code: string) => {
+  const characters = code.toUpperCase().replace(/[^A-Z2-7]/g, "");
+  let bits = "";
+  for (const character of characters) {
+    bits += base32Alphabet.indexOf(character).toString(2).padStart(5, "0");
+  }
+  const bytes: number[] = [];
+  for (let i = 0; i + 8 <= bits.length; i += 8) {
+    bytes.push(parseInt(bits.substring(i, i + 8), 2));
+  }
+  return bytes.length === 32 && characters.length === 52
+    ? Buffer.from(bytes)
+    : null;
+};
+
+// A random key is created if there is none yet, so no passphrase needs to be
+// chosen nor sent around
+const showPairingCode = () => {
+  if (!controlKey) {
+    setControlKey(randomBytes(32));
+  }
+  const code = getPairingCode(controlKey);
+  const window = new BrowserWindow({
+    width: 520,
+    height: 220,
+    title: "Pairing code",
+    icon: getAppIcon(),
+    resizable: false,
+    minimizable: false,
+    maximizable: false,
+    autoHideMenuBar: true,
+  });
+  const html = `<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8" />
+    <title>Pairing code</title>
+    <style>
+      body { font-family: sans-serif; font-size: 14px; margin: 16px; }
+      code { display: block; font-size: 18px; margin: 16px 0; user-select: all; }
+    </style>
+  </head>
+  <body>
+    <p>On your other computer, open <b>Remote control &gt; Enter pairing code...</b> and enter:</p>
+    <code>${escapeHtml(code)}</code>
+    <p>Anyone with this code can send commands to this computer.</p>
+  </body>
+</html>`;
+  window.loadURL(`data:text/html;charset=utf-8,${encodeURIComponent(html)}`);
+};
+
+const getPairingRequestPath = (host: string) => {
+  return path.join(syncFolder, `${host}.pairing-request.json`);
+};
+
+const getPairingAnswerPath = (host: string, to: string) => {
+  return path.join(syncFolder, `${host}.pairing-answer.${to}.json`);
+};
+
+const getPairingNoncePath = (host: string, to: string) => {
+  return path.join(syncFolder, `${host}.pairing-nonce.${to}.json`);
+};
+
+const isPairingFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.pairing-(request|(answer|nonce)\.[0-9a-zA-Z-]+)\.json$/.test(
+    path.basename(file)
+  );
+};
+
+const writePairingMessage = (file: string, message: PairingMessage) => {
+  try {
+    fs.writeFileSync(file, JSON.stringify(message, null, 2), {
+      encoding: "utf8",
+    });
+  } catch (error) {
+    console.error(`Error writing ${file}: ${error}`);
+  }
+};
+
+// For computers where the code can't be typed, like the ones with no one in
+// front of them. Any paired computer answers, and the key is only sent and
+// trusted once the code shown on both matches.
+const requestPairing = () => {
+  if (!syncFolder || !folderAvailable) {
+    return;
+  }
+  const ecdh = createECDH("prime256v1");
+  ecdh.generateKeys();
+  pairingRequest = { ecdh, time: Date.now(), exchanges: new Map() };
+  writePairingMessage(getPairingRequestPath(hostname), {
+    publicKey: ecdh.getPublicKey("base64"),
+    time: pairingRequest.time,
+  });
+  new Notification({
+    title: "Pairing requested",
+    body: `Waiting for one of your other computers to answer in the next ${
+      pairingMaxAgeMs / 60000
+    } minutes`,
+    icon: getAppIcon(),
+  }).show();
+};
+
+// Only commits to its nonce at first, which is revealed once the nonce of the
+// other computer arrives
+const answerPairingRequest = (host: string, request: PairingMessage) => {
+  if (
+    !controlKey ||
+    pairingApprovals.has(host) ||
+    typeof request.publicKey !== "string" ||
+    !isPairingMessageFresh(request)
+  ) {
+    return;
+  }
+  if (pairingApprovals.size >= maxPendingPairings) {
+    console.log(
+      `Ignoring pairing request from ${host}, as ${maxPendingPairings} are pending`
+    );
+    return;
+  }
+  const ecdh = createECDH("prime256v1");
+  ecdh.generateKeys();
+  const publicKey = ecdh.getPublicKey("base64");
+  const nonce = randomBytes(16).toString("hex");
+  const approval = {
+    ecdh,
+    otherPublicKey: request.publicKey,
+    commitment: getPairingCommitment(nonce, request.publicKey, publicKey),
+    nonce,
+    time: Date.now(),
+  };
+  pairingApprovals.set(host, approval);
+  setTimeout(() => pairingApprovals.delete(host), pairingMaxAgeMs);
+  writePairingMessage(getPairingAnswerPath(hostname, host), {
+    publicKey,
+    commitment: approval.commitment,
+    time: approval.time,
+  });
+};
+
+// One pairing dialog is shown at a time, the requests arriving meanwhile must
+// be made again
+const handlePairingNonce = async (host: string, message: PairingMessage) => {
+  const approval = pairingApprovals.get(host);
+  if (
+    !approval ||
+    approval.otherNonce ||
+    typeof message.nonce !== "string" ||
+    !isPairingMessageFresh(message) ||
+    Date.now() - approval.time > pairingMaxAgeMs
+  ) {
+    return;
+  }
+  if (pairingDialogOpen) {
+    console.log(`Ignoring pairing request from ${host}, as another is open`);
+    return;
+  }
+  approval.otherNonce = message.nonce;
+  const publicKey = approval.ecdh.getPublicKey("base64");
+  const answerPath = getPairingAnswerPath(hostname, host);
+  const answer: PairingMessage = {
+    publicKey,
+    commitment: approval.commitment,
+    nonce: approval.nonce,
+    time: approval.time,
+  };
+  writePairingMessage(answerPath, answer);
+  pairingDialogOpen = true;
+  try {
+    const { code, key } = getPairingSecrets(
+      approval.ecdh,
+      approval.otherPublicKey,
+      publicKey,
+      approval.otherNonce,
+      approval.nonce
+    );
+    const { response } = await dialog.showMessageBox({
+      type: "question",
+      title: "Pairing request",
+      message: `${host} asks to be paired`,
+      detail: `Approve only if ${host} shows the code ${code}. Once paired, it can send commands to your computers.`,
+      buttons: ["Approve", "Reject"],
+      defaultId: 1,
+      cancelId: 1,
+    });
+    if (response !== 0 || Date.now() - approval.time > pairingMaxAgeMs) {
+      return;
+    }
+    answer.encryptedKey = encryptBuffer(controlKey, key).toString("base64");
+    writePairingMessage(answerPath, answer);
+    console.log(`Approved pairing with ${host}`);
+  } catch (error) {
+    console.error(`Error answering pairing request from ${host}: ${error}`);
+  } finally {
+    pairingDialogOpen = false;
+  }
+};
+
+// The nonce is only sent once the answer commits to its own, and the public
+// key and the commitment first seen are kept
+const handlePairingAnswer = async (host: string, answer: PairingMessage) => {
+  const request = pairingRequest;
+  if (!request || Date.now() - request.time > pairingMaxAgeMs) {
+    return;
+  }
+  const publicKey = request.ecdh.getPublicKey("base64");
+  const exchange = request.exchanges.get(host);
+  if (!exchange) {
+    if (
+      typeof answer.publicKey !== "string" ||
+      typeof answer.commitment !== "string" ||
+      request.exchanges.size >= maxPendingPairings
+    ) {
+      return;
+    }
+    const nonce = randomBytes(16).toString("hex");
+    request.exchanges.set(host, {
+      otherPublicKey: answer.publicKey,
+      commitment: answer.commitment,
+      nonce,
+      time: Date.now(),
+    });
+    writePairingMessage(getPairingNoncePath(hostname, host), {
+      nonce,
+      time: Date.now(),
+    });
+    return;
+  }
+  if (
+    typeof answer.nonce !== "string" ||
+    answer.publicKey !== exchange.otherPublicKey ||
+    answer.commitment !== exchange.commitment
+  ) {
+    return;
+  }
+  if (
+    getPairingCommitment(answer.nonce, publicKey, exchange.otherPublicKey) !==
+    exchange.commitment
+  ) {
+    console.error(`Pairing answer from ${host} does not match its commitment`);
+    return;
+  }
+  try {
+    const { code, key } = getPairingSecrets(
+      request.ecdh,
+      publicKey,
+      exchange.otherPublicKey,
+      exchange.nonce,
+      answer.nonce
+    );
+    if (!answer.encryptedKey) {
+      new Notification({
+        title: `Pairing with ${host}`,
+        body: `Approve it on ${host} if it shows the code ${code}`,
+        icon: getAppIcon(),
+      }).show();
+      return;
+    }
+    const newControlKey = decryptBuffer(
+      Buffer.from(answer.encryptedKey, "base64"),
+      key
+    );
+    pairingRequest = null;
+    const { response } = await dialog.showMessageBox({
+      type: "question",
+      title: "Pairing approved",
+      message: `${host} approved the pairing`,
+      detail: `Trust it only if ${host} showed the code ${code}.`,
+      buttons: ["Trust", "Reject"],
+      defaultId: 1,
+      cancelId: 1,
+    });
+    if (response !== 0) {
+      return;
+    }
+    [getPairingRequestPath(hostname), getPairingNoncePath(hostname, host)]
+      .filter((file) => fs.existsSync(file))
+      .forEach((file) => fs.unlinkSync(file));
+    if (!controlKey || !controlKey.equals(newControlKey)) {
+      setControlKey(newControlKey);
+    }
+    console.log(`Paired with ${host}`);
+  } catch (error) {
+    console.error(`Error handling pairing answer from ${host}: ${error}`);
+  }
+};
+
+const handlePairingFile = (file: string) => {
+  if (!fs.existsSync(file)) {
+    return;
+  }
+  const [host, kind, to] = path.basename(file, ".json").split(".");
+  if (host === hostname) {
+    return;
+  }
+  let message: PairingMessage;
+  try {
+    message = JSON.parse(fs.readFileSync(file, { encoding: "utf8" }));
+  } catch (error) {
+    console.error(`Error reading pairing file ${file}: ${error}`);
+    return;
+  }
+  if (!message || typeof message !== "object") {
+    return;
+  }
+  if (kind === "pairing-request") {
+    answerPairingRequest(host, message);
+  } else if (to === hostname && kind === "pairing-nonce") {
+    handlePairingNonce(host, message);
+  } else if (to === hostname) {
+    handlePairingAnswer(host, message);
+  }
+};
+
+const enterPairingCode = async () => {
+  const code = await askForText({
+    title: "Pairing code",
+    message:
+      "Enter the code shown by Remote control > Pair another computer... on your other computer",
+  });
+  if (!code) {
+    return;
+  }
+  const newControlKey = parsePairingCode(code);
+  if (!newControlKey) {
+    dialog.showErrorBox(
+      "Invalid pairing code",
+      "The pairing code was mistyped, check it and try again."
+    );
+    return;
+  }
+  if (
+    (controlKey && controlKey.equals(newControlKey)) ||
+    !(await confirmControlKeyChange())
+  ) {
+    return;
+  }
+  setControlKey(newControlKey);
+};
+
+const loadControlKeys = () => {
+  controlKey = unprotectControlKey(config.get("protectedControlKey"));
+  previousControlKey = null;
+  if (Date.now() < config.get("previousControlKeyExpiry", 0)) {
+    previousControlKey = unprotectControlKey(
+      config.get("protectedPreviousControlKey")
+    );
+  } else if (config.get("protectedPreviousControlKey")) {
+    config.delete("protectedPreviousControlKey");
+    config.delete("previousControlKeyExpiry");
+  }
+};
+
+// The key which signed the message, if it is still accepted. Messages from
+// older versions have no key ID, and are checked with the current key.
+const getControlKeyFor = (message: ControlMessage) => {
+  if (!message.keyId || message.keyId === getControlKeyId(controlKey)) {
+    return controlKey;
+  }
+  if (
+    previousControlKey &&
+    message.keyId === getControlKeyId(previousControlKey) &&
+    Date.now() < config.get("previousControlKeyExpiry", 0)
+  ) {
+    return previousControlKey;
+  }
+  return null;
+};
+
+const isControlMessageFile = (file: string) => {
+  return /^[0-9a-zA-Z-]+\.[0-9a-f]+\.command\.json$/.test(path.basename(file));
+};
+
+const sendControlMessage = (
+  to: string,
+  action: ControlAction,
+  args: Record<string, ProtobufValue> = {}
+) => {
+  if (!controlKey || !syncFolder || !folderAvailable) {
+    return;
+  }
+  const message: ControlMessage = {
+    id: randomBytes(8).toString("hex"),
+    from: hostname,
+    to,
+    action,
+    args,
+    time: Date.now(),
+    keyId: getControlKeyId(controlKey),
+    signature: null,
+  };
+  message.signature = getControlMessageSignature(message, controlKey);
+  try {
+    fs.writeFileSync(
+      path.join(syncFolder, `${hostname}.${message.id}.command.json`),
+      JSON.stringify(message, null, 2),
+      { encoding: "utf8" }
+    );
+    console.log(`Sent ${action} command to ${to === "*" ? "all" : to}`);
+  } catch (error) {
+    console.error(`Error sending ${action} command: ${error}`);
+    notifyError(
+      "Could not send command",
+      `Error writing to the folder: ${getErrorDescription(error)}`
+    );
+  }
+};
+
+const isValidControlMessage = (message: ControlMessage) => {
+  if (
+    !message ||
+    typeof message.id !== "string" ||
+    typeof message.signature !== "string" ||
+    message.from === hostname ||
+    (message.to !== hostname && message.to !== "*") ||
+    Math.abs(Date.now() - message.time) > controlMessageMaxAgeMs ||
+    handledControlMessages.has(message.id)
+  ) {
+    return false;
+  }
+  const key = getControlKeyFor(message);
+  if (!key) {
+    console.log(
+      `Ignoring command from ${message.from} signed with unknown key ${message.keyId}`
+    );
+    return false;
+  }
+  const expected = Buffer.from(getControlMessageSignature(message, key), "hex");
+  const actual = Buffer.from(message.signature, "hex");
+  return expected.length === actual.length && timingSafeEqual(expected, actual);
+};
+
+const handleControlMessageFile = (file: string) => {
+  if (!controlKey || !fs.existsSync(file)) {
+    return;
+  }
+  let message: ControlMessage;
+  try {
+    message = JSON.parse(fs.readFileSync(file, { encoding: "utf8" }));
+  } catch (error) {
+    console.error(`Error reading command from ${file}: ${error}`);
+    return;
+  }
+  if (!isValidControlMessage(message) || !isWrittenBy(file, message.from)) {
+    return;
+  }
+  rememberHandledMessage(handledControlMessages, message.id);
+
+  if (message.action === "status-reply") {
+    const args = message.args;
+    new Notification({
+      title: `Status of ${getDeviceName(message.from)}`,
+      body: [
+        `${args.state}, sending ${args.sending ? "on" : "off"}, receiving ${
+          args.receiving ? "on" : "off"
+        }`,
+        `Last sent: ${
+          args.lastSentTime
+            ? new Date(Number(args.lastSentTime)).toLocaleString()
+            : "never"
+        }`,
+        `Last received: ${
+          args.lastReceivedTime
+            ? new Date(Number(args.lastReceivedTime)).toLocaleString()
+            : "never"
+        }`,
+      ].join("\n"),
+      icon: getAppIcon(),
+    }).show();
+    return;
+  }
+
+  if (!config.get("remoteControl", false)) {
+    console.log(`Ignoring ${message.action} command from ${message.from}`);
+    return;
+  }
+  console.log(`Running ${message.action} command from ${message.from}`);
+  switch (message.action) {
+    case "pause":
+      skipCopies(Number(message.args.seconds) || 3600);
+      break;
+    case "cleanup":
+      cleanFiles();
+      pruneHistoryNow();
+      break;
+    case "status": {
+      const health = getHealth();
+      sendControlMessage(message.from, "status-reply", {
+        state: health.state,
+        sending: health.sending,
+        receiving: health.receiving,
+        lastSentTime: health.lastSentTime,
+        lastReceivedTime: health.lastReceivedTime,
+        errors: health.errors,
+      });
+      break;
+    }
+  }
+};
+
+// Checks whether a fullscreen app or a presentation is in the foreground, or
+// the screen is being shared. Run on the timer, as asking is slow on Windows.
+const checkPresenting = async () => {
+  if (
+    !config.get("quietWhilePresenting", true) &&
+    !config.get("suspendSendsWhilePresenting", false)
+  ) {
+    presenting = false;
+    return;
+  }
+  const [command, args, isPresentingOutput]: [
+    string,
+    string[],
+    (output: string) => boolean
+  ] =
+    process.platform === "win32"
+      ? [
+          "powershell.exe",
+          [
+            "-NoProfile",
+            "-Command",
+            "Add-Type -Namespace Shell -Name Api -MemberDefinition '[DllImport(\"shell32.dll\")] public static extern int SHQueryUserNotificationState(out int state);'; $state = 0; [void][Shell.Api]::SHQueryUserNotificationState([ref]$state); $state",
+          ],
+          // Busy with a fullscreen app, running a fullscreen game, or in
+          // presentation mode
+          (output) => ["2", "3", "4"].includes(output),
+        ]
+      : process.platform === "darwin"
+      ? [
+          "osascript",
+          [
+            "-e",
+            'tell application "System Events" to get value of attribute "AXFullScreen" of front window of (first process whose frontmost is true)',
+          ],
+          (output) => output === "true",
+        ]
+      : [
+          "sh",
+          [
+            "-c",
+            "xprop -id \"$(xprop -root _NET_ACTIVE_WINDOW | awk '{print $NF}')\" _NET_WM_STATE",
+          ],
+          (output) => output.includes("_NET_WM_STATE_FULLSCREEN"),
+        ];
+  const result = await runCommand(command, args);
+  const screenShareProcesses = config.get("screenShareProcesses", [
+    "CptHost",
+  ]);
+  const runningNames =
+    screenShareProcesses.length > 0
+      ? await getRunningProcessNames()
+      : new Set<string>();
+  const sharingProcess = screenShareProcesses.find((executable) =>
+    runningNames.has(executable.toLowerCase())
+  );
+  const newPresenting =
+    (result.status === 0 && isPresentingOutput(result.stdout.trim())) ||
+    !!sharingProcess;
+  if (newPresenting !== presenting) {
+    console.log(
+      newPresenting
+        ? `Presenting${
+            sharingProcess ? ` (${sharingProcess} is sharing the screen)` : ""
+          }, holding notifications`
+        : "Not presenting anymore"
+    );
+  }
+  presenting = newPresenting;
+};
+
+const isPresenting = () => {
+  return presenting;
+};
+
+// Notifications showing clipboard contents could leak over a shared screen
+const isQuiet = () => {
+  return config.get("quietWhilePresenting", true) && isPresenting();
+};
+
+// Lower-cased names of the executables running, with and without extension
+const getRunningProcessNames = async () => {
+  const names = new Set<string>();
+  const result =
+    process.platform === "win32"
+      ? await runCommand("tasklist", ["/fo", "csv", "/nh"], {
+          timeout: 10000,
+        })
+      : await runCommand("ps", ["-A", "-o", "comm="], { timeout: 10000 });
+  if (result.status !== 0) {
+    console.error(
+      `Error listing the running processes: ${result.stderr.trim()}`
+    );
+    return names;
+  }
+  result.stdout
+    .split(/\r?\n/)
+    .forEach((line) => {
+      const name = path
+        .basename(
+          process.platform === "win32"
+            ? line.split('","')[0].replace(/^"/, "")
+            : line.trim()
+        )
+        .toLowerCase();
+      if (name) {
+        names.add(name);
+        names.add(name.replace(/\.exe$/, ""));
+      }
+    });
+  return names;
+};
+
+// Why nothing is sent nor received right now, if so
+const getHoldReason = () => {
+  if (paused) {
+    return "paused";
+  }
+  if (scheduledOff) {
+    return "scheduled off";
+  }
+  if (suspendingProcess) {
+    return `${suspendingProcess} is running`;
+  }
+  return null;
+};
+
+const weekDays = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+const parseTimeOfDay = (time: string) => {
+  const match = /^([01]?[0-9]|2[0-3]):([0-5][0-9])$/.exec(time || "");
+  return match ? parseInt(match[1]) * 60 + parseInt(match[2]) : null;
+};
+
+const isInSyncWindow = (syncWindow: SyncWindow, date: Date) => {
+  const from = parseTimeOfDay(syncWindow.from);
+  const to = parseTimeOfDay(syncWindow.to);
+  if (from === null || to === null) {
+    console.error(`Invalid sync schedule ${JSON.stringify(syncWindow)}`);
+    return true;
+  }
+  const days = (syncWindow.days || weekDays).map((day) =>
+    day.toLowerCase().substring(0, 3)
+  );
+  const day = date.getDay();
+  const minutes = date.getHours() * 60 + date.getMinutes();
+  if (from <= to) {
+    return days.includes(weekDays[day]) && minutes >= from && minutes < to;
+  }
+  // Ends on the next day
+  return (
+    (days.includes(weekDays[day]) && minutes >= from) ||
+    (days.includes(weekDays[(day + 6) % 7]) && minutes < to)
+  );
+};
+
+const checkSyncSchedule = () => {
+  const syncSchedule = config.get("syncSchedule", []);
+  const date = new Date();
+  const newScheduledOff =
+    syncSchedule.length > 0 &&
+    !syncSchedule.some((syncWindow) => isInSyncWindow(syncWindow, date));
+  if (newScheduledOff === scheduledOff) {
+    return;
+  }
+  scheduledOff = newScheduledOff;
+  console.log(
+    scheduledOff
+      ? "Suspending syncing outside of the schedule"
+      : "Resuming syncing as scheduled"
+  );
+  if (appIcon) {
+    appIcon.setImage(getIdleTrayIcon());
+  }
+  updateToolTip();
+  setContextMenu();
+};
+
+const checkSuspendingProcesses = async () => {
+  const executables = config.get("suspendWhileRunning", []);
+  let runningExecutable: string = null;
+  if (executables.length > 0) {
+    const runningNames = await getRunningProcessNames();
+    runningExecutable =
+      executables.find((executable) =>
+        runningNames.has(executable.toLowerCase())
+      ) || null;
+  }
+  if (runningExecutable === suspendingProcess) {
+    return;
+  }
+  if (runningExecutable) {
+    console.log(`Suspending syncing while ${runningExecutable} is running`);
+  } else {
+    console.log(`${suspendingProcess} exited, resuming syncing`);
+  }
+  suspendingProcess = runningExecutable;
+  if (appIcon) {
+    appIcon.setImage(getIdleTrayIcon());
+  }
+  updateToolTip();
+  setContextMenu();
+};
+
+// Runs every minute while initialized
+const onTimerTick = () => {
+  checkFolderAvailability();
+  checkSyncSchedule();
+  checkSuspendingProcesses();
+  checkPresenting();
+  sendKeepAliveProbe();
+  checkSequenceGaps();
+  checkDeliveries();
+  pruneHistoryNow();
+  updateFolderUsage();
+  writeHealthFile();
+  refreshDirectPeers();
+  refreshWindowsCloudClipboard();
+  // The presence of the other computers changes on its own
+  setContextMenu();
+};
+
+const initialize = () => {
+  syncFolder = config.get("folder");
+
+  if (!(typeof syncFolder === "string" || typeof syncFolder === "undefined")) {
+    return;
+  }
+
+  if (
+    !syncFolder ||
+    (fs.existsSync(syncFolder) && !fs.lstatSync(syncFolder).isDirectory())
+  ) {
+    askForFolder();
+  }
+
+  folderAvailable = true;
+  if (!fs.existsSync(syncFolder)) {
+    if (config.get("networkShareMode", false)) {
+      // Creating it would hide the share once it comes back
+      console.error(`Folder ${syncFolder} is not available, waiting for it...`);
+      folderAvailable = false;
+    } else {
+      fs.mkdirSync(syncFolder);
+    }
+  }
+
+  if (folderAvailable) {
+    applyRoamingSettings();
+  }
+  if (!controlKey) {
+    loadControlKeys();
+  }
+
+  loadPlugins();
+  registerChannelHotkeys();
+  registerHotkeys();
+  writePinnedFile();
+
+  writeSharedHistory(getHistoryEntries());
+  onTimerTick();
+  timerTask = cron.schedule("* * * * *", onTimerTick, {
+    scheduled: true,
+  });
+
+  if (config.get("send", true)) {
+    clipboardListener = simulatedClipboard || require("clipboard-event");
+    clipboardListener.startListening();
+    clipboardListener.on("change", handleClipboardChange);
+  }
+
+  if (folderAvailable) {
+    startFolderWatcher();
+  }
+
+  startGrpcServer();
+  startMetricsServer();
+  startTransports();
+  startWakeHints();
+  startNtfy();
+
+  if (config.get("autoCleanup", true)) {
+    // Remove files older than 5 minutes, on every 5 minutes by default
+    const cleanupIntervalMinutes = Math.min(
+      Math.max(1, Math.round(config.get("cleanupIntervalMinutes", 5))),
+      59
+    );
+    cleanFiles();
+    filesCleanerTask = cron.schedule(
+      `*/${cleanupIntervalMinutes} * * * *`,
+      () => cleanFiles(),
+      { scheduled: true }
+    );
+  }
+};
+
+const cleanup = () => {
+  writeHealthFile(false);
+
+  if (clipboardListener) {
+    clipboardListener.removeListener("change", handleClipboardChange);
+    clipboardListener.stopListening();
+    clipboardListener = null;
+  }
+
+  if (writeRetryWaiter) {
+    clearTimeout(writeRetryWaiter);
+    writeRetryWaiter = null;
+  }
+  writeFailures = 0;
+  writeError = null;
+
+  stopFolderWatcher();
+  stopGrpcServer();
+  stopMetricsServer();
+  stopTransports();
+  stopWakeHints();
+  stopNtfy();
+
+  if (folderEventsWaiter) {
+    clearTimeout(folderEventsWaiter);
+    folderEventsWaiter = null;
+  }
+  pendingMenuRefresh = false;
+  pendingClipboardFiles.clear();
+  pendingControlMessageFiles.clear();
+  pendingPairingFiles.clear();
+  pendingProbeFiles.clear();
+  pendingResendFiles.clear();
+  pendingIndexRefresh = false;
+  opaqueIndex = new Map();
+  opaqueIndexLoadedTime = null;
+
+  if (filesCleanerTask) {
+    filesCleanerTask.stop();
+    filesCleanerTask = null;
+  }
+
+  if (timerTask) {
+    timerTask.stop();
+    timerTask = null;
+  }
+
+  unloadPlugins();
+  globalShortcut.unregisterAll();
+};
+
+const reload = () => {
+  console.log("Reloading configuration...");
+  cleanup();
+  initialize();
+  setContextMenu();
+};
+
+// Started from the menu, to recover from odd states without restarting
+const reloadPress = () => {
+  if (appIcon) {
+    appIcon.setToolTip(`${app.name} v${app.getVersion()}\nReloading...`);
+  }
+  // Lets the tray show the tooltip before the reload blocks
+  setTimeout(() => {
+    try {
+      reload();
+      console.log("Reloaded");
+    } catch (error) {
+      console.error(`Error reloading: ${error}`);
+      notifyError("Could not reload", getErrorDescription(error));
+    }
+    updateToolTip();
+  }, 100);
+};
+
+const finish = (exitCode: number = 0) => {
+  cleanOwnFiles();
+  cleanup();
+  app.exit(exitCode);
+};
+
+const getAppIcon = () => {
+  const iconExtension =
+    process.platform === "win32"
+      ? "ico"
+      : process.platform === "darwin"
+      ? "icns"
+      : "png";
+
+  return path.resolve(
+    __dirname,
+    `../assets/appicons/${iconExtension}/icon.${iconExtension}`
+  );
+};
+
+const getTrayIcon = (icon: ClipboardIcon) => {
+  const iconExtension = process.platform === "win32" ? "ico" : "png";
+
+  return path.resolve(
+    __dirname,
+    `../assets/trayicons/${iconExtension}/${icon}.${iconExtension}`
+  );
+};
+
+// The regular icon, faded while paused or suspended
+const getIdleTrayIcon = () => {
+  if (!getHoldReason()) {
+    return getTrayIcon("clipboard");
+  }
+  const image = nativeImage.createFromPath(getTrayIcon("clipboard"));
+  const bitmap = image.toBitmap();
+  // The pixels are premultiplied, so halving every channel halves the opacity
+  for (let i = 0; i < bitmap.length; i++) {
+    bitmap[i] = bitmap[i] >> 1;
+  }
+  return nativeImage.createFromBitmap(bitmap, image.getSize());
+};
+
+const updateToolTip = () => {
+  if (!appIcon) {
+    return;
+  }
+  let toolTip = `${app.name} v${app.getVersion()}${
+    profile ? ` (${profile})` : ""
+  }`;
+  if (!folderAvailable) {
+    toolTip += "\nFolder is not available";
+  }
+  if (paused) {
+    toolTip += "\nPaused";
+  }
+  if (scheduledOff) {
+    toolTip += "\nScheduled off";
+  }
+  if (suspendingProcess) {
+    toolTip += `\nSuspended while ${suspendingProcess} is running`;
+  }
+  if (folderUsageInMb !== null) {
+    toolTip += `\nFolder usage: ${folderUsageInMb.toFixed(1)}MB`;
+  }
+  toolTip += `\nWritten today: ${(
+    getBytesWrittenToday() /
+    (1024 * 1024)
+  ).toFixed(1)}MB`;
+  if (writeError) {
+    toolTip += `\nSending is failing: ${writeError}`;
+  }
+  appIcon.setToolTip(toolTip);
+};
+
+const setIconFor5Seconds = (icon: ClipboardIcon) => {
+  if (!appIcon) {
+    return;
+  }
+  appIcon.setImage(getTrayIcon(icon));
+
+  if (iconWaiter) {
+    clearTimeout(iconWaiter);
+  }
+  iconWaiter = setTimeout(() => {
+    appIcon.setImage(getIdleTrayIcon());
+  }, 5000);
+};
+
+// Only macOS shows a title next to the tray icon, which is easier to notice
+// than the icon flashing on a crowded menu bar
+const setMenuBarTitle = (
+  direction: ClipboardDirection,
+  peer: string,
+  size: string
+) => {
+  if (!appIcon || process.platform !== "darwin") {
+    return;
+  }
+  const menuBarTitle = config.get("menuBarTitle", "off");
+  const arrow = direction === "send" ? "→" : "←";
+  appIcon.setTitle(
+    menuBarTitle === "peer"
+      ? `${arrow}${direction === "send" ? "" : peer}`
+      : menuBarTitle === "size"
+      ? `${arrow}${size}`
+      : ""
+  );
+};
+
+const handleSendCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("send", checkBox.checked);
+  reload();
+};
+
+const handleReceiveCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("receive", checkBox.checked);
+  reload();
+};
+
+const handleCleanupCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("autoCleanup", checkBox.checked);
+  reload();
+};
+
+const handleNetworkShareCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("networkShareMode", checkBox.checked);
+  reload();
+};
+
+// Adopts the shared settings if there are some already, otherwise shares ours
+const handleRoamSettingsCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("roamSettings", checkBox.checked);
+  if (checkBox.checked && !readRoamingSettings()) {
+    writeRoamingSettings();
+  }
+  reload();
+};
+
+const handlePluginCheckBox = (
+  checkBox: Electron.MenuItem,
+  pluginName: string
+) => {
+  config.set("plugins", {
+    ...config.get("plugins", {}),
+    [pluginName]: checkBox.checked,
+  });
+  reload();
+};
+
+const getPluginsSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const enabledPlugins = config.get("plugins", {});
+  const pluginItems = getAvailablePlugins().map(
+    (name): Electron.MenuItemConstructorOptions => ({
+      label: name,
+      type: "checkbox",
+      checked: enabledPlugins[name] === true,
+      click: (checkBox: Electron.MenuItem) =>
+        handlePluginCheckBox(checkBox, name),
+    })
+  );
+  return [
+    ...pluginItems,
+    ...(pluginItems.length > 0
+      ? [{ type: "separator" } as Electron.MenuItemConstructorOptions]
+      : []),
+    {
+      label: "Open plugins folder",
+      type: "normal",
+      click: () => {
+        fs.mkdirSync(getPluginsFolder(), { recursive: true });
+        shell.openPath(getPluginsFolder());
+        setContextMenu();
+      },
+    },
+  ];
+};
+
+const getRemoteControlTargetSubmenu = (
+  to: string
+): Electron.MenuItemConstructorOptions[] => {
+  return [
+    {
+      label: "Pause sending for 1 hour",
+      type: "normal",
+      click: () => sendControlMessage(to, "pause", { seconds: 3600 }),
+    },
+    {
+      label: "Clean up now",
+      type: "normal",
+      click: () => sendControlMessage(to, "cleanup"),
+    },
+    {
+      label: "Send me your status",
+      type: "normal",
+      click: () => sendControlMessage(to, "status"),
+    },
+  ];
+};
+
+const getRemoteControlSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const targets = controlKey && folderAvailable ? [...getPeersInFolder()] : [];
+  return [
+    ...(controlKey && !safeStorage.isEncryptionAvailable()
+      ? [
+          {
+            label: "Key not saved, set it again after restarting",
+            type: "normal",
+            enabled: false,
+          } as Electron.MenuItemConstructorOptions,
+        ]
+      : []),
+    {
+      label: "Accept commands from my other computers",
+      type: "checkbox",
+      checked: config.get("remoteControl", false),
+      enabled: controlKey !== null,
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("remoteControl", checkBox.checked);
+        setContextMenu();
+      },
+    },
+    {
+      label: controlKey ? "Change passphrase..." : "Set passphrase...",
+      type: "normal",
+      click: setControlPassphrase,
+    },
+    {
+      label: "Pair another computer...",
+      type: "normal",
+      click: showPairingCode,
+    },
+    {
+      label: "Enter pairing code...",
+      type: "normal",
+      click: enterPairingCode,
+    },
+    {
+      label: "Hide file names",
+      type: "checkbox",
+      checked: config.get("opaqueFilenames", false),
+      enabled: controlKey !== null,
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("opaqueFilenames", checkBox.checked);
+        setContextMenu();
+      },
+      toolTip:
+        "Write the clipboard files under random names, listed in an index encrypted with the key shared by your computers",
+    },
+    {
+      label: "Zero-knowledge mode",
+      type: "checkbox",
+      checked: config.get("zeroKnowledgeMode", false),
+      enabled: controlKey !== null,
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("zeroKnowledgeMode", checkBox.checked);
+        reload();
+      },
+      toolTip:
+        "Also encrypt and pad the clipboards, and stop sharing anything naming this computer. Files are not sent.",
+    },
+    {
+      label: "Direct transfers over Tailscale/WireGuard",
+      type: "checkbox",
+      checked: config.get("directTransport", false),
+      enabled: controlKey !== null,
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("directTransport", checkBox.checked);
+        reload();
+      },
+      toolTip:
+        "Also send text and images straight to the peers reachable through the tunnel, falling back to the folder",
+    },
+    ...(config.get("relayUrl") && controlKey
+      ? [
+          {
+            label: relayConnection
+              ? "Connected to the relay"
+              : "Not connected to the relay",
+            type: "normal",
+            enabled: false,
+          } as Electron.MenuItemConstructorOptions,
+        ]
+      : []),
+    {
+      label: "Request pairing",
+      type: "normal",
+      enabled: folderAvailable,
+      click: requestPairing,
+      toolTip:
+        "Ask your other computers for the key, confirming a code shown on both",
+    },
+    ...(targets.length > 0
+      ? [
+          { type: "separator" } as Electron.MenuItemConstructorOptions,
+          {
+            label: "All computers",
+            type: "submenu",
+            submenu: getRemoteControlTargetSubmenu("*"),
+          } as Electron.MenuItemConstructorOptions,
+          ...targets.sort().map(
+            (target): Electron.MenuItemConstructorOptions => ({
+              label: target,
+              type: "submenu",
+              submenu: getRemoteControlTargetSubmenu(target),
+            })
+          ),
+        ]
+      : []),
+  ];
+};
+
+const getTransportsSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const transportItems = clipTransports.map(
+    (transport): Electron.MenuItemConstructorOptions => ({
+      label: `${transport.name}: ${
+        transport.isAvailable() ? "available" : "not available"
+      }`,
+      type: "normal",
+      enabled: false,
+    })
+  );
+  const peerItems = [...peerPaths.keys()]
+    .sort()
+    .map((peer) => ({ peer, fastestPath: getFastestPeerPath(peer) }))
+    .filter(({ fastestPath }) => fastestPath)
+    .map(
+      ({ peer, fastestPath }): Electron.MenuItemConstructorOptions => ({
+        label: `${getDeviceName(peer)}: fastest through ${fastestPath.via} (${fastestPath.roundTrip}ms round trip)`,
+        type: "normal",
+        enabled: false,
+      })
+    );
+  return [
+    { label: "folder: always used", type: "normal", enabled: false },
+    ...transportItems,
+    ...(peerItems.length > 0
+      ? [{ type: "separator" } as Electron.MenuItemConstructorOptions]
+      : []),
+    ...peerItems,
+  ];
+};
+
+// When each computer was last seen, from the files it wrote to the folder
+// and the keep-alive probes it answered
+const getDevicesSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const peers = new Set([
+    ...peerRoundTrips.keys(),
+    ...peersLastSeenTime.keys(),
+  ]);
+  const keepAlive = config.get("peerKeepAlive", false);
+  const currentTime = Date.now();
+  const staleAfterMs = Math.max(5 * 60000, 3 * getKeepAliveIntervalMs());
+  const peerItems = [...peers].sort().map(
+    (peer): Electron.MenuItemConstructorOptions => {
+      const linkStats = keepAlive ? getPeerLinkStats(peer) : null;
+      const lastSeenTime = peersLastSeenTime.get(peer);
+      const presence = !lastSeenTime
+        ? "not seen"
+        : currentTime - lastSeenTime < staleAfterMs
+        ? "online"
+        : `last seen ${
+            new Date(lastSeenTime).toDateString() ===
+            new Date(currentTime).toDateString()
+              ? new Date(lastSeenTime).toLocaleTimeString()
+              : new Date(lastSeenTime).toLocaleString()
+          }`;
+      return {
+        label: `${getDeviceName(peer)}: ${presence}${
+          linkStats
+            ? `, ~${Math.max(
+                1,
+                Math.round(linkStats.latency / 1000)
+              )} s, ${Math.round(linkStats.reliability * 100)}%`
+            : keepAlive
+            ? ", no answer"
+            : ""
+        }`,
+        type: "normal",
+        enabled: false,
+      };
+    }
+  );
+  return [
+    ...(peerItems.length > 0
+      ? peerItems
+      : [
+          {
+            label: "No other computers seen",
+            type: "normal",
+            enabled: false,
+          } as Electron.MenuItemConstructorOptions,
+        ]),
+    { type: "separator" },
+    {
+      label: "Track latency and reliability",
+      type: "checkbox",
+      checked: keepAlive,
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("peerKeepAlive", checkBox.checked);
+        setContextMenu();
+      },
+      toolTip:
+        "Write a small probe to the folder every minute and track how long each computer takes to answer it",
+    },
+  ];
+};
+
+// Recreates only the clipboard watcher, e.g. after another clipboard tool
+// broke the clipboard chain on Windows
+const reconnectClipboardWatcher = () => {
+  if (!clipboardListener) {
+    console.log("Not reconnecting the clipboard watcher, sending is disabled");
+    return;
+  }
+  console.log("Reconnecting the clipboard watcher...");
+  clipboardListener.removeListener("change", handleClipboardChange);
+  try {
+    clipboardListener.stopListening();
+  } catch (error) {
+    console.error(`Error stopping the clipboard watcher: ${error}`);
+  }
+  if (writeRetryWaiter) {
+    clearTimeout(writeRetryWaiter);
+    writeRetryWaiter = null;
+  }
+  lastTimeChecked = null;
+  try {
+    clipboardListener.startListening();
+    clipboardListener.on("change", handleClipboardChange);
+  } catch (error) {
+    console.error(`Error starting the clipboard watcher: ${error}`);
+    notifyError(
+      "Could not reconnect the clipboard watcher",
+      getErrorDescription(error)
+    );
+    return;
+  }
+  const child = clipboardListener.child;
+  if (child && (!child.pid || child.exitCode !== null)) {
+    console.error("The clipboard watcher did not start");
+    notifyError(
+      "Could not reconnect the clipboard watcher",
+      "Its helper process did not start"
+    );
+    return;
+  }
+  console.log(
+    `Clipboard watcher re-registered${child ? ` with PID ${child.pid}` : ""}`
+  );
+};
+
+const getTroubleshootingSubmenu =
+  (): Electron.MenuItemConstructorOptions[] => {
+    return [
+      {
+        label: "Deep clean",
+        type: "normal",
+        click: deepClean,
+        toolTip:
+          "Remove the old files, leftovers and stale locks from the folder now, and show what was removed",
+      },
+      {
+        label: "Copy folder path",
+        type: "normal",
+        enabled: !!syncFolder,
+        click: () => copyWithoutSending(syncFolder),
+        toolTip: "Copy the path of the folder, without sending it",
+      },
+      {
+        label: "Copy settings file path",
+        type: "normal",
+        click: () => copyWithoutSending(config.path),
+        toolTip: "Copy the path of the settings file, without sending it",
+      },
+      {
+        label: "Copy status summary",
+        type: "normal",
+        click: () => copyWithoutSending(getStatusSummary()),
+        toolTip:
+          "Copy a line telling the version, the folder and what is being synced, to paste when asking for help, without sending it",
+      },
+      { type: "separator" },
+      {
+        label: "Reconnect clipboard watcher",
+        type: "normal",
+        enabled: !!clipboardListener,
+        click: reconnectClipboardWatcher,
+        toolTip:
+          "Restart watching the clipboard, when copies stopped being sent because of another clipboard tool",
+      },
+      {
+        label: "Measure sync latency",
+        type: "normal",
+        click: measureSyncLatency,
+        toolTip:
+          "Write a probe to the folder and show how long each computer takes to answer it",
+      },
+      {
+        label: "Trace clipboard formats",
+        type: "checkbox",
+        checked: config.get("traceClipboardFormats", false),
+        click: (checkBox: Electron.MenuItem) => {
+          config.set("traceClipboardFormats", checkBox.checked);
+          setContextMenu();
+        },
+        toolTip:
+          "Log the formats and sizes of the clipboards skipped for having an unknown format, to report them",
+      },
+    ];
+  };
+
+// The computers which answered a keep-alive probe recently, or wrote to the
+// folder in the last minutes
+const getOnlinePeers = () => {
+  const onlinePeers = getPeersInFolder();
+  const currentTime = Date.now();
+  peerRoundTrips.forEach((roundTrips, peer) => {
+    if (
+      [...roundTrips.keys()].some(
+        (id) =>
+          currentTime - keepAliveProbes.get(id) < 3 * getKeepAliveIntervalMs()
+      )
+    ) {
+      onlinePeers.add(peer);
+    }
+  });
+  return onlinePeers;
+};
+
+const getActivitySummary = (direction: ClipboardDirection) => {
+  const entry = activityLog.find(
+    (activityEntry) =>
+      activityEntry.direction === direction &&
+      (activityEntry.outcome === "sent" || activityEntry.outcome === "applied")
+  );
+  if (!entry) {
+    return "never";
   }
+  return `${entry.type} at ${new Date(entry.time).toLocaleTimeString()}${
+    direction === "receive" ? ` from ${getDeviceName(entry.hostname)}` : ""
+  }`;
 };
 
-const initialize = () => {
-  syncFolder = config.get("folder");
-
-  if (!(typeof syncFolder === "string" || typeof syncFolder === "undefined")) {
-    return;
+// Why clipboards are not being synced right now, if they aren't
+const getSuspensionReason = () => {
+  if (!folderAvailable) {
+    return "Folder is not available";
   }
-
-  if (
-    !syncFolder ||
-    (fs.existsSync(syncFolder) && !fs.lstatSync(syncFolder).isDirectory())
-  ) {
-    askForFolder();
+  if (!config.get("send", true) && !config.get("receive", true)) {
+    return "Sending and receiving are disabled";
   }
-
-  if (!fs.existsSync(syncFolder)) {
-    fs.mkdirSync(syncFolder);
+  if (paused) {
+    return "Paused";
   }
-
-  if (config.get("send", true)) {
-    clipboardListener = require("clipboard-event");
-    clipboardListener.startListening();
-    clipboardListener.on("change", writeClipboardToFile);
+  if (scheduledOff) {
+    return "Scheduled off";
   }
-
-  if (config.get("receive", true)) {
-    // Watches for files and reads clipboard from it
-    clipboardFilesWatcher = chokidar
-      .watch(syncFolder, {
-        ignoreInitial: true,
-        disableGlobbing: true,
-      })
-      .on("add", readClipboardFromFile);
+  if (suspendingProcess) {
+    return `Suspended while ${suspendingProcess} is running`;
   }
-
-  if (config.get("autoCleanup", true)) {
-    // Remove files older than 5 minutes
-    cleanFiles();
-    filesCleanerTask = cron.schedule("*/5 * * * *", cleanFiles, {
-      scheduled: true,
-    });
+  if (config.get("suspendSendsWhilePresenting", false) && presenting) {
+    return "Not sending while presenting";
+  }
+  if (isSkippingCopies()) {
+    return skipNextCopy
+      ? "Skipping the next copy"
+      : `Not sending until ${new Date(skipCopiesUntil).toLocaleTimeString()}`;
   }
+  return null;
 };
 
-const cleanup = () => {
-  if (clipboardListener) {
-    clipboardListener.stopListening();
-    clipboardListener = null;
-  }
+// A single line to paste in a chat when helping someone
+const getStatusSummary = () => {
+  const suspensionReason = getSuspensionReason();
+  return [
+    `${app.name} v${app.getVersion()} on ${hostname} (${process.platform})`,
+    `folder ${syncFolder}${folderAvailable ? "" : " (not available)"}`,
+    `sending ${config.get("send", true) ? "on" : "off"}`,
+    `receiving ${config.get("receive", true) ? "on" : "off"}`,
+    `${getOnlinePeers().size} other computers online`,
+    `last sent: ${getActivitySummary("send")}`,
+    `last received: ${getActivitySummary("receive")}`,
+    ...(suspensionReason ? [`paused: ${suspensionReason}`] : []),
+    ...(writeError ? [`sending is failing: ${writeError}`] : []),
+  ].join(", ");
+};
 
-  if (clipboardFilesWatcher) {
-    clipboardFilesWatcher.close();
-    clipboardFilesWatcher = null;
-  }
+// Copies something about this computer, which is not sent to the others
+const copyWithoutSending = (text: string) => {
+  lastTextRead = text;
+  lastTimeRead = Date.now();
+  clipboard.writeText(text);
+};
 
-  if (filesCleanerTask) {
-    filesCleanerTask.stop();
-    filesCleanerTask = null;
+// Refreshed whenever the menu is about to open
+const getStatusItems = (): Electron.MenuItemConstructorOptions[] => {
+  if (!syncFolder) {
+    return [];
   }
+  const onlinePeersCount = getOnlinePeers().size;
+  const suspensionReason = getSuspensionReason();
+  const labels = [
+    `Folder: ${path.basename(syncFolder)}`,
+    `${onlinePeersCount} other computer${
+      onlinePeersCount === 1 ? "" : "s"
+    } online`,
+    `Last sent: ${getActivitySummary("send")}${
+      getDeliveryCount() > 0
+        ? `, delivered to ${getDeliveryCount()}`
+        : ""
+    }`,
+    `Last received: ${getActivitySummary("receive")}`,
+    ...(suspensionReason ? [`⏸ ${suspensionReason}`] : []),
+    ...[...incompatiblePeers.entries()].map(
+      ([peer, peerFormatVersion]) =>
+        `⚠ Update ${
+          peerFormatVersion < folderFormatVersion
+            ? getDeviceName(peer)
+            : "this computer"
+        } to sync with ${getDeviceName(peer)}`
+    ),
+  ];
+  return [
+    ...labels.map(
+      (label): Electron.MenuItemConstructorOptions => ({
+        label,
+        type: "normal",
+        enabled: false,
+      })
+    ),
+    { type: "separator" },
+  ];
 };
 
-const reload = () => {
-  console.log("Reloading configuration...");
-  cleanup();
-  initialize();
+const getConflictSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const policy = config.get("conflictPolicy", "latest");
+  const preferredHost = config.get("conflictPreferredHost");
+  const hosts = new Set([hostname, ...getPeersInFolder()]);
+  if (preferredHost) {
+    hosts.add(preferredHost);
+  }
+  const setPolicy = (newPolicy: ConflictPolicy, newPreferredHost?: string) => {
+    config.set("conflictPolicy", newPolicy);
+    if (newPreferredHost) {
+      config.set("conflictPreferredHost", newPreferredHost);
+    }
+    setContextMenu();
+  };
+  return [
+    {
+      label: "Keep the latest",
+      type: "radio",
+      checked: policy === "latest",
+      click: () => setPolicy("latest"),
+    },
+    {
+      label: "Keep the one copied here",
+      type: "radio",
+      checked: policy === "local",
+      click: () => setPolicy("local"),
+    },
+    ...[...hosts].sort().map(
+      (host): Electron.MenuItemConstructorOptions => ({
+        label: `Prefer ${host}`,
+        type: "radio",
+        checked: policy === "host" && preferredHost === host,
+        click: () => setPolicy("host", host),
+      })
+    ),
+    { type: "separator" },
+    {
+      label: "Notify to choose the other one",
+      type: "checkbox",
+      checked: config.get("conflictNotifications", false),
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("conflictNotifications", checkBox.checked);
+        setContextMenu();
+      },
+    },
+  ];
 };
 
-const finish = (exitCode: number = 0) => {
-  cleanup();
-  app.exit(exitCode);
+const getChannelSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const currentChannel = getCurrentChannel();
+  const channels = getChannels();
+  const otherChannels = channels.filter(
+    (channel) => channel !== currentChannel
+  );
+  return [
+    ...channels.map(
+      (channel): Electron.MenuItemConstructorOptions => ({
+        label: channel,
+        type: "radio",
+        checked: channel === currentChannel,
+        click: () => setCurrentChannel(channel),
+      })
+    ),
+    ...(otherChannels.length > 0
+      ? [{ type: "separator" } as Electron.MenuItemConstructorOptions]
+      : []),
+    ...otherChannels.map(
+      (channel): Electron.MenuItemConstructorOptions => ({
+        label: `Get latest from ${channel}`,
+        type: "normal",
+        click: () => readLatestClipboardFromChannel(channel),
+      })
+    ),
+  ];
 };
 
-const getAppIcon = () => {
-  const iconExtension =
-    process.platform === "win32"
-      ? "ico"
-      : process.platform === "darwin"
-      ? "icns"
-      : "png";
+const getSendTargetSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const sendTarget = getSendTarget();
+  const peers = new Set([...getPeersInFolder(), ...peerRoundTrips.keys()]);
+  if (sendTarget) {
+    peers.add(sendTarget);
+  }
+  const setSendTarget = (target: string) => {
+    if (target) {
+      config.set("sendTarget", target);
+      console.log(`Sending the clipboards only to ${getDeviceName(target)}`);
+    } else {
+      config.delete("sendTarget");
+      console.log("Sending the clipboards to all computers");
+    }
+    setContextMenu();
+  };
+  return [
+    {
+      label: "All computers",
+      type: "radio",
+      checked: !sendTarget,
+      click: () => setSendTarget(undefined),
+    },
+    ...[...peers].sort().map(
+      (peer): Electron.MenuItemConstructorOptions => ({
+        label: getDeviceName(peer),
+        type: "radio",
+        checked: peer === sendTarget,
+        click: () => setSendTarget(peer),
+      })
+    ),
+  ];
+};
 
-  return path.resolve(
-    __dirname,
-    `../assets/appicons/${iconExtension}/icon.${iconExtension}`
-  );
+const getClipboardSummary = (text: string) => {
+  const summary = text.replace(/\s+/g, " ").trim();
+  return summary.length > 40 ? `${summary.slice(0, 40)}…` : summary;
 };
 
-const getTrayIcon = (icon: ClipboardIcon) => {
-  const iconExtension = process.platform === "win32" ? "ico" : "png";
+const getHistoryEntryLabel = (entry: HistoryEntry) => {
+  return entry.type === "text"
+    ? getClipboardSummary(entry.text)
+    : `Image from ${new Date(entry.time).toLocaleTimeString()}`;
+};
 
-  return path.resolve(
-    __dirname,
-    `../assets/trayicons/${iconExtension}/${icon}.${iconExtension}`
-  );
+// Turning it off also removes the history kept, which is not updated anymore
+const handleKeepHistoryCheckBox = async (checkBox: Electron.MenuItem) => {
+  config.set("keepHistory", checkBox.checked);
+  if (checkBox.checked) {
+    await openHistory();
+  } else if (history) {
+    setHistoryEntries(pruneHistory([]));
+    history = null;
+    historyThumbnails.clear();
+  }
+  setContextMenu();
 };
 
-const setIconFor5Seconds = (icon: ClipboardIcon) => {
-  appIcon.setImage(getTrayIcon(icon));
+const getKeepHistoryCheckBox = (): Electron.MenuItemConstructorOptions => ({
+  label: "Keep history",
+  type: "checkbox",
+  checked: config.get("keepHistory", false),
+  click: handleKeepHistoryCheckBox,
+  toolTip:
+    "Keep the latest clipboards sent and received on this computer, so they can be copied again",
+});
 
-  if (iconWaiter) {
-    clearTimeout(iconWaiter);
-  }
-  iconWaiter = setTimeout(() => {
-    appIcon.setImage(getTrayIcon("clipboard"));
-  }, 5000);
+const handleSyncHistoryCheckBox = (checkBox: Electron.MenuItem) => {
+  config.set("syncHistory", checkBox.checked);
+  writeSharedHistory(getHistoryEntries());
+  setContextMenu();
 };
 
-const handleSendCheckBox = (checkBox: Electron.MenuItem) => {
-  config.set("send", checkBox.checked);
-  reload();
+const getSyncHistoryCheckBox = (): Electron.MenuItemConstructorOptions => ({
+  label: "Share with other computers",
+  type: "checkbox",
+  checked: config.get("syncHistory", false),
+  click: handleSyncHistoryCheckBox,
+  toolTip:
+    "Share the history through the folder, so it can be restored from any computer sharing it too",
+});
+
+const getEncryptHistoryCheckBox = (): Electron.MenuItemConstructorOptions => ({
+  label: "Encrypt with passphrase",
+  type: "checkbox",
+  checked: config.get("encryptHistory", false),
+  click: handleEncryptHistoryPress,
+  toolTip:
+    "Encrypt the history stored on this computer with a key derived from a passphrase",
+});
+
+const getOthersHistorySubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  return Object.entries(readOthersSharedHistory()).map(
+    ([historyHostname, entries]): Electron.MenuItemConstructorOptions => ({
+      label: `From ${historyHostname}`,
+      type: "submenu",
+      submenu:
+        entries.length > 0
+          ? entries.map(
+              (entry): Electron.MenuItemConstructorOptions => ({
+                label: getHistoryEntryLabel(entry),
+                type: "normal",
+                click: () => copyHistoryEntry(entry, historyHostname),
+              })
+            )
+          : [{ label: "No clipboards yet", type: "normal", enabled: false }],
+    })
+  );
 };
 
-const handleReceiveCheckBox = (checkBox: Electron.MenuItem) => {
-  config.set("receive", checkBox.checked);
-  reload();
+const getHistorySubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  if (!config.get("keepHistory", false)) {
+    return [...getOthersHistorySubmenu(), getKeepHistoryCheckBox()];
+  }
+  if (!history) {
+    return [
+      getKeepHistoryCheckBox(),
+      {
+        label: "Unlock history",
+        type: "normal",
+        click: async () => {
+          await openHistory();
+          setContextMenu();
+        },
+      },
+    ];
+  }
+  const entries = getHistoryEntries();
+  const othersHistorySubmenu = getOthersHistorySubmenu();
+  const searchItem: Electron.MenuItemConstructorOptions = {
+    label: "Search...",
+    type: "normal",
+    accelerator: config.get("historySearchHotkey") || undefined,
+    click: showHistorySearch,
+  };
+  if (entries.length === 0) {
+    return [
+      { label: "No clipboards yet", type: "normal", enabled: false },
+      ...othersHistorySubmenu,
+      { type: "separator" },
+      getKeepHistoryCheckBox(),
+      getSyncHistoryCheckBox(),
+      getEncryptHistoryCheckBox(),
+    ];
+  }
+  return [
+    ...entries.map(
+      (entry): Electron.MenuItemConstructorOptions => ({
+        label: getHistoryEntryLabel(entry),
+        type: "submenu",
+        icon:
+          entry.type === "image"
+            ? getHistoryThumbnail(entry.id)?.resize({ height: 16 })
+            : undefined,
+        submenu: [
+          {
+            label: "Copy",
+            type: "normal",
+            click: () => copyHistoryEntry(entry),
+          },
+          {
+            label: entry.pinned ? "Unpin" : "Pin",
+            type: "normal",
+            click: () => setPinned(entry.id, !entry.pinned),
+          },
+        ],
+      })
+    ),
+    ...othersHistorySubmenu,
+    { type: "separator" },
+    searchItem,
+    { label: "Clear history", type: "normal", click: clearHistory },
+    getKeepHistoryCheckBox(),
+    getSyncHistoryCheckBox(),
+    getEncryptHistoryCheckBox(),
+  ];
 };
 
-const handleCleanupCheckBox = (checkBox: Electron.MenuItem) => {
-  config.set("autoCleanup", checkBox.checked);
-  reload();
+const getPinnedSubmenu = (): Electron.MenuItemConstructorOptions[] => {
+  const ownPinnedEntries = getHistoryEntries()
+    .filter((entry) => entry.pinned);
+  const ownPinnedTexts = ownPinnedEntries.map((entry) => entry.text);
+  const othersPinnedClipboards = readOthersPinnedClipboards().filter(
+    (pinnedClipboard, index, pinnedClipboards) =>
+      !ownPinnedTexts.includes(pinnedClipboard.text) &&
+      pinnedClipboards.findIndex(
+        (otherPinnedClipboard) =>
+          otherPinnedClipboard.text === pinnedClipboard.text
+      ) === index
+  );
+  if (ownPinnedEntries.length === 0 && othersPinnedClipboards.length === 0) {
+    return [{ label: "No pinned clipboards", type: "normal", enabled: false }];
+  }
+  return [
+    ...ownPinnedEntries.map(
+      (entry): Electron.MenuItemConstructorOptions => ({
+        label: getHistoryEntryLabel(entry),
+        type: "normal",
+        click: () => copyHistoryEntry(entry),
+      })
+    ),
+    ...othersPinnedClipboards.map(
+      (pinnedClipboard): Electron.MenuItemConstructorOptions => ({
+        label: getClipboardSummary(pinnedClipboard.text),
+        type: "normal",
+        click: () => clipboard.writeText(pinnedClipboard.text),
+      })
+    ),
+  ];
 };
 
 let updateLabel = "Check for updates";
@@ -726,6 +10389,17 @@ const autoCheckForUpdates = async () => {
 
 const setContextMenu = () => {
   const menu = Menu.buildFromTemplate([
+    ...(writeError
+      ? [
+          {
+            label: `⚠ Sending is failing: ${writeError}`,
+            type: "normal",
+            enabled: false,
+          } as Electron.MenuItemConstructorOptions,
+          { type: "separator" } as Electron.MenuItemConstructorOptions,
+        ]
+      : []),
+    ...getStatusItems(),
     {
       label: "Send",
       type: "checkbox",
@@ -740,13 +10414,210 @@ const setContextMenu = () => {
       click: handleReceiveCheckBox,
       toolTip: "Watch for new files on the folder set to receive to clipboard",
     },
+    {
+      label: "Don't send",
+      type: "submenu",
+      submenu: isSkippingCopies()
+        ? [
+            {
+              label: skipNextCopy
+                ? "Skipping next copy"
+                : `Skipping until ${new Date(
+                    skipCopiesUntil
+                  ).toLocaleTimeString()}`,
+              type: "normal",
+              enabled: false,
+            },
+            { label: "Resume", type: "normal", click: resumeCopies },
+          ]
+        : [
+            {
+              label: "Next copy",
+              type: "normal",
+              accelerator: config.get("skipNextCopyHotkey") || undefined,
+              click: () => skipCopies(),
+            },
+            {
+              label: "For 1 minute",
+              type: "normal",
+              click: () => skipCopies(60),
+            },
+            {
+              label: "For 5 minutes",
+              type: "normal",
+              click: () => skipCopies(300),
+            },
+          ],
+      toolTip: "Temporarily stop sending clipboards, e.g. to copy a password",
+    },
+    {
+      label: paused ? "Resume syncing" : "Pause syncing",
+      type: "normal",
+      accelerator: config.get("pauseHotkey") || undefined,
+      click: () => setPaused(!paused),
+      toolTip: paused
+        ? "Send and receive clipboards again"
+        : "Stop sending and receiving clipboards until resumed, even after restarting",
+    },
+    {
+      label: "Send clipboard now",
+      type: "normal",
+      accelerator: config.get("sendNowHotkey") || undefined,
+      enabled: !!syncFolder && folderAvailable,
+      click: sendClipboardNow,
+      toolTip:
+        "Send the current clipboard, even if it was already sent or the copy was missed",
+    },
+    {
+      label: "Fetch latest clipboard",
+      type: "normal",
+      enabled: !!syncFolder && folderAvailable,
+      click: fetchLatestClipboard,
+      toolTip:
+        "Copy the newest clipboard of the other computers from the folder, when its arrival was missed",
+    },
+    { type: "separator" },
+    {
+      label: "Pinned",
+      type: "submenu",
+      submenu: getPinnedSubmenu(),
+      toolTip: "Clipboards pinned on any computer",
+    },
+    {
+      label: "History",
+      type: "submenu",
+      submenu: getHistorySubmenu(),
+    },
+    {
+      label: "Show activity",
+      type: "normal",
+      click: showActivity,
+      toolTip:
+        "The latest clipboards sent and received, and whether they were applied, skipped or failed",
+    },
     { type: "separator" },
     {
       label: "Auto-clean",
       type: "checkbox",
       checked: config.get("autoCleanup", true),
       click: handleCleanupCheckBox,
-      toolTip: `Auto-clean the files created by ${app.name} older than 5 minutes, on every 5 minutes`,
+      toolTip: `Auto-clean the files created by ${app.name} older than ${config.get(
+        "cleanupMaxAgeMinutes",
+        5
+      )} minutes, on every ${config.get("cleanupIntervalMinutes", 5)} minutes`,
+    },
+    {
+      label: "Archive received clipboards",
+      type: "checkbox",
+      checked: !!config.get("archiveFolder"),
+      click: handleArchiveCheckBox,
+      toolTip: config.get("archiveFolder")
+        ? `Copying the clipboards received to ${config.get("archiveFolder")}`
+        : "Copy the clipboards received to a folder of your choice, in a folder for each day",
+    },
+    {
+      label: "Clean my files on exit",
+      type: "checkbox",
+      checked: config.get("cleanOwnFilesOnExit", false),
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("cleanOwnFilesOnExit", checkBox.checked);
+        setContextMenu();
+      },
+      toolTip:
+        "Remove the clipboard files written by this computer from the folder when exiting",
+    },
+    {
+      label: "Notify on receive",
+      type: "checkbox",
+      checked: config.get("receiveNotifications", false),
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("receiveNotifications", checkBox.checked);
+        setContextMenu();
+      },
+      toolTip:
+        "Show a notification for each clipboard received, with a thumbnail for images",
+    },
+    {
+      label: "Receive text files as text",
+      type: "checkbox",
+      checked: config.get("receiveTextFilesAsText", false),
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("receiveTextFilesAsText", checkBox.checked);
+        setContextMenu();
+      },
+      toolTip:
+        "Copy a single small text file received as text, so it can be pasted into editors",
+    },
+    ...(process.platform === "linux"
+      ? [
+          {
+            label: "Forward to KDE Connect",
+            type: "checkbox",
+            checked: config.get("kdeConnect", false),
+            click: (checkBox: Electron.MenuItem) => {
+              config.set("kdeConnect", checkBox.checked);
+              setContextMenu();
+            },
+            toolTip:
+              "Send the clipboards received to the phones paired with KDE Connect",
+          } as Electron.MenuItemConstructorOptions,
+        ]
+      : []),
+    ...(process.platform === "darwin"
+      ? [
+          {
+            label: "Send Universal Clipboard copies",
+            type: "checkbox",
+            checked: config.get("sendUniversalClipboard", true),
+            click: (checkBox: Electron.MenuItem) => {
+              config.set("sendUniversalClipboard", checkBox.checked);
+              setContextMenu();
+            },
+            toolTip:
+              "Send the clipboards copied on other Apple devices, uncheck when all of them are syncing too",
+          } as Electron.MenuItemConstructorOptions,
+          {
+            label: "Show last clipboard in menu bar",
+            type: "checkbox",
+            checked: config.get("menuBarTitle", "off") !== "off",
+            click: (checkBox: Electron.MenuItem) => {
+              config.set("menuBarTitle", checkBox.checked ? "peer" : "off");
+              if (!checkBox.checked) {
+                appIcon.setTitle("");
+              }
+              setContextMenu();
+            },
+            toolTip:
+              "Show next to the icon where the last clipboard came from, like ←DESKTOP-B",
+          } as Electron.MenuItemConstructorOptions,
+        ]
+      : []),
+    {
+      label: "Network share mode",
+      type: "checkbox",
+      checked: config.get("networkShareMode", false),
+      click: handleNetworkShareCheckBox,
+      toolTip:
+        "Poll the folder and tolerate it disappearing, for network shares without a cloud client",
+    },
+    {
+      label: "Share settings with other computers",
+      type: "checkbox",
+      checked: config.get("roamSettings", false),
+      click: handleRoamSettingsCheckBox,
+      toolTip:
+        "Keep the settings in the folder, except the ones listed in localSettings",
+    },
+    {
+      label: "LAN wake hints",
+      type: "checkbox",
+      checked: config.get("lanWakeHints", false),
+      click: (checkBox: Electron.MenuItem) => {
+        config.set("lanWakeHints", checkBox.checked);
+        reload();
+      },
+      toolTip:
+        "Announce new clipboards on the LAN, so the others check the folder right away instead of on the next poll",
     },
     {
       label: "Auto-start on login",
@@ -758,8 +10629,67 @@ const setContextMenu = () => {
         });
       },
     },
+    {
+      label: "Simultaneous copies",
+      type: "submenu",
+      submenu: getConflictSubmenu(),
+      toolTip:
+        "Which clipboard to keep when another computer copies at the same time as this one",
+    },
+    {
+      label: "Channel",
+      type: "submenu",
+      submenu: getChannelSubmenu(),
+      toolTip: "The channel to send to and receive from",
+    },
+    {
+      label: "Send to",
+      type: "submenu",
+      submenu: getSendTargetSubmenu(),
+      toolTip: "The computers which receive the clipboards copied here",
+    },
+    {
+      label: "Plugins",
+      type: "submenu",
+      submenu: getPluginsSubmenu(),
+      toolTip:
+        "Enable WebAssembly or Lua plugins to inspect, rewrite or veto clipboards",
+    },
+    {
+      label: "Remote control",
+      type: "submenu",
+      submenu: getRemoteControlSubmenu(),
+      toolTip: "Send signed commands to your other computers",
+    },
+    {
+      label: "Transports",
+      type: "submenu",
+      submenu: getTransportsSubmenu(),
+      toolTip:
+        "The ways clipboards are delivered, and the fastest one for each computer",
+    },
+    {
+      label: "Devices",
+      type: "submenu",
+      submenu: getDevicesSubmenu(),
+      toolTip:
+        "The other computers using the folder, with how long they take to answer and how often they do",
+    },
+    {
+      label: "Troubleshooting",
+      type: "submenu",
+      submenu: getTroubleshootingSubmenu(),
+    },
     { type: "separator" },
     { label: "Change folder", type: "normal", click: askForFolder },
+    {
+      label: "Advanced settings",
+      type: "normal",
+      click: () => {
+        config.openInEditor();
+      },
+      toolTip: "Open the settings file to change options not in this menu",
+    },
     {
       label: "Open folder",
       type: "normal",
@@ -783,30 +10713,158 @@ const setContextMenu = () => {
         "Open the GitHub page of the project. Please star it if you like it!",
     },
     { type: "separator" },
+    {
+      label: "Reload",
+      type: "normal",
+      click: reloadPress,
+      toolTip:
+        "Stop and start syncing again, reading the settings file again",
+    },
     {
       label: "Exit",
       type: "normal",
       click: () => finish(),
     },
   ]);
-  appIcon.setContextMenu(menu);
+  if (appIcon) {
+    appIcon.setContextMenu(menu);
+  }
+};
+
+// Used when no script is given, covers sending, receiving without sending it
+// back, deduplication and cleanup
+const defaultSimulationScript: SimulationScript = {
+  steps: [
+    { at: 0, copy: "sent", expectFiles: 0 },
+    { at: 2000, expectFiles: 1 },
+    { at: 3000, from: "PEER-A", text: "received" },
+    { at: 5000, expectText: "received", expectFiles: 2 },
+    { at: 6000, copy: "sent", expectFiles: 2 },
+    { at: 8000, expectFiles: 2, cleanup: true },
+    { at: 9000, expectFiles: 0 },
+  ],
+};
+
+const readSimulationScript = (): SimulationScript => {
+  const scriptPath = getArgValue("simulate-script");
+  if (!scriptPath) {
+    return defaultSimulationScript;
+  }
+  return JSON.parse(fs.readFileSync(scriptPath, { encoding: "utf8" }));
+};
+
+const countClipboardFiles = () => {
+  return fs
+    .readdirSync(syncFolder)
+    .filter((file) => getItemNumber(path.join(syncFolder, file))).length;
+};
+
+const runSimulationStep = (step: SimulationStep) => {
+  const failures: string[] = [];
+  if (step.copy !== undefined) {
+    clipboard.writeText(step.copy);
+  }
+  if (step.from) {
+    // Numbered like the app does, right after the latest clipboard
+    const fileBaseName = `${getNextWriteTime()}-${step.from}`;
+    if (step.text !== undefined) {
+      fs.writeFileSync(
+        path.join(syncFolder, `${fileBaseName}.txt`),
+        step.text,
+        { encoding: "utf8" }
+      );
+    } else if (step.image) {
+      fs.copyFileSync(step.image, path.join(syncFolder, `${fileBaseName}.png`));
+    }
+  }
+  if (
+    step.expectText !== undefined &&
+    clipboard.readText() !== step.expectText
+  ) {
+    failures.push(
+      `expected clipboard "${step.expectText}", got "${clipboard.readText()}"`
+    );
+  }
+  if (step.expectFiles !== undefined) {
+    const filesCount = countClipboardFiles();
+    if (filesCount !== step.expectFiles) {
+      failures.push(
+        `expected ${step.expectFiles} clipboard files, got ${filesCount}`
+      );
+    }
+  }
+  if (step.cleanup) {
+    cleanFiles(0);
+  }
+  return failures;
+};
+
+// Runs the scripted steps and exits with 1 if any expectation failed, so it
+// can be used from integration tests
+const runSimulation = async () => {
+  let script: SimulationScript;
+  try {
+    script = readSimulationScript();
+  } catch (error) {
+    console.error(`Error reading simulation script: ${error}`);
+    app.exit(2);
+    return;
+  }
+
+  // Both are in the temporary folder set as the user data at startup, so the
+  // real settings and folder are never touched
+  const folder = path.join(app.getPath("userData"), "folder");
+  fs.mkdirSync(folder, { recursive: true });
+  config.set("folder", folder);
+  console.log(`Simulating in ${folder}`);
+
+  await openHistory();
+  initialize();
+
+  let failuresCount = 0;
+  const startTime = Date.now();
+  const steps = [...script.steps].sort((a, b) => a.at - b.at);
+  for (const [index, step] of steps.entries()) {
+    await new Promise((resolve) =>
+      setTimeout(resolve, Math.max(0, startTime + step.at - Date.now()))
+    );
+    const failures = runSimulationStep(step);
+    failuresCount += failures.length;
+    for (const failure of failures) {
+      console.error(`Step ${index + 1} at ${step.at}ms: ${failure}`);
+    }
+  }
+
+  console.log(
+    failuresCount
+      ? `Simulation failed with ${failuresCount} unmet expectations`
+      : "Simulation passed"
+  );
+  finish(failuresCount ? 1 : 0);
 };
 
-const createAppIcon = () => {
-  appIcon = new Tray(getTrayIcon("clipboard"));
+const createAppIcon = async () => {
+  appIcon = new Tray(getIdleTrayIcon());
   setContextMenu();
-  appIcon.setToolTip(`${app.name} v${app.getVersion()}`);
+  updateToolTip();
 
   // sets left click to open the context menu too
   appIcon.on("click", () => {
+    setContextMenu();
     appIcon.popUpContextMenu();
   });
 
+  // The status at the top of the menu is refreshed before it opens
+  appIcon.on("mouse-enter", setContextMenu);
+
   appIcon.on("double-click", () => {
     shell.openPath(syncFolder);
   });
 
   initialize();
+  startControlServer();
+  // Opened once syncing started, as it may wait for the passphrase
+  openHistory().then(setContextMenu);
 
   if (firstTime) {
     firstTime = false;
@@ -817,12 +10875,33 @@ const createAppIcon = () => {
 // This method will be called when Electron has finished
 // initialization and is ready to create browser windows.
 // Some APIs can only be used after this event occurs.
-app.on("ready", createAppIcon);
+app.on(
+  "ready",
+  isRelayMode
+    ? startRelayServer
+    : isSimulateMode
+    ? runSimulation
+    : createAppIcon
+);
 
 app.on("window-all-closed", () => {
-  finish();
+  // Windows are only opened for prompts, the app keeps running on the tray
+});
+
+// Forwarded by the instances started later, like "--command pause"
+app.on("second-instance", (event, argv) => {
+  const command = getArgValue("command", argv);
+  if (command && appIcon) {
+    runControlCommand(command);
+  }
 });
 
 app.on("before-quit", () => {
-  cleanup();
+  if (isRelayMode) {
+    stopRelayServer();
+  } else {
+    stopControlServer();
+    cleanOwnFiles();
+    cleanup();
+  }
 });
@@ -0,0 +1,121 @@
+import { createHash, createHmac, ECDH } from "crypto";
+import { ProtobufValue } from "./transports/grpc";
+
+export type ControlAction = "pause" | "cleanup" | "status" | "status-reply";
+
+// Commands exchanged with the other computers through the folder, signed with
+// the key derived from the remote control passphrase
+export type ControlMessage = {
+  id: string;
+  from: string;
+  // A hostname, or "*" for all the other computers
+  to: string;
+  action: ControlAction;
+  args: Record<string, ProtobufValue>;
+  time: number;
+  // Tells which passphrase signed it while rotating them
+  keyId: string;
+  signature: string;
+};
+
+// Written by a computer asking to be paired, and answered by each paired one
+export type PairingMessage = {
+  // Missing on the nonces sent by the computer asking to be paired
+  publicKey?: string;
+  time: number;
+  // Hash of the nonce of the paired computer and both public keys, written
+  // before it knows the nonce of the other computer
+  commitment?: string;
+  nonce?: string;
+  // Added to the answer once the code is approved on the paired computer
+  encryptedKey?: string;
+};
+
+// What each side keeps of a pairing: the public key of the other computer
+// and the commitment as first seen, and the nonces once sent
+export type PairingExchange = {
+  otherPublicKey: string;
+  commitment: string;
+  nonce: string;
+  otherNonce?: string;
+  time: number;
+};
+
+// How long a pairing request can be answered
+export const pairingMaxAgeMs = 300000;
+// Pairing messages dated further in the future are ignored
+export const pairingClockSkewMs = 60000;
+
+// The time is set by the other computer, so the ones in the future are not
+// trusted to make a request last longer
+export const isPairingMessageFresh = (message: PairingMessage) => {
+  return (
+    typeof message.time === "number" &&
+    message.time <= Date.now() + pairingClockSkewMs &&
+    Date.now() - message.time <= pairingMaxAgeMs
+  );
+};
+
+export const getPairingCommitment = (
+  nonce: string,
+  requestKey: string,
+  answerKey: string
+) => {
+  return createHash("sha256")
+    .update([nonce, requestKey, answerKey].join("\n"))
+    .digest("hex");
+};
+
+// The code covers both public keys and both nonces. The paired computer
+// commits to its nonce before seeing the one of the other computer, which
+// sends it before seeing the former, so whoever swaps the public keys can't
+// choose them to make the codes match, and has one chance in a million.
+export const getPairingSecrets = (
+  ecdh: ECDH,
+  requestKey: string,
+  answerKey: string,
+  requestNonce: string,
+  answerNonce: string
+) => {
+  const otherPublicKey =
+    ecdh.getPublicKey("base64") === requestKey ? answerKey : requestKey;
+  const secret = ecdh.computeSecret(otherPublicKey, "base64");
+  const transcript = [requestKey, answerKey, requestNonce, answerNonce].join(
+    "\n"
+  );
+  const code = createHash("sha256")
+    .update(`clipboard-sync-pairing-code\n${transcript}`)
+    .digest()
+    .readUInt32BE(0);
+  return {
+    code: String(code % 1000000).padStart(6, "0"),
+    key: createHmac("sha256", secret)
+      .update(`clipboard-sync-pairing-key\n${transcript}`)
+      .digest(),
+  };
+};
+
+// Tells the keys apart without revealing them
+export const getControlKeyId = (key: Buffer) => {
+  return createHash("sha256").update(key).digest("hex").substring(0, 8);
+};
+
+// The commands are signed with HMAC, so only the computers sharing the key
+// can send them
+export const getControlMessageSignature = (
+  message: ControlMessage,
+  key: Buffer
+) => {
+  return createHmac("sha256", key)
+    .update(
+      JSON.stringify([
+        message.id,
+        message.from,
+        message.to,
+        message.action,
+        message.args,
+        message.time,
+      ])
+    )
+    .digest("hex");
+};
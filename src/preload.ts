@@ -0,0 +1,18 @@
+import { contextBridge, ipcRenderer } from "electron";
+
+// The windows show texts received from the other computers, so they run
+// isolated and without Node.js, and can only talk to the app on the channel
+// given to them through the command line
+const channelArgument = "--ipc-channel=";
+const channel = (
+  process.argv.find((arg) => arg.startsWith(channelArgument)) || ""
+).substring(channelArgument.length);
+
+contextBridge.exposeInMainWorld("clipboardSync", {
+  send: (value: string) => {
+    ipcRenderer.send(channel, value);
+  },
+  onUpdate: (callback: (value: unknown) => void) => {
+    ipcRenderer.on(channel, (event, value: unknown) => callback(value));
+  },
+});
@@ -0,0 +1,129 @@
+import fs = require("fs");
+import path = require("path");
+
+// Sets of files can be sent as a single tar file, which cloud providers sync
+// much faster than hundreds of small files. Only what is needed of the ustar
+// format is implemented: regular files and folders.
+const tarBlockSize = 512;
+
+const getTarHeader = (entryName: string, size: number, mtime: Date) => {
+  const header = Buffer.alloc(tarBlockSize);
+  let name = entryName;
+  let prefix = "";
+  // Longer names are split between the prefix and name fields
+  if (Buffer.byteLength(name) > 100) {
+    let slashIndex = entryName.indexOf("/");
+    while (
+      slashIndex !== -1 &&
+      (Buffer.byteLength(entryName.slice(0, slashIndex)) > 155 ||
+        Buffer.byteLength(entryName.slice(slashIndex + 1)) > 100)
+    ) {
+      slashIndex = entryName.indexOf("/", slashIndex + 1);
+    }
+    if (slashIndex <= 0) {
+      throw new Error(`Path too long to be archived: ${entryName}`);
+    }
+    prefix = entryName.slice(0, slashIndex);
+    name = entryName.slice(slashIndex + 1);
+  }
+  const isDirectory = entryName.endsWith("/");
+  const octal = (value: number, length: number) =>
+    `${value.toString(8).padStart(length - 1, "0")}\0`;
+  header.write(name, 0, 100);
+  header.write(octal(isDirectory ? 0o755 : 0o644, 8), 100);
+  header.write(octal(0, 8), 108);
+  header.write(octal(0, 8), 116);
+  header.write(octal(size, 12), 124);
+  header.write(octal(Math.floor(mtime.getTime() / 1000), 12), 136);
+  header.write("        ", 148);
+  header.write(isDirectory ? "5" : "0", 156);
+  header.write("ustar\0", 257);
+  header.write("00", 263);
+  header.write(prefix, 345, 155);
+  const checksum = header.reduce((sum, byte) => sum + byte, 0);
+  header.write(`${checksum.toString(8).padStart(6, "0")}\0 `, 148);
+  return header;
+};
+
+export const writeTarArchive = (paths: string[], destination: string) => {
+  const fd = fs.openSync(destination, "w");
+  try {
+    const writeEntry = (entryPath: string, entryName: string) => {
+      const entryStat = fs.statSync(entryPath);
+      if (entryStat.isDirectory()) {
+        fs.writeSync(fd, getTarHeader(`${entryName}/`, 0, entryStat.mtime));
+        fs.readdirSync(entryPath).forEach((child) =>
+          writeEntry(path.join(entryPath, child), `${entryName}/${child}`)
+        );
+        return;
+      }
+      const data = fs.readFileSync(entryPath);
+      fs.writeSync(fd, getTarHeader(entryName, data.length, entryStat.mtime));
+      fs.writeSync(fd, data);
+      const padding =
+        (tarBlockSize - (data.length % tarBlockSize)) % tarBlockSize;
+      if (padding > 0) {
+        fs.writeSync(fd, Buffer.alloc(padding));
+      }
+    };
+    paths.forEach((entryPath) =>
+      writeEntry(entryPath, path.basename(entryPath))
+    );
+    // The end of the archive is marked by two empty blocks
+    fs.writeSync(fd, Buffer.alloc(tarBlockSize * 2));
+  } finally {
+    fs.closeSync(fd);
+  }
+};
+
+// Returns the paths of the files and folders at the top of the archive, or
+// null if the archive is not complete yet
+export const extractTarArchive = (archive: string, destination: string) => {
+  const data = fs.readFileSync(archive);
+  const readString = (offset: number, length: number) => {
+    const value = data.toString("utf8", offset, offset + length);
+    return value.includes("\0") ? value.slice(0, value.indexOf("\0")) : value;
+  };
+  const topLevelNames: string[] = [];
+  let offset = 0;
+  let ended = false;
+  while (offset + tarBlockSize <= data.length) {
+    if (data.subarray(offset, offset + tarBlockSize).every((byte) => !byte)) {
+      ended = true;
+      break;
+    }
+    const prefix = readString(offset + 345, 155);
+    const name = readString(offset, 100);
+    const entryName = (prefix ? `${prefix}/${name}` : name).replace(/\/$/, "");
+    const size = parseInt(readString(offset + 124, 12).trim() || "0", 8);
+    const typeFlag = readString(offset + 156, 1);
+    const dataOffset = offset + tarBlockSize;
+    if (dataOffset + size > data.length) {
+      break;
+    }
+    // Never written outside of the destination
+    const segments = entryName.split("/");
+    if (
+      !entryName ||
+      path.isAbsolute(entryName) ||
+      segments.some((segment) => segment === ".." || segment.includes("\\"))
+    ) {
+      throw new Error(`Invalid path in the archive: ${entryName}`);
+    }
+    const entryPath = path.join(destination, ...segments);
+    if (typeFlag === "5") {
+      fs.mkdirSync(entryPath, { recursive: true });
+    } else if (typeFlag === "0" || typeFlag === "") {
+      fs.mkdirSync(path.dirname(entryPath), { recursive: true });
+      fs.writeFileSync(entryPath, data.subarray(dataOffset, dataOffset + size));
+    }
+    if (!topLevelNames.includes(segments[0])) {
+      topLevelNames.push(segments[0]);
+    }
+    offset = dataOffset + Math.ceil(size / tarBlockSize) * tarBlockSize;
+  }
+  if (!ended) {
+    return null;
+  }
+  return topLevelNames.map((name) => path.join(destination, name));
+};
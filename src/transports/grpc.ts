@@ -0,0 +1,82 @@
+// Only what the service in proto/clipboard_sync.proto needs of protobuf and
+// gRPC is implemented: varint and length-delimited fields, and the message
+// framing
+
+export type ProtobufValue = string | number | boolean;
+
+export const encodeVarint = (value: number) => {
+  const bytes: number[] = [];
+  while (value > 127) {
+    bytes.push((value & 0x7f) | 0x80);
+    value = Math.floor(value / 128);
+  }
+  bytes.push(value);
+  return Buffer.from(bytes);
+};
+
+// Encodes the fields of a protobuf message, given as [field number, value]
+export const encodeProtobuf = (fields: [number, ProtobufValue][]) => {
+  return Buffer.concat(
+    fields
+      .filter(([, value]) => value !== null && value !== undefined)
+      .map(([fieldNumber, value]) => {
+        if (typeof value === "string") {
+          const data = Buffer.from(value, "utf8");
+          return Buffer.concat([
+            encodeVarint((fieldNumber << 3) | 2),
+            encodeVarint(data.length),
+            data,
+          ]);
+        }
+        return Buffer.concat([
+          encodeVarint(fieldNumber << 3),
+          encodeVarint(Math.max(0, Math.round(Number(value)))),
+        ]);
+      })
+  );
+};
+
+// Decodes the varint and length-delimited fields of a protobuf message
+export const decodeProtobuf = (data: Buffer) => {
+  const fields = new Map<number, number | Buffer>();
+  let offset = 0;
+  const readVarint = () => {
+    let value = 0;
+    let multiplier = 1;
+    while (offset < data.length) {
+      const byte = data[offset++];
+      value += (byte & 0x7f) * multiplier;
+      if (byte < 128) {
+        break;
+      }
+      multiplier *= 128;
+    }
+    return value;
+  };
+  while (offset < data.length) {
+    const key = readVarint();
+    const wireType = key & 7;
+    if (wireType === 0) {
+      fields.set(key >> 3, readVarint());
+    } else if (wireType === 2) {
+      const length = readVarint();
+      fields.set(key >> 3, data.subarray(offset, offset + length));
+      offset += length;
+    } else {
+      break;
+    }
+  }
+  return fields;
+};
+
+// Adds the gRPC length-prefixed message framing
+export const frameGrpcMessage = (message: Buffer) => {
+  const header = Buffer.alloc(5);
+  header.writeUInt32BE(message.length, 1);
+  return Buffer.concat([header, message]);
+};
+
+// Removes the gRPC framing of the first message of a request
+export const readGrpcMessage = (body: Buffer) => {
+  return body.length >= 5 ? body.subarray(5, 5 + body.readUInt32BE(1)) : body;
+};
@@ -0,0 +1,43 @@
+// Only the parts of the NATS client protocol used are implemented:
+// https://docs.nats.io/reference/reference-protocols/nats-protocol
+
+// Adds the line ending, and the size of the payload if any
+export const encodeNatsCommand = (command: string, payload?: string) => {
+  return payload === undefined
+    ? `${command}\r\n`
+    : `${command} ${Buffer.byteLength(payload)}\r\n${payload}\r\n`;
+};
+
+// Returns the function to hand the data received to, which calls onOperation
+// with each complete operation, and the payload of the MSG ones
+export const createNatsParser = (
+  onOperation: (operation: string, args: string[], payload?: Buffer) => void
+) => {
+  let buffered = Buffer.alloc(0);
+  return (chunk: Buffer) => {
+    buffered = Buffer.concat([buffered, chunk]);
+    for (;;) {
+      const lineEnd = buffered.indexOf("\r\n");
+      if (lineEnd === -1) {
+        return;
+      }
+      const line = buffered.subarray(0, lineEnd).toString("utf8");
+      const [operation, ...args] = line.split(" ");
+      if (operation === "MSG") {
+        // MSG <subject> <sid> [reply-to] <#bytes>
+        const size = parseInt(args[args.length - 1]);
+        if (buffered.length < lineEnd + 2 + size + 2) {
+          return;
+        }
+        const payload = Buffer.from(
+          buffered.subarray(lineEnd + 2, lineEnd + 2 + size)
+        );
+        buffered = buffered.subarray(lineEnd + 2 + size + 2);
+        onOperation(operation, args, payload);
+        continue;
+      }
+      buffered = buffered.subarray(lineEnd + 2);
+      onOperation(operation, args);
+    }
+  };
+};
@@ -0,0 +1,170 @@
+import net = require("net");
+import { createHash, randomBytes } from "crypto";
+
+export type WebSocketConnection = {
+  // Sent as text or binary depending on the data, unless the opcode is given
+  send: (data: Buffer | string, opcode?: number) => void;
+  ping: () => void;
+  close: () => void;
+  // Whether anything was received since the last ping
+  alive: boolean;
+};
+
+export const encodeWebSocketFrame = (
+  opcode: number,
+  payload: Buffer,
+  masked: boolean
+) => {
+  let header: Buffer;
+  if (payload.length < 126) {
+    header = Buffer.from([0x80 | opcode, payload.length]);
+  } else if (payload.length < 65536) {
+    header = Buffer.alloc(4);
+    header.writeUInt8(0x80 | opcode, 0);
+    header.writeUInt8(126, 1);
+    header.writeUInt16BE(payload.length, 2);
+  } else {
+    header = Buffer.alloc(10);
+    header.writeUInt8(0x80 | opcode, 0);
+    header.writeUInt8(127, 1);
+    header.writeBigUInt64BE(BigInt(payload.length), 2);
+  }
+  if (!masked) {
+    return Buffer.concat([header, payload]);
+  }
+  // Clients must mask what they send
+  header[1] |= 0x80;
+  const mask = randomBytes(4);
+  const maskedPayload = Buffer.from(payload);
+  for (let i = 0; i < maskedPayload.length; i++) {
+    maskedPayload[i] ^= mask[i % 4];
+  }
+  return Buffer.concat([header, mask, maskedPayload]);
+};
+
+// Applies to each frame and to the fragments of a message altogether
+export const maxWebSocketMessageSize = 64 * 1024 * 1024;
+
+// Wraps an upgraded socket, calling onMessage with each complete text or
+// binary message and its opcode, and answering pings and closes
+export const createWebSocketConnection = (
+  socket: net.Socket,
+  masked: boolean,
+  onMessage: (message: Buffer, opcode: number) => void,
+  onClose: () => void
+): WebSocketConnection => {
+  let buffered = Buffer.alloc(0);
+  let fragments: Buffer[] = [];
+  let fragmentsSize = 0;
+  let messageOpcode: number = null;
+  let closed = false;
+  const connection: WebSocketConnection = {
+    send: (data, opcode) => {
+      if (!closed) {
+        socket.write(
+          encodeWebSocketFrame(
+            opcode ?? (typeof data === "string" ? 0x1 : 0x2),
+            typeof data === "string" ? Buffer.from(data, "utf8") : data,
+            masked
+          )
+        );
+      }
+    },
+    ping: () => {
+      if (!closed) {
+        socket.write(encodeWebSocketFrame(0x9, Buffer.alloc(0), masked));
+      }
+    },
+    close: () => {
+      if (!closed) {
+        socket.end(encodeWebSocketFrame(0x8, Buffer.alloc(0), masked));
+      }
+    },
+    alive: true,
+  };
+  const handleFrame = (opcode: number, fin: boolean, payload: Buffer) => {
+    connection.alive = true;
+    if (opcode === 0x8) {
+      connection.close();
+    } else if (opcode === 0x9) {
+      socket.write(encodeWebSocketFrame(0xa, payload, masked));
+    } else if (opcode === 0x0 || opcode === 0x1 || opcode === 0x2) {
+      // Continuations only follow the first fragment of a message
+      if ((opcode === 0x0) !== (messageOpcode !== null)) {
+        socket.destroy();
+        return;
+      }
+      if (opcode !== 0x0) {
+        messageOpcode = opcode;
+      }
+      fragmentsSize += payload.length;
+      if (fragmentsSize > maxWebSocketMessageSize) {
+        socket.destroy();
+        return;
+      }
+      fragments.push(payload);
+      if (fin) {
+        const message = Buffer.concat(fragments);
+        const finishedOpcode = messageOpcode;
+        fragments = [];
+        fragmentsSize = 0;
+        messageOpcode = null;
+        onMessage(message, finishedOpcode);
+      }
+    }
+  };
+  socket.on("data", (chunk: Buffer) => {
+    buffered = Buffer.concat([buffered, chunk]);
+    while (buffered.length >= 2 && !socket.destroyed) {
+      const fin = (buffered[0] & 0x80) !== 0;
+      const opcode = buffered[0] & 0x0f;
+      const isMasked = (buffered[1] & 0x80) !== 0;
+      let length = buffered[1] & 0x7f;
+      let offset = 2;
+      if (length === 126) {
+        if (buffered.length < 4) {
+          return;
+        }
+        length = buffered.readUInt16BE(2);
+        offset = 4;
+      } else if (length === 127) {
+        if (buffered.length < 10) {
+          return;
+        }
+        length = Number(buffered.readBigUInt64BE(2));
+        offset = 10;
+      }
+      if (length > maxWebSocketMessageSize) {
+        socket.destroy();
+        return;
+      }
+      const mask = isMasked ? buffered.subarray(offset, offset + 4) : null;
+      offset += isMasked ? 4 : 0;
+      if (buffered.length < offset + length) {
+        return;
+      }
+      const payload = Buffer.from(buffered.subarray(offset, offset + length));
+      if (mask) {
+        for (let i = 0; i < payload.length; i++) {
+          payload[i] ^= mask[i % 4];
+        }
+      }
+      buffered = buffered.subarray(offset + length);
+      handleFrame(opcode, fin, payload);
+    }
+  });
+  socket.on("close", () => {
+    closed = true;
+    onClose();
+  });
+  socket.on("error", (error) => {
+    console.error(`WebSocket error: ${error}`);
+  });
+  return connection;
+};
+
+export const getWebSocketAccept = (key: string) => {
+  return createHash("sha1")
+    .update(`${key}258EAFA5-E914-47DA-95CA-C5AB0DC85B11`)
+    .digest("base64");
+};
@@ -0,0 +1,72 @@
+import assert = require("assert");
+import { randomBytes } from "crypto";
+import {
+  ClipEnvelope,
+  decryptBuffer,
+  encryptBuffer,
+  openEnvelope,
+  sealEnvelope,
+} from "../src/envelope";
+import { test } from "./harness";
+
+const key = randomBytes(32);
+const maxAgeMs = 60000;
+
+const getEnvelope = (): ClipEnvelope => ({
+  id: "42-DESKTOP-A",
+  itemNumber: 42,
+  hostname: "DESKTOP-A",
+  channel: "default",
+  type: "text",
+  data: Buffer.from("hello", "utf8").toString("base64"),
+  time: Date.now(),
+});
+
+const getKey = (keyId: string) => (keyId === "key-1" ? key : null);
+
+test("opens the envelopes sealed with a known key", () => {
+  const envelope = getEnvelope();
+  const message = Buffer.from(sealEnvelope(envelope, "key-1", key), "utf8");
+  assert.deepStrictEqual(openEnvelope(message, getKey, maxAgeMs), envelope);
+});
+
+test("never shows the clipboard sealed", () => {
+  const message = sealEnvelope(getEnvelope(), "key-1", key);
+  assert.strictEqual(message.includes("DESKTOP-A"), false);
+  assert.strictEqual(message.includes(getEnvelope().data), false);
+});
+
+test("rejects the envelopes sealed with another key", () => {
+  const envelope = getEnvelope();
+  const unknown = Buffer.from(sealEnvelope(envelope, "key-2", key), "utf8");
+  assert.throws(() => openEnvelope(unknown, getKey, maxAgeMs), /unknown key/);
+  const forged = Buffer.from(
+    sealEnvelope(envelope, "key-1", randomBytes(32)),
+    "utf8"
+  );
+  assert.throws(() => openEnvelope(forged, getKey, maxAgeMs));
+});
+
+test("rejects the envelopes changed on the way", () => {
+  const sealed = JSON.parse(sealEnvelope(getEnvelope(), "key-1", key));
+  const data = Buffer.from(sealed.data, "base64");
+  data[data.length - 1] ^= 1;
+  const message = Buffer.from(
+    JSON.stringify({ ...sealed, data: data.toString("base64") }),
+    "utf8"
+  );
+  assert.throws(() => openEnvelope(message, getKey, maxAgeMs));
+});
+
+test("rejects the envelopes too old", () => {
+  const envelope = { ...getEnvelope(), time: Date.now() - maxAgeMs - 1000 };
+  const message = Buffer.from(sealEnvelope(envelope, "key-1", key), "utf8");
+  assert.throws(() => openEnvelope(message, getKey, maxAgeMs), /too old/);
+});
+
+test("encrypts with a new IV every time", () => {
+  const data = Buffer.from("same", "utf8");
+  const first = encryptBuffer(data, key);
+  assert.notDeepStrictEqual(first, encryptBuffer(data, key));
+  assert.deepStrictEqual(decryptBuffer(first, key), data);
+});
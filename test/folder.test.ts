@@ -0,0 +1,74 @@
+import assert = require("assert");
+import { isLockFile, parseClipboardFileName } from "../src/folder";
+import { test } from "./harness";
+
+test("parses the name of a text clipboard", () => {
+  assert.deepStrictEqual(parseClipboardFileName("42-DESKTOP-A.txt"), {
+    itemNumber: 42,
+    hostname: "DESKTOP-A",
+    channel: "default",
+    type: "text",
+    filesCount: undefined,
+    target: undefined,
+    compressed: undefined,
+    archived: undefined,
+    sensitive: undefined,
+  });
+});
+
+test("parses the channel, target and sensitive mark", () => {
+  const clipboardFile = parseClipboardFileName(
+    "7-LAPTOP@work.to-DESKTOP-A.sensitive.txt.br"
+  );
+  assert.strictEqual(clipboardFile.itemNumber, 7);
+  assert.strictEqual(clipboardFile.hostname, "LAPTOP");
+  assert.strictEqual(clipboardFile.channel, "work");
+  assert.strictEqual(clipboardFile.target, "DESKTOP-A");
+  assert.strictEqual(clipboardFile.sensitive, true);
+  assert.strictEqual(clipboardFile.compressed, true);
+});
+
+test("parses images and files", () => {
+  assert.strictEqual(parseClipboardFileName("3-HOST.png").type, "image");
+  const folder = parseClipboardFileName("3-HOST.12_files");
+  assert.strictEqual(folder.type, "files");
+  assert.strictEqual(folder.filesCount, 12);
+  assert.strictEqual(folder.archived, undefined);
+  const archive = parseClipboardFileName("3-HOST.12_files.tar");
+  assert.strictEqual(archive.filesCount, 12);
+  assert.strictEqual(archive.archived, true);
+});
+
+test("rejects the names of other files", () => {
+  [
+    "42-DESKTOP-A.txt.part",
+    "042-DESKTOP-A.txt",
+    "42-DESKTOP_A.txt",
+    "42-DESKTOP-A (conflicted copy).txt",
+    "42-DESKTOP-A.jpg",
+    "DESKTOP-A.sequence.json",
+    "42-DESKTOP-A.01_files",
+  ].forEach((fileName) => {
+    assert.strictEqual(parseClipboardFileName(fileName), null, fileName);
+  });
+});
+
+test("tells the locks of the clipboards apart", () => {
+  [
+    "42-DESKTOP-A.lock",
+    "/folder/42-DESKTOP-A@work.lock",
+    "42-DESKTOP-A.to-LAPTOP.lock",
+    "42-DESKTOP-A.sensitive.lock",
+    "0123456789abcdef0123456789abcdef.lock",
+  ].forEach((file) => {
+    assert.strictEqual(isLockFile(file), true, file);
+  });
+  [
+    "42-DESKTOP-A.txt",
+    "notes.lock",
+    "42-DESKTOP-A.lock.txt",
+    "0123456789abcdef.lock",
+  ].forEach((file) => {
+    assert.strictEqual(isLockFile(file), false, file);
+  });
+});
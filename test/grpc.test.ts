@@ -0,0 +1,75 @@
+import assert = require("assert");
+import {
+  decodeProtobuf,
+  encodeProtobuf,
+  encodeVarint,
+  frameGrpcMessage,
+  readGrpcMessage,
+} from "../src/transports/grpc";
+import { test } from "./harness";
+
+test("encodes varints like protobuf", () => {
+  assert.deepStrictEqual(encodeVarint(0), Buffer.from([0x00]));
+  assert.deepStrictEqual(encodeVarint(127), Buffer.from([0x7f]));
+  assert.deepStrictEqual(encodeVarint(128), Buffer.from([0x80, 0x01]));
+  assert.deepStrictEqual(encodeVarint(300), Buffer.from([0xac, 0x02]));
+});
+
+test("encodes the example of the protobuf documentation", () => {
+  // https://protobuf.dev/programming-guides/encoding/
+  assert.deepStrictEqual(
+    encodeProtobuf([[1, 150]]),
+    Buffer.from([0x08, 0x96, 0x01])
+  );
+  assert.deepStrictEqual(
+    encodeProtobuf([[2, "testing"]]),
+    Buffer.from([0x12, 0x07, ...Buffer.from("testing")])
+  );
+});
+
+test("decodes the fields encoded", () => {
+  const time = 1700000000123;
+  const fields = decodeProtobuf(
+    encodeProtobuf([
+      [1, true],
+      [2, false],
+      [3, "/home/user/Clipboard Sync"],
+      [4, null],
+      [6, time],
+      [8, "ñ"],
+    ])
+  );
+  assert.strictEqual(fields.get(1), 1);
+  assert.strictEqual(fields.get(2), 0);
+  assert.strictEqual(
+    (fields.get(3) as Buffer).toString("utf8"),
+    "/home/user/Clipboard Sync"
+  );
+  assert.strictEqual(fields.has(4), false);
+  assert.strictEqual(fields.get(6), time);
+  assert.strictEqual((fields.get(8) as Buffer).toString("utf8"), "ñ");
+});
+
+test("stops decoding at the wire types not supported", () => {
+  // Field 1 as a fixed64, then field 2 as a varint
+  const fields = decodeProtobuf(
+    Buffer.from([0x09, 1, 2, 3, 4, 5, 6, 7, 8, 0x10, 0x01])
+  );
+  assert.strictEqual(fields.size, 0);
+});
+
+test("frames the gRPC messages with their length", () => {
+  const message = encodeProtobuf([[1, true]]);
+  const framed = frameGrpcMessage(message);
+  assert.deepStrictEqual(
+    framed,
+    Buffer.from([0x00, 0x00, 0x00, 0x00, 0x02, 0x08, 0x01])
+  );
+  assert.deepStrictEqual(readGrpcMessage(framed), message);
+  // Only the first message of the body is read
+  assert.deepStrictEqual(
+    readGrpcMessage(Buffer.concat([framed, frameGrpcMessage(message)])),
+    message
+  );
+  assert.deepStrictEqual(readGrpcMessage(Buffer.alloc(0)), Buffer.alloc(0));
+});
@@ -0,0 +1,27 @@
+// A tiny test runner, as the Node.js of Electron 18 has no node:test. Only
+// the modules which don't need Electron are tested.
+type Test = {
+  name: string;
+  run: () => void | Promise<void>;
+};
+
+const tests: Test[] = [];
+
+export const test = (name: string, run: Test["run"]) => {
+  tests.push({ name, run });
+};
+
+export const runTests = async () => {
+  let failures = 0;
+  for (const { name, run } of tests) {
+    try {
+      await run();
+      console.log(`ok - ${name}`);
+    } catch (error) {
+      failures++;
+      console.error(`not ok - ${name}\n${error.stack || error}`);
+    }
+  }
+  console.log(`${tests.length - failures} passed, ${failures} failed`);
+  process.exitCode = failures > 0 ? 1 : 0;
+};
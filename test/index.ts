@@ -0,0 +1,9 @@
+import "./envelope.test";
+import "./folder.test";
+import "./grpc.test";
+import "./nats.test";
+import "./tar.test";
+import "./websocket.test";
+import { runTests } from "./harness";
+
+runTests();
@@ -0,0 +1,81 @@
+import assert = require("assert");
+import { createNatsParser, encodeNatsCommand } from "../src/transports/nats";
+import { test } from "./harness";
+
+type Operation = [string, string[], string?];
+
+// Returns the parser, and the operations it reads, with the payloads as text
+const parse = () => {
+  const operations: Operation[] = [];
+  const handleData = createNatsParser((operation, args, payload) => {
+    operations.push(
+      payload === undefined
+        ? [operation, args]
+        : [operation, args, payload.toString("utf8")]
+    );
+  });
+  return { operations, handleData };
+};
+
+test("encodes the NATS commands", () => {
+  assert.strictEqual(encodeNatsCommand("PING"), "PING\r\n");
+  assert.strictEqual(
+    encodeNatsCommand("SUB clipboard-sync.default 1"),
+    "SUB clipboard-sync.default 1\r\n"
+  );
+  assert.strictEqual(
+    encodeNatsCommand("PUB clipboard-sync.default", "Hello"),
+    "PUB clipboard-sync.default 5\r\nHello\r\n"
+  );
+  // The size is in bytes, not characters
+  assert.strictEqual(encodeNatsCommand("PUB a", "ñ"), "PUB a 2\r\nñ\r\n");
+});
+
+test("reads the NATS operations", () => {
+  const { operations, handleData } = parse();
+  handleData(
+    Buffer.from(
+      'INFO {"max_payload":1048576}\r\nPING\r\n+OK\r\n' +
+        "MSG clipboard-sync.default 1 5\r\nHello\r\n" +
+        "MSG clipboard-sync.default 2 $JS.ACK.a.b 3\r\nBye\r\n"
+    )
+  );
+  assert.deepStrictEqual(operations, [
+    ["INFO", ['{"max_payload":1048576}']],
+    ["PING", []],
+    ["+OK", []],
+    ["MSG", ["clipboard-sync.default", "1", "5"], "Hello"],
+    ["MSG", ["clipboard-sync.default", "2", "$JS.ACK.a.b", "3"], "Bye"],
+  ]);
+});
+
+test("reads the NATS operations split across chunks", () => {
+  const { operations, handleData } = parse();
+  const data = Buffer.from(
+    "PING\r\nMSG clipboard-sync.default 1 10\r\nHello\r\nBye\r\nPONG\r\n"
+  );
+  for (let i = 0; i < data.length; i++) {
+    handleData(data.subarray(i, i + 1));
+  }
+  // The line ending inside the payload is part of it
+  assert.deepStrictEqual(operations, [
+    ["PING", []],
+    ["MSG", ["clipboard-sync.default", "1", "10"], "Hello\r\nBye"],
+    ["PONG", []],
+  ]);
+});
+
+test("reads the NATS payloads sent", () => {
+  const { operations, handleData } = parse();
+  const text = "ñ\r\n".repeat(1000);
+  // The server relays each PUB as a MSG, adding the subscription id
+  const command = encodeNatsCommand("PUB clipboard-sync.default", text);
+  handleData(Buffer.from(command.replace(/^PUB (\S+)/, "MSG $1 1")));
+  assert.deepStrictEqual(operations, [
+    [
+      "MSG",
+      ["clipboard-sync.default", "1", `${Buffer.byteLength(text)}`],
+      text,
+    ],
+  ]);
+});
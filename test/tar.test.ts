@@ -0,0 +1,84 @@
+import assert = require("assert");
+import fs = require("fs");
+import os = require("os");
+import path = require("path");
+import { extractTarArchive, writeTarArchive } from "../src/tar";
+import { test } from "./harness";
+
+const withTemporaryFolder = (run: (folder: string) => void) => {
+  const folder = fs.mkdtempSync(path.join(os.tmpdir(), "clipboard-sync-"));
+  try {
+    run(folder);
+  } finally {
+    fs.rmSync(folder, { recursive: true, force: true });
+  }
+};
+
+test("extracts the files and folders archived", () => {
+  withTemporaryFolder((folder) => {
+    const source = path.join(folder, "source");
+    const longName = `${"a".repeat(90)}/${"b".repeat(90)}`;
+    fs.mkdirSync(path.join(source, "folder", longName), { recursive: true });
+    fs.writeFileSync(path.join(source, "file.txt"), "file");
+    fs.writeFileSync(path.join(source, "folder", "empty.txt"), "");
+    fs.writeFileSync(
+      path.join(source, "folder", longName, "large.bin"),
+      Buffer.alloc(1500, 7)
+    );
+    const archive = path.join(folder, "1-HOST.2_files.tar");
+    writeTarArchive(
+      [path.join(source, "file.txt"), path.join(source, "folder")],
+      archive
+    );
+    assert.strictEqual(fs.statSync(archive).size % 512, 0);
+
+    const destination = path.join(folder, "destination");
+    assert.deepStrictEqual(extractTarArchive(archive, destination), [
+      path.join(destination, "file.txt"),
+      path.join(destination, "folder"),
+    ]);
+    assert.strictEqual(
+      fs.readFileSync(path.join(destination, "file.txt"), "utf8"),
+      "file"
+    );
+    assert.strictEqual(
+      fs.statSync(path.join(destination, "folder", "empty.txt")).size,
+      0
+    );
+    assert.deepStrictEqual(
+      fs.readFileSync(path.join(destination, "folder", longName, "large.bin")),
+      Buffer.alloc(1500, 7)
+    );
+  });
+});
+
+test("waits for the archives still being written", () => {
+  withTemporaryFolder((folder) => {
+    fs.writeFileSync(path.join(folder, "file.txt"), "x".repeat(2000));
+    const archive = path.join(folder, "1-HOST.1_files.tar");
+    writeTarArchive([path.join(folder, "file.txt")], archive);
+    const data = fs.readFileSync(archive);
+    fs.writeFileSync(archive, data.subarray(0, 1024));
+    assert.strictEqual(
+      extractTarArchive(archive, path.join(folder, "destination")),
+      null
+    );
+  });
+});
+
+test("never extracts outside of the destination", () => {
+  withTemporaryFolder((folder) => {
+    fs.writeFileSync(path.join(folder, "file.txt"), "file");
+    const archive = path.join(folder, "1-HOST.1_files.tar");
+    writeTarArchive([path.join(folder, "file.txt")], archive);
+    const data = fs.readFileSync(archive);
+    // Renamed, with the checksum left as is, which is not verified
+    data.write("../escaped.txt\0", 0);
+    fs.writeFileSync(archive, data);
+    assert.throws(
+      () => extractTarArchive(archive, path.join(folder, "destination")),
+      /Invalid path/
+    );
+    assert.strictEqual(fs.existsSync(path.join(folder, "escaped.txt")), false);
+  });
+});
@@ -0,0 +1,136 @@
+import assert = require("assert");
+import net = require("net");
+import { EventEmitter } from "events";
+import {
+  createWebSocketConnection,
+  encodeWebSocketFrame,
+  getWebSocketAccept,
+  maxWebSocketMessageSize,
+} from "../src/transports/websocket";
+import { test } from "./harness";
+
+// Keeps what is written instead of sending it
+const createFakeSocket = () => {
+  const socket = Object.assign(new EventEmitter(), {
+    written: [] as Buffer[],
+    destroyed: false,
+    write: (data: Buffer) => {
+      socket.written.push(data);
+      return true;
+    },
+    end: (data?: Buffer) => {
+      if (data) {
+        socket.written.push(data);
+      }
+    },
+    destroy: () => {
+      socket.destroyed = true;
+    },
+  });
+  return socket;
+};
+
+const connect = (masked = false) => {
+  const socket = createFakeSocket();
+  const messages: [string, number][] = [];
+  const connection = createWebSocketConnection(
+    socket as unknown as net.Socket,
+    masked,
+    (message, opcode) => messages.push([message.toString("utf8"), opcode]),
+    () => undefined
+  );
+  return { socket, connection, messages };
+};
+
+test("answers the handshake like RFC 6455", () => {
+  assert.strictEqual(
+    getWebSocketAccept("dGhlIHNhbXBsZSBub25jZQ=="),
+    "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+  );
+});
+
+test("encodes the frames like RFC 6455", () => {
+  assert.deepStrictEqual(
+    encodeWebSocketFrame(0x1, Buffer.from("Hello"), false),
+    Buffer.from([0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f])
+  );
+  const medium = encodeWebSocketFrame(0x2, Buffer.alloc(256), false);
+  assert.deepStrictEqual(medium.subarray(0, 4), Buffer.from([0x82, 126, 1, 0]));
+  const large = encodeWebSocketFrame(0x2, Buffer.alloc(65536), false);
+  assert.deepStrictEqual(
+    large.subarray(0, 10),
+    Buffer.from([0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0])
+  );
+});
+
+test("reads the frames of RFC 6455, even split", () => {
+  const { socket, messages } = connect();
+  // A masked "Hello", then a fragmented unmasked one, byte by byte
+  socket.emit(
+    "data",
+    Buffer.from([0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51])
+  );
+  socket.emit("data", Buffer.from([0x58]));
+  [0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f].forEach((byte) =>
+    socket.emit("data", Buffer.from([byte]))
+  );
+  assert.deepStrictEqual(messages, [
+    ["Hello", 0x1],
+    ["Hello", 0x1],
+  ]);
+});
+
+test("reads the frames masked by the clients", () => {
+  const { socket, messages } = connect();
+  const payload = Buffer.alloc(70000, "a");
+  socket.emit("data", encodeWebSocketFrame(0x2, payload, true));
+  assert.deepStrictEqual(messages, [[payload.toString("utf8"), 0x2]]);
+});
+
+test("answers the pings and closes", () => {
+  const { socket } = connect();
+  socket.emit("data", Buffer.from([0x89, 0x02, 0x68, 0x69]));
+  assert.deepStrictEqual(
+    socket.written[0],
+    Buffer.from([0x8a, 0x02, 0x68, 0x69])
+  );
+  socket.emit("data", Buffer.from([0x88, 0x00]));
+  assert.deepStrictEqual(socket.written[1], Buffer.from([0x88, 0x00]));
+});
+
+test("sends with the opcode given", () => {
+  const { socket, connection } = connect();
+  connection.send("text");
+  connection.send(Buffer.from("binary"));
+  connection.send(Buffer.from("relayed"), 0x1);
+  assert.deepStrictEqual(
+    socket.written.map((frame) => frame[0]),
+    [0x81, 0x82, 0x81]
+  );
+});
+
+test("closes the connections sending too much", () => {
+  const tooLarge = connect();
+  const header = Buffer.from([0x82, 127, 0, 0, 0, 0, 0, 0, 0, 0]);
+  header.writeBigUInt64BE(BigInt(maxWebSocketMessageSize + 1), 2);
+  tooLarge.socket.emit("data", header);
+  assert.strictEqual(tooLarge.socket.destroyed, true);
+
+  // Each fragment is small enough, but not all of them together
+  const fragmented = connect();
+  const half = Buffer.alloc(maxWebSocketMessageSize / 2 + 1);
+  const first = encodeWebSocketFrame(0x2, half, false);
+  first[0] &= 0x7f;
+  fragmented.socket.emit("data", first);
+  assert.strictEqual(fragmented.socket.destroyed, false);
+  fragmented.socket.emit("data", encodeWebSocketFrame(0x0, half, false));
+  assert.strictEqual(fragmented.socket.destroyed, true);
+  assert.deepStrictEqual(fragmented.messages, []);
+});
+
+test("closes the connections continuing no message", () => {
+  const { socket, messages } = connect();
+  socket.emit("data", Buffer.from([0x80, 0x02, 0x68, 0x69]));
+  assert.strictEqual(socket.destroyed, true);
+  assert.deepStrictEqual(messages, []);
+});